@@ -0,0 +1,47 @@
+// Copies one texture into another of a different size, filtering as it
+// goes. Used to build each mip level from the one above it, and (with
+// fs_encode_srgb) to gamma encode frames for surfaces that aren't sRGB
+
+struct VertexOutput {
+   @builtin(position) clip_position: vec4<f32>,
+   @location(0) tex_coords: vec2<f32>,
+};
+
+// A single triangle big enough to cover the whole screen - no vertex
+// buffer needed, the positions come from the vertex index:
+//    0 => (-1,  1)    1 => (3,  1)    2 => (-1, -3)
+@vertex
+fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> VertexOutput {
+   var out: VertexOutput;
+   let x = f32(i32(in_vertex_index & 1u) * 4 - 1);
+   let y = f32(1 - i32(in_vertex_index & 2u) * 2);
+   out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+   out.tex_coords = vec2<f32>((x + 1.0) * 0.5, (1.0 - y) * 0.5);
+   return out;
+}
+
+@group(0) @binding(0)
+var t_source: texture_2d<f32>;
+@group(0) @binding(1)
+var s_source: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+   return textureSample(t_source, s_source, in.tex_coords);
+}
+
+// sRGB targets encode what we write for us. Other 8/10 bit targets store
+// our linear values as is, which displays too dark - so we do the
+// encoding ourselves, with the same curve the hardware uses
+fn linear_to_srgb(linear: vec3<f32>) -> vec3<f32> {
+   let c = clamp(linear, vec3<f32>(0.0), vec3<f32>(1.0));
+   let lower = c * 12.92;
+   let higher = 1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055;
+   return select(higher, lower, c <= vec3<f32>(0.0031308));
+}
+
+@fragment
+fn fs_encode_srgb(in: VertexOutput) -> @location(0) vec4<f32> {
+   let color = textureSample(t_source, s_source, in.tex_coords);
+   return vec4<f32>(linear_to_srgb(color.rgb), color.a);
+}
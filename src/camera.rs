@@ -0,0 +1,139 @@
+use cgmath::prelude::*;
+
+// wgpu's normalized device coordinates have z going from 0.0 to 1.0, while
+// cgmath (like OpenGL) builds projections for z going from -1.0 to 1.0.
+// This matrix scales and translates z to fix that up
+//
+// Note: Matrix4::new takes its arguments column by column
+#[rustfmt::skip]
+pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
+   1.0, 0.0, 0.0, 0.0,
+   0.0, 1.0, 0.0, 0.0,
+   0.0, 0.0, 0.5, 0.0,
+   0.0, 0.0, 0.5, 1.0,
+);
+
+#[derive(Copy, Clone, Debug)]
+pub enum Projection {
+   // fovy is the vertical field of view, in degrees
+   Perspective { fovy: f32 },
+   // height is how many world units fit between the bottom and top of the
+   // screen; the width follows from the aspect ratio
+   Orthographic { height: f32 },
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Camera {
+   pub eye: cgmath::Point3<f32>,
+   pub target: cgmath::Point3<f32>,
+   pub up: cgmath::Vector3<f32>,
+   pub aspect: f32,
+   pub projection: Projection,
+   pub znear: f32,
+   pub zfar: f32,
+}
+
+impl Camera {
+   // Looks at the origin from slightly above and in front of it - the
+   // pentagon sits at z = 0 so this frames it nicely
+   pub fn new(width: u32, height: u32) -> Self {
+      Self {
+         eye: (0.0, 1.0, 2.0).into(),
+         target: (0.0, 0.0, 0.0).into(),
+         up: cgmath::Vector3::unit_y(),
+         aspect: width as f32 / height as f32,
+         projection: Projection::Perspective { fovy: 45.0 },
+         znear: 0.1,
+         zfar: 100.0,
+      }
+   }
+
+   pub fn resize(&mut self, width: u32, height: u32) {
+      if width > 0 && height > 0 {
+         self.aspect = width as f32 / height as f32;
+      }
+   }
+
+   // The view matrix moves the world to be at the position and rotation
+   // of the camera - it's the inverse of the camera's transform matrix
+   pub fn build_view_matrix(&self) -> cgmath::Matrix4<f32> {
+      cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up)
+   }
+
+   // The projection matrix warps the scene to give the effect of depth
+   // (perspective) or flattens it (orthographic), then squashes it into
+   // wgpu's clip space
+   pub fn build_projection_matrix(&self) -> cgmath::Matrix4<f32> {
+      let proj = match self.projection {
+         Projection::Perspective { fovy } => {
+            cgmath::perspective(cgmath::Deg(fovy), self.aspect, self.znear, self.zfar)
+         },
+         Projection::Orthographic { height } => {
+            let half_height = height / 2.0;
+            let half_width = half_height * self.aspect;
+            cgmath::ortho(-half_width, half_width, -half_height, half_height, self.znear, self.zfar)
+         },
+      };
+
+      OPENGL_TO_WGPU_MATRIX * proj
+   }
+
+   pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
+      self.build_projection_matrix() * self.build_view_matrix()
+   }
+
+   // The eight corners (in world space) of the slice of what the camera
+   // sees between near and far, measured along the way it's looking. The
+   // near four come first
+   pub fn frustum_corners(&self, near: f32, far: f32) -> [cgmath::Point3<f32>; 8] {
+      let forward = (self.target - self.eye).normalize();
+      let right = forward.cross(self.up).normalize();
+      let up = right.cross(forward);
+
+      let mut corners = [self.eye; 8];
+      for (i, distance) in [near, far].into_iter().enumerate() {
+         let half_height = match self.projection {
+            Projection::Perspective { fovy } => distance * (cgmath::Deg(fovy) / 2.0).tan(),
+            Projection::Orthographic { height } => height / 2.0,
+         };
+         let half_width = half_height * self.aspect;
+         let centre = self.eye + forward * distance;
+         for (j, (x, y)) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].into_iter().enumerate() {
+            corners[i * 4 + j] = centre + right * (x * half_width) + up * (y * half_height);
+         }
+      }
+      corners
+   }
+}
+
+// We need this to be Pod so we can store it in a buffer. cgmath types
+// can't be used with bytemuck directly, so we convert the Matrix4 into
+// a 4x4 f32 array
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CameraUniform {
+   // Where the camera is, for working out specular highlights. A vec4
+   // rather than a vec3 to keep the uniform a multiple of 16 bytes
+   view_position: [f32; 4],
+   view_proj: [[f32; 4]; 4],
+}
+
+impl CameraUniform {
+   pub fn new() -> Self {
+      Self {
+         view_position: [0.0; 4],
+         view_proj: cgmath::Matrix4::identity().into(),
+      }
+   }
+
+   pub fn update_view_proj(&mut self, camera: &Camera) {
+      self.view_position = camera.eye.to_homogeneous().into();
+      self.view_proj = camera.build_view_projection_matrix().into();
+   }
+}
+
+impl Default for CameraUniform {
+   fn default() -> Self {
+      Self::new()
+   }
+}
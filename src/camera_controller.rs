@@ -0,0 +1,494 @@
+use std::time::Duration;
+
+use cgmath::prelude::*;
+use winit::{
+   dpi::PhysicalPosition,
+   event::*,
+};
+
+use crate::camera::{ Camera, Projection };
+
+// A CameraController turns window events into camera movement. Input is
+// split into two steps:
+//
+// 1. process_event is called from State::input for every WindowEvent.
+//       Controllers only record what happened here (keys held, how far the
+//       mouse moved) and return true if they used the event
+//
+// 2. update_camera is called once per frame from State::update with the
+//       time since the last frame, so that movement speed doesn't depend on
+//       the frame rate
+pub trait CameraController {
+   fn process_event(&mut self, event: &WindowEvent) -> bool;
+   fn update_camera(&mut self, camera: &mut Camera, dt: Duration);
+   // Called with the size of the surface whenever it changes, for
+   // controllers that need to convert pixels into world units
+   fn resize(&mut self, _width: u32, _height: u32) {}
+}
+
+// Tracks the cursor while a mouse button is held, and adds up how far it
+// moved since the last frame. CursorMoved gives us absolute positions, so
+// we have to work out the deltas ourselves
+#[derive(Debug, Default)]
+struct MouseDrag {
+   button: Option<MouseButton>,
+   dragging: bool,
+   last_position: Option<PhysicalPosition<f64>>,
+   dx: f32,
+   dy: f32,
+}
+
+impl MouseDrag {
+   fn new(button: MouseButton) -> Self {
+      Self { button: Some(button), ..Default::default() }
+   }
+
+   fn process_event(&mut self, event: &WindowEvent) -> bool {
+      match event {
+         WindowEvent::MouseInput { state, button, .. } if Some(*button) == self.button => {
+            self.dragging = *state == ElementState::Pressed;
+            true
+         },
+         WindowEvent::CursorMoved { position, .. } => {
+            let consumed = match self.last_position {
+               Some(last) if self.dragging => {
+                  self.dx += (position.x - last.x) as f32;
+                  self.dy += (position.y - last.y) as f32;
+                  true
+               },
+               _ => false,
+            };
+            self.last_position = Some(*position);
+            consumed
+         },
+         WindowEvent::CursorLeft { .. } => {
+            self.last_position = None;
+            false
+         },
+         _ => false,
+      }
+   }
+
+   // Returns the movement since the last call and resets it
+   fn take(&mut self) -> (f32, f32) {
+      let delta = (self.dx, self.dy);
+      self.dx = 0.0;
+      self.dy = 0.0;
+      delta
+   }
+}
+
+// Scroll wheels report lines, touchpads report pixels - treat roughly 20
+// pixels as one line so both feel about the same
+fn scroll_lines(delta: &MouseScrollDelta) -> f32 {
+   match delta {
+      MouseScrollDelta::LineDelta(_, y) => *y,
+      MouseScrollDelta::PixelDelta(PhysicalPosition { y, .. }) => *y as f32 / 20.0,
+   }
+}
+
+fn key_pressed(event: &WindowEvent) -> Option<(VirtualKeyCode, bool)> {
+   match event {
+      WindowEvent::KeyboardInput {
+         input: KeyboardInput {
+            state,
+            virtual_keycode: Some(keycode),
+            ..
+         },
+         ..
+      } => Some((*keycode, *state == ElementState::Pressed)),
+      _ => None,
+   }
+}
+
+// Keeps pitch just short of straight up/down, where look_at_rh breaks down
+// because the view direction lines up with the up vector
+const SAFE_FRAC_PI_2: f32 = std::f32::consts::FRAC_PI_2 - 0.0001;
+
+// Splits a direction into yaw (around y) and pitch (up/down) angles, in radians
+fn yaw_pitch(direction: cgmath::Vector3<f32>) -> (f32, f32) {
+   let direction = direction.normalize();
+   (direction.z.atan2(direction.x), direction.y.asin())
+}
+
+fn from_yaw_pitch(yaw: f32, pitch: f32) -> cgmath::Vector3<f32> {
+   let (sin_yaw, cos_yaw) = yaw.sin_cos();
+   let (sin_pitch, cos_pitch) = pitch.sin_cos();
+   cgmath::Vector3::new(cos_pitch * cos_yaw, sin_pitch, cos_pitch * sin_yaw)
+}
+
+
+// ORBIT - drag with the left mouse button (or use the arrow keys) to rotate
+// around camera.target, scroll to move closer or further away
+pub struct OrbitController {
+   // radians per pixel dragged
+   pub sensitivity: f32,
+   // radians per second while an arrow key is held
+   pub key_speed: f32,
+   // fraction of the distance to the target moved per scroll line
+   pub zoom_speed: f32,
+   pub min_distance: f32,
+   drag: MouseDrag,
+   scroll: f32,
+   left: bool,
+   right: bool,
+   up: bool,
+   down: bool,
+}
+
+impl OrbitController {
+   pub fn new() -> Self {
+      Self {
+         sensitivity: 0.005,
+         key_speed: 1.5,
+         zoom_speed: 0.1,
+         min_distance: 0.1,
+         drag: MouseDrag::new(MouseButton::Left),
+         scroll: 0.0,
+         left: false,
+         right: false,
+         up: false,
+         down: false,
+      }
+   }
+}
+
+impl Default for OrbitController {
+   fn default() -> Self {
+      Self::new()
+   }
+}
+
+impl CameraController for OrbitController {
+   fn process_event(&mut self, event: &WindowEvent) -> bool {
+      if let Some((keycode, pressed)) = key_pressed(event) {
+         match keycode {
+            VirtualKeyCode::Left => self.left = pressed,
+            VirtualKeyCode::Right => self.right = pressed,
+            VirtualKeyCode::Up => self.up = pressed,
+            VirtualKeyCode::Down => self.down = pressed,
+            _ => return false,
+         }
+         return true;
+      }
+      if let WindowEvent::MouseWheel { delta, .. } = event {
+         self.scroll += scroll_lines(delta);
+         return true;
+      }
+      self.drag.process_event(event)
+   }
+
+   fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
+      let dt = dt.as_secs_f32();
+      let (dx, dy) = self.drag.take();
+
+      let offset = camera.eye - camera.target;
+      let (mut yaw, mut pitch) = yaw_pitch(offset);
+      let mut distance = offset.magnitude();
+
+      // Dragging right should swing the camera right, around the target
+      yaw += dx * self.sensitivity;
+      pitch += dy * self.sensitivity;
+      let horizontal = (self.right as i32 - self.left as i32) as f32;
+      let vertical = (self.up as i32 - self.down as i32) as f32;
+      yaw += horizontal * self.key_speed * dt;
+      pitch += vertical * self.key_speed * dt;
+      pitch = pitch.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2);
+
+      distance *= 1.0 - self.scroll * self.zoom_speed;
+      distance = distance.max(self.min_distance);
+      self.scroll = 0.0;
+
+      camera.eye = camera.target + from_yaw_pitch(yaw, pitch) * distance;
+   }
+}
+
+
+// FLY - WASD to move, Space/Left Shift to go up/down, drag with the right
+// mouse button to look around. Moves the target along with the eye
+pub struct FlyController {
+   // world units per second
+   pub speed: f32,
+   // radians per pixel dragged
+   pub sensitivity: f32,
+   drag: MouseDrag,
+   forward: bool,
+   backward: bool,
+   left: bool,
+   right: bool,
+   up: bool,
+   down: bool,
+}
+
+impl FlyController {
+   pub fn new() -> Self {
+      Self {
+         speed: 2.0,
+         sensitivity: 0.003,
+         drag: MouseDrag::new(MouseButton::Right),
+         forward: false,
+         backward: false,
+         left: false,
+         right: false,
+         up: false,
+         down: false,
+      }
+   }
+}
+
+impl Default for FlyController {
+   fn default() -> Self {
+      Self::new()
+   }
+}
+
+impl CameraController for FlyController {
+   fn process_event(&mut self, event: &WindowEvent) -> bool {
+      if let Some((keycode, pressed)) = key_pressed(event) {
+         match keycode {
+            VirtualKeyCode::W => self.forward = pressed,
+            VirtualKeyCode::S => self.backward = pressed,
+            VirtualKeyCode::A => self.left = pressed,
+            VirtualKeyCode::D => self.right = pressed,
+            VirtualKeyCode::Space => self.up = pressed,
+            VirtualKeyCode::LShift => self.down = pressed,
+            _ => return false,
+         }
+         return true;
+      }
+      self.drag.process_event(event)
+   }
+
+   fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
+      let dt = dt.as_secs_f32();
+
+      // Look around first, keeping the eye where it is
+      let to_target = camera.target - camera.eye;
+      let distance = to_target.magnitude();
+      let (mut yaw, mut pitch) = yaw_pitch(to_target);
+      let (dx, dy) = self.drag.take();
+      yaw += dx * self.sensitivity;
+      pitch = (pitch - dy * self.sensitivity).clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2);
+      let forward = from_yaw_pitch(yaw, pitch);
+
+      // Then move along the (new) view direction
+      let right = forward.cross(camera.up).normalize();
+      let up = camera.up.normalize();
+      let mut movement = cgmath::Vector3::zero();
+      movement += forward * (self.forward as i32 - self.backward as i32) as f32;
+      movement += right * (self.right as i32 - self.left as i32) as f32;
+      movement += up * (self.up as i32 - self.down as i32) as f32;
+      if movement.magnitude2() > 0.0 {
+         movement = movement.normalize() * self.speed * dt;
+      }
+
+      camera.eye += movement;
+      camera.target = camera.eye + forward * distance;
+   }
+}
+
+
+// PAN - drag with the left mouse button (or use WASD / the arrow keys) to
+// slide the view around, scroll to zoom. Meant for looking at 2D scenes,
+// so it never rotates the camera
+pub struct PanController {
+   // world units per second while a key is held, relative to the view height
+   pub key_speed: f32,
+   // fraction of the view height zoomed per scroll line
+   pub zoom_speed: f32,
+   drag: MouseDrag,
+   scroll: f32,
+   left: bool,
+   right: bool,
+   up: bool,
+   down: bool,
+   // Dragging should move the scene with the cursor, so we need to know
+   // how many pixels tall the view is
+   viewport_height: f32,
+}
+
+impl PanController {
+   pub fn new() -> Self {
+      Self {
+         key_speed: 1.0,
+         zoom_speed: 0.1,
+         drag: MouseDrag::new(MouseButton::Left),
+         scroll: 0.0,
+         left: false,
+         right: false,
+         up: false,
+         down: false,
+         viewport_height: 1.0,
+      }
+   }
+}
+
+impl Default for PanController {
+   fn default() -> Self {
+      Self::new()
+   }
+}
+
+// How many world units are visible from the bottom to the top of the screen,
+// measured at the target
+fn view_height(camera: &Camera) -> f32 {
+   match camera.projection {
+      Projection::Orthographic { height } => height,
+      Projection::Perspective { fovy } => {
+         let distance = (camera.target - camera.eye).magnitude();
+         2.0 * distance * (cgmath::Deg(fovy) / 2.0).tan()
+      },
+   }
+}
+
+impl CameraController for PanController {
+   fn process_event(&mut self, event: &WindowEvent) -> bool {
+      if let Some((keycode, pressed)) = key_pressed(event) {
+         match keycode {
+            VirtualKeyCode::A | VirtualKeyCode::Left => self.left = pressed,
+            VirtualKeyCode::D | VirtualKeyCode::Right => self.right = pressed,
+            VirtualKeyCode::W | VirtualKeyCode::Up => self.up = pressed,
+            VirtualKeyCode::S | VirtualKeyCode::Down => self.down = pressed,
+            _ => return false,
+         }
+         return true;
+      }
+      if let WindowEvent::MouseWheel { delta, .. } = event {
+         self.scroll += scroll_lines(delta);
+         return true;
+      }
+      self.drag.process_event(event)
+   }
+
+   fn resize(&mut self, _width: u32, height: u32) {
+      self.viewport_height = height.max(1) as f32;
+   }
+
+   fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
+      let dt = dt.as_secs_f32();
+      let forward = (camera.target - camera.eye).normalize();
+      let right = forward.cross(camera.up).normalize();
+      let up = right.cross(forward);
+
+      let height = view_height(camera);
+      let units_per_pixel = height / self.viewport_height;
+      let (dx, dy) = self.drag.take();
+
+      // Screen y goes down while world y goes up, and dragging moves the
+      // scene rather than the camera, hence the signs
+      let mut pan = right * (-dx * units_per_pixel) + up * (dy * units_per_pixel);
+      let horizontal = (self.right as i32 - self.left as i32) as f32;
+      let vertical = (self.up as i32 - self.down as i32) as f32;
+      pan += (right * horizontal + up * vertical) * self.key_speed * height * dt;
+
+      camera.eye += pan;
+      camera.target += pan;
+
+      let zoom = (1.0 - self.scroll * self.zoom_speed).max(0.01);
+      self.scroll = 0.0;
+      match &mut camera.projection {
+         Projection::Orthographic { height } => *height *= zoom,
+         Projection::Perspective { .. } => {
+            camera.eye = camera.target - forward * (camera.target - camera.eye).magnitude() * zoom;
+         },
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn camera_looking_down_z() -> Camera {
+      let mut camera = Camera::new(100, 100);
+      camera.eye = (0.0, 0.0, 5.0).into();
+      camera.target = (0.0, 0.0, 0.0).into();
+      camera
+   }
+
+   fn assert_close(actual: f32, expected: f32) {
+      assert!((actual - expected).abs() < 1e-4, "{} isn't close to {}", actual, expected);
+   }
+
+   #[test]
+   fn orbit_pitch_stops_short_of_straight_up_and_down() {
+      let mut camera = camera_looking_down_z();
+      let mut controller = OrbitController::new();
+      controller.up = true;
+      // Long enough to go right over the top if nothing stopped it
+      controller.update_camera(&mut camera, Duration::from_secs(10));
+
+      // Still on the side it started, almost directly above the target
+      assert!(camera.eye.z > 0.0 && camera.eye.z < 0.01, "{:?}", camera.eye);
+      assert_close(camera.eye.y, 5.0);
+      assert!(camera.build_view_matrix().x.x.is_finite());
+
+      controller.up = false;
+      controller.drag.dy = -1.0e6;
+      controller.update_camera(&mut camera, Duration::from_millis(16));
+      assert!(camera.eye.z > 0.0 && camera.eye.z < 0.01, "{:?}", camera.eye);
+      assert_close(camera.eye.y, -5.0);
+   }
+
+   #[test]
+   fn orbit_zoom_stops_at_min_distance() {
+      let mut camera = camera_looking_down_z();
+      let mut controller = OrbitController::new();
+      controller.min_distance = 0.5;
+      // Zooming in past the target would flip the distance negative
+      controller.scroll = 100.0;
+      controller.update_camera(&mut camera, Duration::from_millis(16));
+
+      assert_close((camera.eye - camera.target).magnitude(), 0.5);
+      assert!(camera.eye.z > 0.0);
+   }
+
+   #[test]
+   fn fly_moves_diagonally_at_the_same_speed() {
+      let mut camera = camera_looking_down_z();
+      let mut controller = FlyController::new();
+      controller.speed = 2.0;
+      controller.forward = true;
+      controller.right = true;
+      controller.update_camera(&mut camera, Duration::from_millis(500));
+
+      // Forward is -z and right is +x, half each
+      let moved = camera.eye - cgmath::Point3::new(0.0, 0.0, 5.0);
+      assert_close(moved.magnitude(), 1.0);
+      assert_close(moved.x, -moved.z);
+      assert_close((camera.target - camera.eye).magnitude(), 5.0);
+   }
+
+   #[test]
+   fn fly_movement_scales_with_dt() {
+      let mut controller = FlyController::new();
+      controller.forward = true;
+      let moved = |controller: &mut FlyController, dt| {
+         let mut camera = camera_looking_down_z();
+         controller.update_camera(&mut camera, dt);
+         5.0 - camera.eye.z
+      };
+
+      assert_close(moved(&mut controller, Duration::from_millis(100)), 0.2);
+      assert_close(moved(&mut controller, Duration::from_millis(400)), 0.8);
+      assert_close(moved(&mut controller, Duration::ZERO), 0.0);
+   }
+
+   #[test]
+   fn pan_drag_follows_the_cursor_after_resize() {
+      let mut controller = PanController::new();
+      let mut dragged = |viewport_height, dx| {
+         let mut camera = camera_looking_down_z();
+         camera.projection = Projection::Orthographic { height: 4.0 };
+         controller.resize(viewport_height * 2, viewport_height);
+         controller.drag.dx = dx;
+         controller.update_camera(&mut camera, Duration::from_millis(16));
+         camera.eye.x
+      };
+
+      // 4 units over 200 pixels is 0.02 units per pixel, and dragging
+      // right moves the camera left
+      assert_close(dragged(200, 50.0), -1.0);
+      assert_close(dragged(400, 50.0), -0.5);
+   }
+}
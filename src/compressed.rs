@@ -0,0 +1,205 @@
+use anyhow::*;
+
+// KTX2 and DDS are containers for texture data that's ready to go straight
+// to the gpu: usually block compressed (BCn on desktop, ETC2/ASTC on mobile)
+// and with the whole mip chain already built. All we do here is find the
+// format and the bytes of each mip level - nothing gets decoded
+
+const KTX2_MAGIC: [u8; 12] = [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+const DDS_MAGIC: [u8; 4] = *b"DDS ";
+
+pub struct CompressedImage {
+   pub format: wgpu::TextureFormat,
+   pub width: u32,
+   pub height: u32,
+   // Largest first, each one tightly packed rows of blocks
+   pub levels: Vec<Vec<u8>>,
+}
+
+// Whether bytes look like one of the containers we can load, rather than
+// something for the image crate
+pub fn is_container(bytes: &[u8]) -> bool {
+   bytes.starts_with(&KTX2_MAGIC) || bytes.starts_with(&DDS_MAGIC)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<CompressedImage> {
+   let image = if bytes.starts_with(&KTX2_MAGIC) {
+      parse_ktx2(bytes)?
+   } else if bytes.starts_with(&DDS_MAGIC) {
+      parse_dds(bytes)?
+   } else {
+      bail!("not a KTX2 or DDS file");
+   };
+
+   for (mip_level, level) in image.levels.iter().enumerate() {
+      let expected = level_byte_size(image.format, image.width, image.height, mip_level as u32);
+      ensure!(
+         level.len() == expected,
+         "mip level {} is {} bytes, expected {}", mip_level, level.len(), expected
+      );
+   }
+   Ok(image)
+}
+
+// Bytes in one mip level. Blocks cover 4x4 pixels (more for some ASTC), so
+// levels smaller than a block still take up a whole one
+pub fn level_byte_size(format: wgpu::TextureFormat, width: u32, height: u32, mip_level: u32) -> usize {
+   let (block_width, block_height) = format.block_dimensions();
+   let width = (width >> mip_level).max(1);
+   let height = (height >> mip_level).max(1);
+   let blocks = width.div_ceil(block_width) * height.div_ceil(block_height);
+   blocks as usize * format.block_size(None).unwrap() as usize
+}
+
+fn parse_ktx2(bytes: &[u8]) -> Result<CompressedImage> {
+   let reader = ktx2::Reader::new(bytes).map_err(|e| anyhow!("invalid KTX2 file: {}", e))?;
+   let header = reader.header();
+
+   ensure!(
+      header.supercompression_scheme.is_none(),
+      "supercompressed KTX2 files ({:?}) aren't supported", header.supercompression_scheme.unwrap()
+   );
+   ensure!(
+      header.pixel_depth <= 1 && header.layer_count <= 1 && header.face_count == 1,
+      "only 2d KTX2 textures are supported, not arrays, cubemaps or 3d textures"
+   );
+   let format = header.format.ok_or_else(|| anyhow!("KTX2 file has no format (Basis Universal?)"))?;
+   let format = ktx2_format(format).ok_or_else(|| anyhow!("unsupported KTX2 format {:?}", format))?;
+
+   Ok(CompressedImage {
+      format,
+      width: header.pixel_width,
+      height: header.pixel_height.max(1),
+      levels: reader.levels().map(|level| level.data.to_vec()).collect(),
+   })
+}
+
+fn ktx2_format(format: ktx2::Format) -> Option<wgpu::TextureFormat> {
+   use ktx2::Format as K;
+   use wgpu::TextureFormat as W;
+
+   let format = match format {
+      K::R8G8B8A8_UNORM => W::Rgba8Unorm,
+      K::R8G8B8A8_SRGB => W::Rgba8UnormSrgb,
+      // wgpu has no BC1 without alpha - the rgb variant decodes the same,
+      // it just ignores the 1 bit of alpha
+      K::BC1_RGB_UNORM_BLOCK | K::BC1_RGBA_UNORM_BLOCK => W::Bc1RgbaUnorm,
+      K::BC1_RGB_SRGB_BLOCK | K::BC1_RGBA_SRGB_BLOCK => W::Bc1RgbaUnormSrgb,
+      K::BC2_UNORM_BLOCK => W::Bc2RgbaUnorm,
+      K::BC2_SRGB_BLOCK => W::Bc2RgbaUnormSrgb,
+      K::BC3_UNORM_BLOCK => W::Bc3RgbaUnorm,
+      K::BC3_SRGB_BLOCK => W::Bc3RgbaUnormSrgb,
+      K::BC4_UNORM_BLOCK => W::Bc4RUnorm,
+      K::BC4_SNORM_BLOCK => W::Bc4RSnorm,
+      K::BC5_UNORM_BLOCK => W::Bc5RgUnorm,
+      K::BC5_SNORM_BLOCK => W::Bc5RgSnorm,
+      K::BC6H_UFLOAT_BLOCK => W::Bc6hRgbUfloat,
+      K::BC6H_SFLOAT_BLOCK => W::Bc6hRgbFloat,
+      K::BC7_UNORM_BLOCK => W::Bc7RgbaUnorm,
+      K::BC7_SRGB_BLOCK => W::Bc7RgbaUnormSrgb,
+      K::ETC2_R8G8B8_UNORM_BLOCK => W::Etc2Rgb8Unorm,
+      K::ETC2_R8G8B8_SRGB_BLOCK => W::Etc2Rgb8UnormSrgb,
+      K::ETC2_R8G8B8A1_UNORM_BLOCK => W::Etc2Rgb8A1Unorm,
+      K::ETC2_R8G8B8A1_SRGB_BLOCK => W::Etc2Rgb8A1UnormSrgb,
+      K::ETC2_R8G8B8A8_UNORM_BLOCK => W::Etc2Rgba8Unorm,
+      K::ETC2_R8G8B8A8_SRGB_BLOCK => W::Etc2Rgba8UnormSrgb,
+      K::EAC_R11_UNORM_BLOCK => W::EacR11Unorm,
+      K::EAC_R11_SNORM_BLOCK => W::EacR11Snorm,
+      K::EAC_R11G11_UNORM_BLOCK => W::EacRg11Unorm,
+      K::EAC_R11G11_SNORM_BLOCK => W::EacRg11Snorm,
+      _ => return astc_format(format.value()),
+   };
+   Some(format)
+}
+
+// The ASTC formats come in unorm/srgb pairs, one pair per block size, in
+// the same order as wgpu's AstcBlock. The hdr ones are numbered separately
+fn astc_format(value: u32) -> Option<wgpu::TextureFormat> {
+   use wgpu::{ AstcBlock::*, AstcChannel };
+
+   const BLOCKS: [wgpu::AstcBlock; 14] = [
+      B4x4, B5x4, B5x5, B6x5, B6x6, B8x5, B8x6, B8x8, B10x5, B10x6, B10x8, B10x10, B12x10, B12x12,
+   ];
+   const LDR_START: u32 = 157;
+   const HDR_START: u32 = 1000066000;
+
+   let (block, channel) = match value {
+      LDR_START..=184 => {
+         let i = value - LDR_START;
+         let channel = [AstcChannel::Unorm, AstcChannel::UnormSrgb][i as usize % 2];
+         (BLOCKS[i as usize / 2], channel)
+      },
+      HDR_START..=1000066013 => (BLOCKS[(value - HDR_START) as usize], AstcChannel::Hdr),
+      _ => return None,
+   };
+   Some(wgpu::TextureFormat::Astc { block, channel })
+}
+
+fn parse_dds(bytes: &[u8]) -> Result<CompressedImage> {
+   let dds = ddsfile::Dds::read(bytes)?;
+
+   ensure!(
+      dds.get_depth() <= 1 && dds.get_num_array_layers() <= 1,
+      "only 2d DDS textures are supported, not arrays, cubemaps or 3d textures"
+   );
+   let format = match (dds.get_dxgi_format(), dds.get_d3d_format()) {
+      (Some(format), _) => dxgi_format(format).ok_or_else(|| anyhow!("unsupported DDS format {:?}", format))?,
+      (None, Some(format)) => d3d_format(format).ok_or_else(|| anyhow!("unsupported DDS format {:?}", format))?,
+      (None, None) => bail!("DDS file has no format we recognise"),
+   };
+
+   let (width, height) = (dds.get_width(), dds.get_height());
+   let level_count = dds.get_num_mipmap_levels().max(1);
+
+   // All the levels are stored one after the other
+   let mut data = dds.get_data(0)?;
+   let mut levels = Vec::with_capacity(level_count as usize);
+   for mip_level in 0..level_count {
+      let size = level_byte_size(format, width, height, mip_level);
+      ensure!(data.len() >= size, "DDS file is missing data for mip level {}", mip_level);
+      let (level, rest) = data.split_at(size);
+      levels.push(level.to_vec());
+      data = rest;
+   }
+
+   Ok(CompressedImage { format, width, height, levels })
+}
+
+fn dxgi_format(format: ddsfile::DxgiFormat) -> Option<wgpu::TextureFormat> {
+   use ddsfile::DxgiFormat as D;
+   use wgpu::TextureFormat as W;
+
+   Some(match format {
+      D::R8G8B8A8_UNorm => W::Rgba8Unorm,
+      D::R8G8B8A8_UNorm_sRGB => W::Rgba8UnormSrgb,
+      D::BC1_UNorm => W::Bc1RgbaUnorm,
+      D::BC1_UNorm_sRGB => W::Bc1RgbaUnormSrgb,
+      D::BC2_UNorm => W::Bc2RgbaUnorm,
+      D::BC2_UNorm_sRGB => W::Bc2RgbaUnormSrgb,
+      D::BC3_UNorm => W::Bc3RgbaUnorm,
+      D::BC3_UNorm_sRGB => W::Bc3RgbaUnormSrgb,
+      D::BC4_UNorm => W::Bc4RUnorm,
+      D::BC4_SNorm => W::Bc4RSnorm,
+      D::BC5_UNorm => W::Bc5RgUnorm,
+      D::BC5_SNorm => W::Bc5RgSnorm,
+      D::BC6H_UF16 => W::Bc6hRgbUfloat,
+      D::BC6H_SF16 => W::Bc6hRgbFloat,
+      D::BC7_UNorm => W::Bc7RgbaUnorm,
+      D::BC7_UNorm_sRGB => W::Bc7RgbaUnormSrgb,
+      _ => return None,
+   })
+}
+
+// Older DDS files without the DX10 header only say which DXTn they are
+fn d3d_format(format: ddsfile::D3DFormat) -> Option<wgpu::TextureFormat> {
+   use ddsfile::D3DFormat as D;
+   use wgpu::TextureFormat as W;
+
+   Some(match format {
+      D::DXT1 => W::Bc1RgbaUnorm,
+      D::DXT3 => W::Bc2RgbaUnorm,
+      D::DXT5 => W::Bc3RgbaUnorm,
+      D::A8B8G8R8 => W::Rgba8Unorm,
+      _ => return None,
+   })
+}
@@ -0,0 +1,140 @@
+use wgpu::util::DeviceExt;
+
+use crate::texture::{ SamplerCache, SamplerOptions };
+
+// Cubemaps are 2d textures with 6 array layers, one per face, in this
+// order: +x, -x, +y, -y, +z, -z
+pub const FACE_COUNT: u32 = 6;
+
+pub fn create_cube_texture(
+   device: &wgpu::Device,
+   label: Option<&str>,
+   face_size: u32,
+   format: wgpu::TextureFormat,
+   mip_level_count: u32,
+   usage: wgpu::TextureUsages,
+) -> wgpu::Texture {
+   device.create_texture(&wgpu::TextureDescriptor {
+      label,
+      size: wgpu::Extent3d {
+         width: face_size,
+         height: face_size,
+         depth_or_array_layers: FACE_COUNT,
+      },
+      mip_level_count,
+      sample_count: 1,
+      dimension: wgpu::TextureDimension::D2,
+      format,
+      usage,
+      view_formats: &[],
+   })
+}
+
+// A view of one face (array layer) at one mip level, for rendering into
+pub fn face_view(texture: &wgpu::Texture, face: u32, mip_level: u32) -> wgpu::TextureView {
+   texture.create_view(&wgpu::TextureViewDescriptor {
+      label: Some("Cube Face View"),
+      dimension: Some(wgpu::TextureViewDimension::D2),
+      base_mip_level: mip_level,
+      mip_level_count: Some(1),
+      base_array_layer: face,
+      array_layer_count: Some(1),
+      ..Default::default()
+   })
+}
+
+// Fills in every face of cube (which must have RENDER_ATTACHMENT usage)
+// from an equirectangular panorama, one render pass per face. For each
+// texel we work out which direction it faces and look that direction up in
+// the panorama
+pub fn from_equirectangular(
+   device: &wgpu::Device,
+   queue: &wgpu::Queue,
+   samplers: &mut SamplerCache,
+   equirect: &wgpu::TextureView,
+   cube: &wgpu::Texture,
+   format: wgpu::TextureFormat,
+) {
+   let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+      label: Some("Equirect Shader"),
+      source: wgpu::ShaderSource::Wgsl(include_str!("equirect.wgsl").into()),
+   });
+
+   let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+      label: Some("Equirect Pipeline"),
+      layout: None,
+      vertex: wgpu::VertexState {
+         module: &shader,
+         entry_point: "vs_main",
+         buffers: &[],
+      },
+      fragment: Some(wgpu::FragmentState {
+         module: &shader,
+         entry_point: "fs_main",
+         targets: &[Some(format.into())],
+      }),
+      primitive: wgpu::PrimitiveState {
+         topology: wgpu::PrimitiveTopology::TriangleList,
+         ..Default::default()
+      },
+      depth_stencil: None,
+      multisample: wgpu::MultisampleState::default(),
+      multiview: None,
+   });
+   let bind_group_layout = pipeline.get_bind_group_layout(0);
+
+   // Repeat across the seam at the back of the panorama
+   let sampler = samplers.get(device, &SamplerOptions::default()
+      .address_modes(wgpu::AddressMode::Repeat, wgpu::AddressMode::ClampToEdge, wgpu::AddressMode::ClampToEdge)
+      .filters(wgpu::FilterMode::Linear, wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest));
+
+   let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+      label: Some("Equirect Encoder"),
+   });
+
+   for face in 0..FACE_COUNT {
+      // The face index goes in a uniform, padded out to 16 bytes
+      let face_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+         label: Some("Cube Face Buffer"),
+         contents: bytemuck::cast_slice(&[face, 0, 0, 0]),
+         usage: wgpu::BufferUsages::UNIFORM,
+      });
+      let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+         label: None,
+         layout: &bind_group_layout,
+         entries: &[
+            wgpu::BindGroupEntry {
+               binding: 0,
+               resource: wgpu::BindingResource::TextureView(equirect),
+            },
+            wgpu::BindGroupEntry {
+               binding: 1,
+               resource: wgpu::BindingResource::Sampler(&sampler),
+            },
+            wgpu::BindGroupEntry {
+               binding: 2,
+               resource: face_buffer.as_entire_binding(),
+            },
+         ],
+      });
+
+      let view = face_view(cube, face, 0);
+      let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+         label: Some("Equirect Pass"),
+         color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: &view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+               load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+               store: true,
+            },
+         })],
+         depth_stencil_attachment: None,
+      });
+      render_pass.set_pipeline(&pipeline);
+      render_pass.set_bind_group(0, &bind_group, &[]);
+      render_pass.draw(0..3, 0..1);
+   }
+
+   queue.submit(std::iter::once(encoder.finish()));
+}
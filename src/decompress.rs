@@ -0,0 +1,366 @@
+use anyhow::*;
+
+// Cpu decoders for block compressed formats, for when the device can't
+// sample them itself. Every format here splits the image into 4x4 pixel
+// blocks, and each block decodes on its own into 16 pixels. We decode into
+// plain RGBA8 - which costs 4-8 times the memory, but at least it works.
+//
+// Only the formats with simple block layouts are here: BC1-5 and
+// ETC2/EAC. BC6H, BC7 and ASTC pack a lot more modes into each block
+// (and BC6H holds hdr values, which don't fit in RGBA8), so loading those
+// on a device without support for them fails with an error instead
+
+type Block = [[u8; 4]; 16];
+
+pub fn can_decompress(format: wgpu::TextureFormat) -> bool {
+   block_decoder(format).is_some()
+}
+
+// Decodes one mip level. The values come out exactly as stored, so for
+// the sRGB formats they're still sRGB encoded
+pub fn decompress(format: wgpu::TextureFormat, width: u32, height: u32, data: &[u8]) -> Result<image::RgbaImage> {
+   let decode_block = block_decoder(format)
+      .ok_or_else(|| anyhow!("can't decompress {:?} on the cpu", format))?;
+   let block_size = format.block_size(None).unwrap() as usize;
+   let blocks_wide = width.div_ceil(4);
+   ensure!(
+      data.len() == (blocks_wide * height.div_ceil(4)) as usize * block_size,
+      "{:?} data is the wrong size for {}x{}", format, width, height
+   );
+
+   let mut img = image::RgbaImage::new(width, height);
+   for (i, block) in data.chunks_exact(block_size).enumerate() {
+      let block_x = i as u32 % blocks_wide * 4;
+      let block_y = i as u32 / blocks_wide * 4;
+      for (p, pixel) in decode_block(block).into_iter().enumerate() {
+         // Edge blocks hang off the image when it isn't a multiple of 4
+         let (x, y) = (block_x + p as u32 % 4, block_y + p as u32 / 4);
+         if x < width && y < height {
+            img.put_pixel(x, y, image::Rgba(pixel));
+         }
+      }
+   }
+   Ok(img)
+}
+
+// Decoders return their pixels row by row
+fn block_decoder(format: wgpu::TextureFormat) -> Option<fn(&[u8]) -> Block> {
+   use wgpu::TextureFormat::*;
+
+   Some(match format.remove_srgb_suffix() {
+      Bc1RgbaUnorm => |block| bc1(block, true),
+      Bc2RgbaUnorm => bc2,
+      Bc3RgbaUnorm => bc3,
+      Bc4RUnorm => bc4,
+      Bc5RgUnorm => bc5,
+      Etc2Rgb8Unorm => |block| etc2(block, false),
+      Etc2Rgb8A1Unorm => |block| etc2(block, true),
+      Etc2Rgba8Unorm => etc2_rgba8,
+      EacR11Unorm => eac_r11,
+      EacRg11Unorm => eac_rg11,
+      _ => return None,
+   })
+}
+
+// BCn
+
+// Two RGB565 end points and a 2 bit index per pixel, picking one of four
+// colors along the line between them. If the first end point is the smaller
+// one there are only three, and the fourth is transparent black instead -
+// but only in BC1. BC2/3 always use four colors
+fn bc1(block: &[u8], allow_transparent: bool) -> Block {
+   let c0 = u16::from_le_bytes([block[0], block[1]]);
+   let c1 = u16::from_le_bytes([block[2], block[3]]);
+   let (e0, e1) = (rgb565(c0), rgb565(c1));
+   let mix = |w0: u32, w1: u32| -> [u8; 4] {
+      let channel = |i: usize| ((e0[i] as u32 * w0 + e1[i] as u32 * w1) / (w0 + w1)) as u8;
+      [channel(0), channel(1), channel(2), 255]
+   };
+
+   let palette = if c0 > c1 || !allow_transparent {
+      [e0, e1, mix(2, 1), mix(1, 2)]
+   } else {
+      [e0, e1, mix(1, 1), [0, 0, 0, 0]]
+   };
+   let indices = u32::from_le_bytes(block[4..8].try_into().unwrap());
+   std::array::from_fn(|p| palette[(indices >> (2 * p)) as usize & 3])
+}
+
+fn rgb565(color: u16) -> [u8; 4] {
+   let (r, g, b) = ((color >> 11) & 31, (color >> 5) & 63, color & 31);
+   [(r << 3 | r >> 2) as u8, (g << 2 | g >> 4) as u8, (b << 3 | b >> 2) as u8, 255]
+}
+
+// BC1 colors after 4 bits of alpha per pixel
+fn bc2(block: &[u8]) -> Block {
+   let alpha = u64::from_le_bytes(block[0..8].try_into().unwrap());
+   let mut pixels = bc1(&block[8..16], false);
+   for (p, pixel) in pixels.iter_mut().enumerate() {
+      pixel[3] = ((alpha >> (4 * p)) & 15) as u8 * 17;
+   }
+   pixels
+}
+
+// BC1 colors after a BC4 style alpha block
+fn bc3(block: &[u8]) -> Block {
+   let alpha = bc4_channel(&block[0..8]);
+   let mut pixels = bc1(&block[8..16], false);
+   for (pixel, alpha) in pixels.iter_mut().zip(alpha) {
+      pixel[3] = alpha;
+   }
+   pixels
+}
+
+fn bc4(block: &[u8]) -> Block {
+   bc4_channel(block).map(|r| [r, 0, 0, 255])
+}
+
+fn bc5(block: &[u8]) -> Block {
+   let red = bc4_channel(&block[0..8]);
+   let green = bc4_channel(&block[8..16]);
+   std::array::from_fn(|p| [red[p], green[p], 0, 255])
+}
+
+// A single channel: two 8 bit end points and a 3 bit index per pixel. If
+// the first end point is the larger there are six values between them,
+// otherwise four, plus 0 and 255
+fn bc4_channel(block: &[u8]) -> [u8; 16] {
+   let (a0, a1) = (block[0] as u32, block[1] as u32);
+   let palette: [u32; 8] = if a0 > a1 {
+      std::array::from_fn(|i| match i {
+         0 => a0,
+         1 => a1,
+         i => (a0 * (8 - i as u32) + a1 * (i as u32 - 1)) / 7,
+      })
+   } else {
+      std::array::from_fn(|i| match i {
+         0 => a0,
+         1 => a1,
+         6 => 0,
+         7 => 255,
+         i => (a0 * (6 - i as u32) + a1 * (i as u32 - 1)) / 5,
+      })
+   };
+
+   let mut bytes = [0; 8];
+   bytes[..6].copy_from_slice(&block[2..8]);
+   let indices = u64::from_le_bytes(bytes);
+   std::array::from_fn(|p| palette[(indices >> (3 * p)) as usize & 7] as u8)
+}
+
+// ETC2 / EAC
+
+const ETC_MODIFIERS: [[i32; 2]; 8] = [
+   [2, 8], [5, 17], [9, 29], [13, 42], [18, 60], [24, 80], [33, 106], [47, 183],
+];
+const ETC_DISTANCES: [i32; 8] = [3, 6, 11, 16, 23, 32, 41, 64];
+
+const EAC_MODIFIERS: [[i32; 8]; 16] = [
+   [-3, -6, -9, -15, 2, 5, 8, 14],
+   [-3, -7, -10, -13, 2, 6, 9, 12],
+   [-2, -5, -8, -13, 1, 4, 7, 12],
+   [-2, -4, -6, -13, 1, 3, 5, 12],
+   [-3, -6, -8, -12, 2, 5, 7, 11],
+   [-3, -7, -9, -11, 2, 6, 8, 10],
+   [-4, -7, -8, -11, 3, 6, 7, 10],
+   [-3, -5, -8, -11, 2, 4, 7, 10],
+   [-2, -6, -8, -10, 1, 5, 7, 9],
+   [-2, -5, -8, -10, 1, 4, 7, 9],
+   [-2, -4, -8, -10, 1, 3, 7, 9],
+   [-2, -5, -7, -10, 1, 4, 6, 9],
+   [-3, -4, -7, -10, 2, 3, 6, 9],
+   [-1, -2, -3, -10, 0, 1, 2, 9],
+   [-4, -6, -8, -9, 3, 5, 7, 8],
+   [-3, -5, -7, -9, 2, 4, 6, 8],
+];
+
+// ETC blocks are big endian 64 bit words, and number their pixels down
+// each column rather than along each row
+fn etc_pixel(x: usize, y: usize) -> usize {
+   x * 4 + y
+}
+
+// The ETC2 RGB block. It's ETC1 - two half blocks, each a base color plus
+// a per pixel brightness modifier - with three extra modes (T, H and
+// planar) squeezed into combinations ETC1 never used: a differential
+// base color whose red, green or blue overflows.
+//
+// With punchthrough (RGB8A1) the ETC1 "differential" bit says whether the
+// block is opaque instead, and when it isn't one index means transparent
+fn etc2(block: &[u8], punchthrough: bool) -> Block {
+   let word = u64::from_be_bytes(block[0..8].try_into().unwrap());
+   let bits = |high: u32, low: u32| ((word >> low) & ((1 << (high - low + 1)) - 1)) as i32;
+   let index = |p: usize| (bits(16 + p as u32, 16 + p as u32) << 1) | bits(p as u32, p as u32);
+
+   let opaque = !punchthrough || bits(33, 33) == 1;
+   let differential = punchthrough || bits(33, 33) == 1;
+   let transparent = |index: i32| !opaque && index == 2;
+
+   // Each mode ends up with one color per pixel, before alpha
+   let colors: [[i32; 3]; 16] = if !differential {
+      let base = [
+         [bits(63, 60), bits(55, 52), bits(47, 44)].map(extend_4),
+         [bits(59, 56), bits(51, 48), bits(43, 40)].map(extend_4),
+      ];
+      etc_half_blocks(bits, &index, base, opaque)
+   } else {
+      let signed = |delta: i32| if delta >= 4 { delta - 8 } else { delta };
+      let (r, g, b) = (bits(63, 59), bits(55, 51), bits(47, 43));
+      let (r2, g2, b2) = (r + signed(bits(58, 56)), g + signed(bits(50, 48)), b + signed(bits(42, 40)));
+
+      if !(0..32).contains(&r2) {
+         // T mode
+         let c1 = [(bits(60, 59) << 2) | bits(57, 56), bits(55, 52), bits(51, 48)].map(extend_4);
+         let c2 = [bits(47, 44), bits(43, 40), bits(39, 36)].map(extend_4);
+         let d = ETC_DISTANCES[((bits(35, 34) << 1) | bits(32, 32)) as usize];
+         let paint = [c1, add(c2, d), c2, add(c2, -d)];
+         std::array::from_fn(|p| paint[index(p) as usize])
+      } else if !(0..32).contains(&g2) {
+         // H mode - which way round the two colors are stored is the
+         // last bit of the distance
+         let c1 = [bits(62, 59), (bits(58, 56) << 1) | bits(52, 52), (bits(51, 51) << 3) | bits(49, 47)];
+         let c2 = [bits(46, 43), bits(42, 39), bits(38, 35)];
+         let packed = |c: [i32; 3]| (c[0] << 8) | (c[1] << 4) | c[2];
+         let order = (packed(c1) >= packed(c2)) as i32;
+         let d = ETC_DISTANCES[((bits(34, 34) << 2) | (bits(32, 32) << 1) | order) as usize];
+         let (c1, c2) = (c1.map(extend_4), c2.map(extend_4));
+         let paint = [add(c1, d), add(c1, -d), add(c2, d), add(c2, -d)];
+         std::array::from_fn(|p| paint[index(p) as usize])
+      } else if !(0..32).contains(&b2) {
+         // Planar mode - a gradient from three colors, and always opaque
+         let origin = [
+            extend_6(bits(62, 57)),
+            extend_7((bits(56, 56) << 6) | bits(54, 49)),
+            extend_6((bits(48, 48) << 5) | (bits(44, 43) << 3) | bits(41, 39)),
+         ];
+         let horizontal = [extend_6((bits(38, 34) << 1) | bits(32, 32)), extend_7(bits(31, 25)), extend_6(bits(24, 19))];
+         let vertical = [extend_6(bits(18, 13)), extend_7(bits(12, 6)), extend_6(bits(5, 0))];
+         let mut colors = [[0; 3]; 16];
+         for (x, y) in (0..4).flat_map(|x| (0..4).map(move |y| (x, y))) {
+            colors[etc_pixel(x, y)] = std::array::from_fn(|c| {
+               let (o, h, v) = (origin[c], horizontal[c], vertical[c]);
+               (x as i32 * (h - o) + y as i32 * (v - o) + 4 * o + 2) >> 2
+            });
+         }
+         return row_major(|p| {
+            let [r, g, b] = colors[p].map(clamp_u8);
+            [r, g, b, 255]
+         });
+      } else {
+         let base = [[r, g, b].map(extend_5), [r2, g2, b2].map(extend_5)];
+         etc_half_blocks(bits, &index, base, opaque)
+      }
+   };
+
+   row_major(|p| {
+      if transparent(index(p)) {
+         [0, 0, 0, 0]
+      } else {
+         let [r, g, b] = colors[p].map(clamp_u8);
+         [r, g, b, 255]
+      }
+   })
+}
+
+// The ETC1 modes: the block is split into two halves (side by side, or
+// on top of each other if the flip bit is set), each with a base color and
+// a table of modifiers to add to it
+fn etc_half_blocks<B, I>(bits: B, index: &I, base: [[i32; 3]; 2], opaque: bool) -> [[i32; 3]; 16]
+where
+   B: Fn(u32, u32) -> i32,
+   I: Fn(usize) -> i32,
+{
+   let flip = bits(32, 32) == 1;
+   let tables = [bits(39, 37), bits(36, 34)];
+   let mut colors = [[0; 3]; 16];
+   for (x, y) in (0..4).flat_map(|x| (0..4).map(move |y| (x, y))) {
+      let p = etc_pixel(x, y);
+      let half = if flip { y >= 2 } else { x >= 2 } as usize;
+      let [small, large] = ETC_MODIFIERS[tables[half] as usize];
+      let modifier = match index(p) {
+         // Non-opaque punchthrough blocks give up the small modifiers
+         0 if !opaque => 0,
+         0 => small,
+         1 => large,
+         2 => -small,
+         _ => -large,
+      };
+      colors[p] = add(base[half], modifier);
+   }
+   colors
+}
+
+// EAC alpha, then ETC2 color
+fn etc2_rgba8(block: &[u8]) -> Block {
+   let alpha = eac_channel(&block[0..8], false);
+   let mut pixels = etc2(&block[8..16], false);
+   for (pixel, alpha) in pixels.iter_mut().zip(alpha) {
+      pixel[3] = alpha;
+   }
+   pixels
+}
+
+fn eac_r11(block: &[u8]) -> Block {
+   eac_channel(block, true).map(|r| [r, 0, 0, 255])
+}
+
+fn eac_rg11(block: &[u8]) -> Block {
+   let red = eac_channel(&block[0..8], true);
+   let green = eac_channel(&block[8..16], true);
+   std::array::from_fn(|p| [red[p], green[p], 0, 255])
+}
+
+// A single channel: a base value, plus one of eight modifiers per pixel
+// (from one of 16 tables) scaled by a multiplier. The 11 bit version used
+// by the R11/RG11 formats is scaled up before the modifier is added, which
+// we then round back down to 8 bits
+fn eac_channel(block: &[u8], eleven_bit: bool) -> [u8; 16] {
+   let word = u64::from_be_bytes(block[0..8].try_into().unwrap());
+   let base = (word >> 56) as i32;
+   let multiplier = ((word >> 52) & 15) as i32;
+   let table = EAC_MODIFIERS[((word >> 48) & 15) as usize];
+
+   let mut values = [0; 16];
+   for (x, y) in (0..4).flat_map(|x| (0..4).map(move |y| (x, y))) {
+      let p = etc_pixel(x, y);
+      let modifier = table[((word >> (45 - 3 * p)) & 7) as usize];
+      values[p] = if eleven_bit {
+         let scale = if multiplier == 0 { 1 } else { multiplier * 8 };
+         let value = (base * 8 + 4 + modifier * scale).clamp(0, 2047);
+         ((value * 255 + 1023) / 2047) as u8
+      } else {
+         clamp_u8(base + modifier * multiplier)
+      };
+   }
+   row_major(|p| values[p])
+}
+
+// Reorders 16 column by column pixels into rows
+fn row_major<T>(pixel: impl Fn(usize) -> T) -> [T; 16] {
+   std::array::from_fn(|p| pixel(etc_pixel(p % 4, p / 4)))
+}
+
+fn add(color: [i32; 3], amount: i32) -> [i32; 3] {
+   color.map(|c| c + amount)
+}
+
+fn clamp_u8(value: i32) -> u8 {
+   value.clamp(0, 255) as u8
+}
+
+// Expands an n bit value to 8 bits by repeating its top bits
+fn extend_4(value: i32) -> i32 {
+   (value << 4) | value
+}
+
+fn extend_5(value: i32) -> i32 {
+   (value << 3) | (value >> 2)
+}
+
+fn extend_6(value: i32) -> i32 {
+   (value << 2) | (value >> 4)
+}
+
+fn extend_7(value: i32) -> i32 {
+   (value << 1) | (value >> 6)
+}
@@ -0,0 +1,59 @@
+// Renders one face of a cubemap from an equirectangular panorama - the
+// kind of 2:1 image where x is the angle around the horizon and y the
+// angle up from straight down
+
+struct Face {
+   // Which face we're drawing, in wgpu's order: +x, -x, +y, -y, +z, -z
+   index: u32,
+   _padding0: u32,
+   _padding1: u32,
+   _padding2: u32,
+};
+@group(0) @binding(2)
+var<uniform> face: Face;
+
+struct VertexOutput {
+   @builtin(position) clip_position: vec4<f32>,
+   // -1 to 1 across the face, y pointing down like texture coordinates
+   @location(0) face_coords: vec2<f32>,
+};
+
+// The same full screen triangle as blit.wgsl
+@vertex
+fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> VertexOutput {
+   var out: VertexOutput;
+   let x = f32(i32(in_vertex_index & 1u) * 4 - 1);
+   let y = f32(1 - i32(in_vertex_index & 2u) * 2);
+   out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+   out.face_coords = vec2<f32>(x, -y);
+   return out;
+}
+
+@group(0) @binding(0)
+var t_equirect: texture_2d<f32>;
+@group(0) @binding(1)
+var s_equirect: sampler;
+
+const PI: f32 = 3.14159265359;
+
+// The direction a texel of a cubemap face looks in. This is the table from
+// the cubemap section of the Vulkan/D3D specs, which wgpu follows
+fn face_direction(index: u32, uv: vec2<f32>) -> vec3<f32> {
+   switch index {
+      case 0u: { return vec3<f32>(1.0, -uv.y, -uv.x); }
+      case 1u: { return vec3<f32>(-1.0, -uv.y, uv.x); }
+      case 2u: { return vec3<f32>(uv.x, 1.0, uv.y); }
+      case 3u: { return vec3<f32>(uv.x, -1.0, -uv.y); }
+      case 4u: { return vec3<f32>(uv.x, -uv.y, 1.0); }
+      default: { return vec3<f32>(-uv.x, -uv.y, -1.0); }
+   }
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+   let dir = normalize(face_direction(face.index, in.face_coords));
+   // -z is the middle of the panorama, the seam is behind it at +z
+   let u = atan2(dir.x, -dir.z) / (2.0 * PI) + 0.5;
+   let v = acos(clamp(dir.y, -1.0, 1.0)) / PI;
+   return textureSampleLevel(t_equirect, s_equirect, vec2<f32>(u, v), 0.0);
+}
@@ -0,0 +1,183 @@
+use std::{
+   collections::{ HashMap, HashSet },
+   path::{ Path, PathBuf },
+   sync::mpsc,
+   time::{ Duration, Instant },
+};
+
+use anyhow::*;
+use notify::Watcher;
+
+// Tells us when files we care about change on disk, so they can be hot
+// reloaded.
+//
+// 1. We watch the directory each file is in rather than the file itself.
+//       Lots of editors save by writing a new file and renaming it over the
+//       old one, and a watch on the old file stops working after that
+//
+// 2. A save usually arrives as a burst of events (truncate, write, write...)
+//       and reading in the middle of one gets half a file. So a file is
+//       only reported once it has been quiet for DEBOUNCE
+pub struct FileWatcher {
+   watcher: notify::RecommendedWatcher,
+   events: mpsc::Receiver<notify::Result<notify::Event>>,
+   files: HashSet<PathBuf>,
+   dirs: HashSet<PathBuf>,
+   // When each changed file last had an event
+   pending: HashMap<PathBuf, Instant>,
+}
+
+impl FileWatcher {
+   const DEBOUNCE: Duration = Duration::from_millis(100);
+
+   pub fn new() -> Result<Self> {
+      // Events arrive on notify's own thread, the channel gets them to
+      // whichever thread calls changed()
+      let (sender, events) = mpsc::channel();
+      let watcher = notify::recommended_watcher(sender)?;
+
+      Ok(Self {
+         watcher,
+         events,
+         files: HashSet::new(),
+         dirs: HashSet::new(),
+         pending: HashMap::new(),
+      })
+   }
+
+   // Paths are canonicalized, so changed() always reports a file the same
+   // way however it was named here. Returns the canonical path
+   pub fn watch(&mut self, path: &Path) -> Result<PathBuf> {
+      let path = path.canonicalize()
+         .with_context(|| format!("Couldn't watch {}", path.display()))?;
+      let dir = path.parent().unwrap_or(&path).to_path_buf();
+      if !self.dirs.contains(&dir) {
+         self.watcher.watch(&dir, notify::RecursiveMode::NonRecursive)?;
+         self.dirs.insert(dir);
+      }
+      self.files.insert(path.clone());
+      Ok(path)
+   }
+
+   // The watched files that have changed (and then settled down) since
+   // the last call. Meant to be called every frame
+   pub fn changed(&mut self) -> Vec<PathBuf> {
+      let events = self.events.try_iter()
+         .filter_map(|event| event.map_err(|e| log::warn!("File watcher error: {}", e)).ok())
+         .collect::<Vec<_>>();
+      self.debounce(events, Instant::now())
+   }
+
+   // The debouncing half of changed(). now is passed in rather than read
+   // here, so the tests can step through time without sleeping
+   fn debounce(&mut self, events: Vec<notify::Event>, now: Instant) -> Vec<PathBuf> {
+      let events = events.into_iter()
+         .filter(|event| event.kind.is_create() || event.kind.is_modify());
+      for event in events {
+         for path in event.paths.into_iter().filter(|path| self.files.contains(path)) {
+            self.pending.insert(path, now);
+         }
+      }
+
+      let settled = self.pending.iter()
+         .filter(|(_, last_event)| now.duration_since(**last_event) >= Self::DEBOUNCE)
+         .map(|(path, _)| path.clone())
+         .collect::<Vec<_>>();
+      for path in &settled {
+         self.pending.remove(path);
+      }
+      settled
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use notify::event::{ AccessKind, CreateKind, DataChange, EventKind, ModifyKind };
+
+   // A directory of its own for each test, so tests running at the same
+   // time (or a previous run's leftovers) can't trigger each other's
+   // watches
+   fn test_dir(name: &str) -> PathBuf {
+      let dir = std::env::temp_dir()
+         .join(format!("wgpu_tutorial_file_watcher_{}_{}", std::process::id(), name));
+      let _ = std::fs::remove_dir_all(&dir);
+      std::fs::create_dir_all(&dir).unwrap();
+      dir
+   }
+
+   fn write_event(path: &Path) -> notify::Event {
+      notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any))).add_path(path.to_path_buf())
+   }
+
+   // A watcher that knows about path, without it having to exist. Events
+   // go straight to debounce
+   fn watching(path: &Path) -> FileWatcher {
+      let mut watcher = FileWatcher::new().unwrap();
+      watcher.files.insert(path.to_path_buf());
+      watcher
+   }
+
+   #[test]
+   fn burst_of_writes_is_reported_once() {
+      let path = PathBuf::from("/watched.txt");
+      let mut watcher = watching(&path);
+      let start = Instant::now();
+      let ms = |ms| start + Duration::from_millis(ms);
+
+      // Writes closer together than DEBOUNCE, polled in between like a
+      // frame loop would. Files nobody watches are ignored
+      for time in [0, 30, 60] {
+         let events = vec![write_event(&path), write_event(Path::new("/not_watched.txt"))];
+         assert!(watcher.debounce(events, ms(time)).is_empty());
+      }
+      assert!(watcher.debounce(Vec::new(), ms(159)).is_empty());
+      assert_eq!(watcher.debounce(Vec::new(), ms(160)), vec![path]);
+      assert!(watcher.debounce(Vec::new(), ms(1000)).is_empty());
+   }
+
+   #[test]
+   fn only_creates_and_modifies_count() {
+      let path = PathBuf::from("/watched.txt");
+      let mut watcher = watching(&path);
+      let start = Instant::now();
+
+      let read = notify::Event::new(EventKind::Access(AccessKind::Any)).add_path(path.clone());
+      assert!(watcher.debounce(vec![read], start).is_empty());
+      assert!(watcher.debounce(Vec::new(), start + FileWatcher::DEBOUNCE).is_empty());
+
+      let created = notify::Event::new(EventKind::Create(CreateKind::File)).add_path(path.clone());
+      assert!(watcher.debounce(vec![created], start).is_empty());
+      assert_eq!(watcher.debounce(Vec::new(), start + FileWatcher::DEBOUNCE), vec![path]);
+   }
+
+   // The one test that goes through the file system, for the part that
+   // depends on it: the watch has to survive the file being replaced
+   #[test]
+   fn atomic_rename_save_is_reported_once() {
+      let dir = test_dir("rename");
+      let path = dir.join("watched.txt");
+      std::fs::write(&path, "a").unwrap();
+
+      let mut watcher = FileWatcher::new().unwrap();
+      let path = watcher.watch(&path).unwrap();
+      // Save the way lots of editors do, then do it again to check the
+      // watch survives the file being replaced. Polls for a second, long
+      // enough for the save to settle and for anything reported twice to
+      // show up
+      for contents in ["b", "c"] {
+         let temp = dir.join("watched.txt.tmp");
+         std::fs::write(&temp, contents).unwrap();
+         std::fs::rename(&temp, &path).unwrap();
+
+         let start = Instant::now();
+         let mut changed = Vec::new();
+         while start.elapsed() < Duration::from_secs(1) {
+            changed.extend(watcher.changed());
+            std::thread::sleep(Duration::from_millis(10));
+         }
+         assert_eq!(changed, vec![path.clone()]);
+      }
+      std::fs::remove_dir_all(&dir).unwrap();
+   }
+}
@@ -0,0 +1,356 @@
+use std::path::Path;
+
+use anyhow::*;
+use base64::Engine;
+use cgmath::prelude::*;
+
+use crate::{
+   model::{
+      self, Material, MaterialDesc, MaterialFactors, MaterialTexture, Mesh, Model, Node,
+      TextureSlot, TextureSource, Vertex,
+   },
+   texture,
+};
+
+impl Model {
+   // Loads a .gltf (plus its .bin and image files) or a .glb from disk.
+   // External files are looked up relative to the glTF file's directory.
+   // Textures are loaded with options, like Model::load
+   pub fn load_gltf<P: AsRef<Path>>(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      layout: &wgpu::BindGroupLayout,
+      samplers: &mut texture::SamplerCache,
+      options: texture::TextureOptions,
+      path: P,
+   ) -> Result<Self> {
+      let path = path.as_ref();
+      let dir = path.parent().unwrap_or_else(|| Path::new(""));
+      let bytes = std::fs::read(path)
+         .with_context(|| format!("Couldn't read {}", path.display()))?;
+
+      let mut model = Self::from_gltf_bytes(device, queue, layout, samplers, options, &bytes, |name| {
+         let file = dir.join(name);
+         std::fs::read(&file).with_context(|| format!("Couldn't read {}", file.display()))
+      })?;
+      model.resolve_texture_paths(dir);
+      Ok(model)
+   }
+
+   // Builds a model from the contents of a .gltf or .glb file. Like
+   // from_obj_bytes, load_file is called with the (relative) uri of every
+   // external buffer and image. data: uris are decoded here and never
+   // reach load_file
+   //
+   // Our pipeline only has one model matrix per instance, so each node's
+   // world transform gets baked into the vertices of the meshes built for
+   // it. The hierarchy is still there in Model::nodes for anyone who needs
+   // to walk it
+   pub fn from_gltf_bytes<F>(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      layout: &wgpu::BindGroupLayout,
+      samplers: &mut texture::SamplerCache,
+      options: texture::TextureOptions,
+      bytes: &[u8],
+      load_file: F,
+   ) -> Result<Self>
+   where
+      F: Fn(&str) -> Result<Vec<u8>>,
+   {
+      // from_slice works out whether this is a .glb (binary) or .gltf (json)
+      let gltf::Gltf { document, mut blob } = gltf::Gltf::from_slice(bytes)?;
+
+      let buffers = document.buffers().map(|buffer| {
+         let mut data = match buffer.source() {
+            gltf::buffer::Source::Bin => blob.take()
+               .ok_or_else(|| anyhow!("glTF refers to a binary chunk it doesn't have"))?,
+            gltf::buffer::Source::Uri(uri) => load_uri(uri, &load_file)?,
+         };
+         ensure!(data.len() >= buffer.length(), "buffer {} is too short", buffer.index());
+         // glb chunks are padded to 4 bytes, the accessors don't expect that
+         data.truncate(buffer.length());
+         Ok(data)
+      }).collect::<Result<Vec<_>>>()?;
+
+      let images = document.images().map(|image| {
+         let encoded = match image.source() {
+            gltf::image::Source::View { view, .. } => buffers.get(view.buffer().index())
+               .and_then(|buffer| buffer.get(view.offset()..view.offset() + view.length()))
+               .ok_or_else(|| anyhow!("image {} reaches past the end of its buffer", image.index()))?
+               .to_vec(),
+            gltf::image::Source::Uri { uri, .. } => load_uri(uri, &load_file)?,
+         };
+         image::load_from_memory(&encoded)
+            .with_context(|| format!("Couldn't decode image {}", image.index()))
+      }).collect::<Result<Vec<_>>>()?;
+
+      // Each texture is loaded in the color space its slot needs - the same
+      // image could in theory be used for two slots, but never is in
+      // practice. glTF's factors are linear, and go straight into the
+      // material's uniform
+      let mut load_texture = |texture: gltf::Texture, slot: TextureSlot, name: &str| -> Result<MaterialTexture> {
+         let source = texture.source();
+         let img = &images[source.index()];
+         let options = options
+            .color_space(slot.color_space())
+            .sampler(sampler_options(options.sampler, &texture.sampler()));
+         let loaded = MaterialTexture::new(texture::Texture::from_image_with_options(
+            device, queue, samplers, img, Some(name), options
+         )?);
+         // Only images in files of their own can be reloaded
+         Ok(match source.source() {
+            gltf::image::Source::Uri { uri, .. } if !uri.starts_with("data:") => {
+               loaded.with_source(TextureSource { path: percent_decode(uri).into(), options })
+            },
+            _ => loaded,
+         })
+      };
+
+      let mut descs = document.materials().map(|material| {
+         let name = material.name().unwrap_or("material").to_string();
+         let pbr = material.pbr_metallic_roughness();
+         let mut desc = MaterialDesc::default().factors(MaterialFactors {
+            base_color: pbr.base_color_factor(),
+            metallic: pbr.metallic_factor(),
+            roughness: pbr.roughness_factor(),
+            normal_scale: material.normal_texture().map_or(1.0, |normal| normal.scale()),
+            occlusion_strength: material.occlusion_texture().map_or(1.0, |occlusion| occlusion.strength()),
+            emissive: material.emissive_factor(),
+         });
+
+         let textures = [
+            (TextureSlot::BaseColor, pbr.base_color_texture().map(|info| info.texture())),
+            (TextureSlot::MetallicRoughness, pbr.metallic_roughness_texture().map(|info| info.texture())),
+            (TextureSlot::Normal, material.normal_texture().map(|normal| normal.texture())),
+            (TextureSlot::Occlusion, material.occlusion_texture().map(|occlusion| occlusion.texture())),
+            (TextureSlot::Emissive, material.emissive_texture().map(|info| info.texture())),
+         ];
+         for (slot, texture) in textures {
+            if let Some(texture) = texture {
+               desc = desc.texture(slot, load_texture(texture, slot, &name)?);
+            }
+         }
+         Ok((name, desc))
+      }).collect::<Result<Vec<_>>>()?;
+
+      // Primitives without a material get the glTF default material, which
+      // is white, fully metallic and fully rough
+      let default_material = descs.len();
+      descs.push(("default".to_string(), MaterialDesc::default().factors(MaterialFactors {
+         metallic: 1.0,
+         roughness: 1.0,
+         ..Default::default()
+      })));
+      let materials = descs.into_iter()
+         .map(|(name, desc)| Material::new(device, queue, samplers, &name, desc, layout))
+         .collect::<Vec<_>>();
+
+      let mut nodes = document.nodes().map(|node| Node {
+         name: node.name().map(str::to_string),
+         transform: node.transform().matrix().into(),
+         children: node.children().map(|child| child.index()).collect(),
+         meshes: Vec::new(),
+      }).collect::<Vec<_>>();
+
+      let scene = document.default_scene()
+         .or_else(|| document.scenes().next())
+         .ok_or_else(|| anyhow!("glTF file has no scenes"))?;
+      let root_nodes = scene.nodes().map(|node| node.index()).collect::<Vec<_>>();
+
+      // Walk the hierarchy from the roots, carrying the parent's world
+      // transform down to its children. glTF nodes form trees, so reaching
+      // a node twice means the file is broken - and if it's a cycle, we'd
+      // go round it forever
+      let document_nodes = document.nodes().collect::<Vec<_>>();
+      let mut visited = vec![false; nodes.len()];
+      let mut meshes = Vec::new();
+      let mut stack = root_nodes.iter()
+         .map(|&index| (index, cgmath::Matrix4::identity()))
+         .collect::<Vec<_>>();
+      while let Some((index, parent_transform)) = stack.pop() {
+         ensure!(!visited[index], "glTF node {} is reached more than once in the scene's hierarchy", index);
+         visited[index] = true;
+         let world = parent_transform * nodes[index].transform;
+         let node = &document_nodes[index];
+
+         if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+               if primitive.mode() != gltf::mesh::Mode::Triangles {
+                  log::warn!("Skipping {:?} primitive in mesh {}, only triangles are supported", primitive.mode(), mesh.index());
+                  continue;
+               }
+               let name = mesh.name().map(str::to_string)
+                  .unwrap_or_else(|| format!("mesh {}", mesh.index()));
+               let material = primitive.material().index().unwrap_or(default_material);
+               let built = build_mesh(device, &name, &primitive, &buffers, world, material)?;
+               nodes[index].meshes.push(meshes.len());
+               meshes.push(built);
+            }
+         }
+
+         stack.extend(nodes[index].children.iter().map(|&child| (child, world)));
+      }
+
+      Ok(Self {
+         meshes,
+         materials,
+         nodes,
+         root_nodes,
+      })
+   }
+}
+
+fn build_mesh(
+   device: &wgpu::Device,
+   name: &str,
+   primitive: &gltf::Primitive,
+   buffers: &[Vec<u8>],
+   transform: cgmath::Matrix4<f32>,
+   material: usize,
+) -> Result<Mesh> {
+   let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+
+   let positions = reader.read_positions()
+      .ok_or_else(|| anyhow!("{} has a primitive without positions", name))?
+      .collect::<Vec<_>>();
+   let normals = reader.read_normals().map(|n| n.collect::<Vec<_>>());
+   let tex_coords = reader.read_tex_coords(0).map(|t| t.into_f32().collect::<Vec<_>>());
+   ensure!(
+      normals.as_ref().is_none_or(|n| n.len() == positions.len()),
+      "{} has a primitive with a different number of normals than positions", name
+   );
+   ensure!(
+      tex_coords.as_ref().is_none_or(|t| t.len() == positions.len()),
+      "{} has a primitive with a different number of texture coordinates than positions", name
+   );
+
+   // Normals need the inverse transpose so that non-uniform scales don't
+   // skew them
+   let normal_matrix = cgmath::Matrix3::from_cols(
+      transform.x.truncate(),
+      transform.y.truncate(),
+      transform.z.truncate(),
+   );
+   let normal_matrix = normal_matrix.invert().unwrap_or(normal_matrix).transpose();
+
+   let mut vertices = positions.iter().enumerate().map(|(i, position)| {
+      let position = transform.transform_point(cgmath::Point3::from(*position));
+      let normal = normals.as_ref()
+         .map(|n| (normal_matrix * cgmath::Vector3::from(n[i])).normalize().into())
+         .unwrap_or([0.0, 0.0, 0.0]);
+      Vertex {
+         position: position.into(),
+         // glTF already puts v = 0 at the top of the image, same as wgpu
+         tex_coords: tex_coords.as_ref().map(|t| t[i]).unwrap_or([0.0, 0.0]),
+         normal,
+      }
+   }).collect::<Vec<_>>();
+
+   // u8, u16 and u32 indices all get widened here - Mesh::new then picks
+   // the smallest index format that fits the vertex count
+   let mut indices = match reader.read_indices() {
+      Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+      // Non-indexed primitives draw their vertices in order
+      None => (0..vertices.len() as u32).collect(),
+   };
+   ensure!(
+      indices.iter().all(|&i| (i as usize) < vertices.len()),
+      "{} has a primitive with indices past its last vertex", name
+   );
+
+   // A transform that mirrors the mesh (an odd number of negative scales)
+   // turns its triangles inside out, and back face culling would throw
+   // away the side we should see. Swapping two corners of every triangle
+   // puts the front faces back where they were
+   if transform.determinant() < 0.0 {
+      for triangle in indices.chunks_exact_mut(3) {
+         triangle.swap(1, 2);
+      }
+   }
+
+   if normals.is_none() {
+      model::compute_normals(&mut vertices, &indices);
+   }
+
+   Ok(Mesh::new(device, name, &vertices, &indices, material))
+}
+
+// glTF samplers map almost one to one onto ours. Textures without a
+// sampler of their own, and anything a sampler leaves out, keep the
+// options every other texture is loaded with
+fn sampler_options(options: texture::SamplerOptions, sampler: &gltf::texture::Sampler) -> texture::SamplerOptions {
+   use gltf::texture::{ MagFilter, MinFilter, WrappingMode };
+
+   if sampler.index().is_none() {
+      return options;
+   }
+
+   let address_mode = |mode| match mode {
+      WrappingMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+      WrappingMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
+      WrappingMode::Repeat => wgpu::AddressMode::Repeat,
+   };
+   let mut options = options.address_modes(
+      address_mode(sampler.wrap_s()),
+      address_mode(sampler.wrap_t()),
+      wgpu::AddressMode::ClampToEdge,
+   );
+
+   if let Some(mag) = sampler.mag_filter() {
+      options.mag_filter = match mag {
+         MagFilter::Nearest => wgpu::FilterMode::Nearest,
+         MagFilter::Linear => wgpu::FilterMode::Linear,
+      };
+   }
+   // glTF rolls the mipmap filter into the min filter
+   if let Some(min) = sampler.min_filter() {
+      use wgpu::FilterMode::{ Linear, Nearest };
+      (options.min_filter, options.mipmap_filter) = match min {
+         MinFilter::Nearest | MinFilter::NearestMipmapNearest => (Nearest, Nearest),
+         MinFilter::Linear | MinFilter::LinearMipmapNearest => (Linear, Nearest),
+         MinFilter::NearestMipmapLinear => (Nearest, Linear),
+         MinFilter::LinearMipmapLinear => (Linear, Linear),
+      };
+   }
+
+   options
+}
+
+// Buffers and images either live in another file, or are embedded in the
+// uri itself as base64 (data:application/octet-stream;base64,....)
+fn load_uri<F>(uri: &str, load_file: &F) -> Result<Vec<u8>>
+where
+   F: Fn(&str) -> Result<Vec<u8>>,
+{
+   if let Some(data) = uri.strip_prefix("data:") {
+      let (_, encoded) = data.split_once(";base64,")
+         .ok_or_else(|| anyhow!("Only base64 data uris are supported"))?;
+      return Ok(base64::engine::general_purpose::STANDARD.decode(encoded)?);
+   }
+   load_file(&percent_decode(uri))
+}
+
+// Relative uris are percent-encoded, so "my%20texture.png" is the file
+// "my texture.png" on disk
+fn percent_decode(uri: &str) -> String {
+   let bytes = uri.as_bytes();
+   let mut out = Vec::with_capacity(bytes.len());
+   let mut i = 0;
+   while i < bytes.len() {
+      let hex = bytes.get(i + 1..i + 3)
+         .and_then(|h| std::str::from_utf8(h).ok())
+         .and_then(|h| u8::from_str_radix(h, 16).ok());
+      match (bytes[i], hex) {
+         (b'%', Some(byte)) => {
+            out.push(byte);
+            i += 3;
+         },
+         (byte, _) => {
+            out.push(byte);
+            i += 1;
+         },
+      }
+   }
+   String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,525 @@
+use std::path::{ Path, PathBuf };
+
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{ cubemap, texture };
+
+// Image based lighting: ambient light that comes from an environment map,
+// so objects pick up the colors of the sky around them and reflect it.
+// Doing that properly means integrating over the whole environment for
+// every pixel, which is far too slow to do every frame. Like most engines
+// we do the expensive part once, up front (Epic's "split sum"
+// approximation), and keep three textures:
+//
+// 1. irradiance - the environment averaged over a whole hemisphere, which
+//       is all diffuse light needs. There's no detail left after that, so
+//       it can be tiny
+//
+// 2. specular - the environment blurred by the GGX lobe, with a roughness
+//       per mip level: mip 0 is a mirror, the last mip is fully rough
+//
+// 3. brdf_lut - the rest of the specular integral, which depends only on
+//       the angle to the camera and the roughness, not on the environment
+//
+// They're made with render passes rather than compute shaders, so WebGL can
+// make them too
+pub struct Ibl {
+   pub irradiance: texture::Texture,
+   pub specular: texture::Texture,
+   pub brdf_lut: texture::Texture,
+}
+
+// How big the precomputed textures are and how carefully they're made.
+// Chain the builder methods to change them, e.g.
+//
+//    IblSettings::default()
+//       .sample_count(1024)
+//       .cache_dir("target/ibl-cache")
+#[derive(Clone, Debug)]
+pub struct IblSettings {
+   pub irradiance_size: u32,
+   pub specular_size: u32,
+   // Mip levels in the specular cubemap, spread evenly from roughness 0 to 1
+   pub specular_mip_count: u32,
+   pub brdf_lut_size: u32,
+   // Samples per texel. The environment doesn't have mipmaps to average
+   // over, so small bright spots (like the sun) need a lot of them
+   pub sample_count: u32,
+   // Where to keep the results between runs. None makes them every time
+   pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for IblSettings {
+   fn default() -> Self {
+      Self {
+         irradiance_size: 32,
+         specular_size: 128,
+         specular_mip_count: 5,
+         brdf_lut_size: 128,
+         sample_count: 512,
+         cache_dir: None,
+      }
+   }
+}
+
+impl IblSettings {
+   pub fn irradiance_size(mut self, size: u32) -> Self {
+      self.irradiance_size = size;
+      self
+   }
+
+   pub fn specular_size(mut self, size: u32, mip_count: u32) -> Self {
+      self.specular_size = size;
+      self.specular_mip_count = mip_count;
+      self
+   }
+
+   pub fn brdf_lut_size(mut self, size: u32) -> Self {
+      self.brdf_lut_size = size;
+      self
+   }
+
+   pub fn sample_count(mut self, sample_count: u32) -> Self {
+      self.sample_count = sample_count;
+      self
+   }
+
+   pub fn cache_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+      self.cache_dir = Some(dir.as_ref().to_path_buf());
+      self
+   }
+
+   // The specular cubemap can't have more mips than its size allows
+   fn specular_mips(&self) -> u32 {
+      self.specular_mip_count.clamp(1, self.specular_size.max(1).ilog2() + 1)
+   }
+}
+
+// What the shader needs to know besides the textures. Matches Environment
+// in pbr.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct EnvironmentUniform {
+   intensity: f32,
+   // The roughest mip level, as a float so the shader can scale by it
+   max_specular_mip: f32,
+   _padding: [f32; 2],
+}
+
+impl EnvironmentUniform {
+   pub fn new(ibl: &Ibl, intensity: f32) -> Self {
+      Self {
+         intensity,
+         max_specular_mip: (ibl.specular.texture.mip_level_count() - 1) as f32,
+         _padding: [0.0; 2],
+      }
+   }
+}
+
+const CUBE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+// Only two channels: a scale and a bias for f0
+const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+// Bump this whenever ibl.wgsl changes what it makes, so old cache files
+// stop being used
+const CACHE_VERSION: u32 = 1;
+const CACHE_MAGIC: &[u8; 4] = b"IBL ";
+
+// Matches Params in ibl.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct Params {
+   face: u32,
+   roughness: f32,
+   sample_count: u32,
+   _padding: u32,
+}
+
+impl Ibl {
+   // Black everywhere, for when there's no environment. The shader can
+   // still sample it, it just adds nothing
+   pub fn empty(device: &wgpu::Device, samplers: &mut texture::SamplerCache) -> Self {
+      // New textures start out zeroed, so there's nothing to write
+      Self {
+         irradiance: create_cube(device, samplers, "irradiance", 1, 1),
+         specular: create_cube(device, samplers, "specular", 1, 1),
+         brdf_lut: create_brdf_lut(device, samplers, 1),
+      }
+   }
+
+   // Precomputes everything for environment, a cubemap (see
+   // Texture::from_equirectangular). With a cache_dir, the results are
+   // saved there and loaded again next time the same environment comes
+   // along with the same settings - a broken or missing cache file just
+   // means working them out again
+   pub fn new(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut texture::SamplerCache,
+      environment: &texture::Texture,
+      settings: &IblSettings,
+   ) -> Result<Self> {
+      let Some(cache_dir) = &settings.cache_dir else {
+         return Ok(Self::precompute(device, queue, samplers, environment, settings));
+      };
+
+      // Named after what went into it, so a different environment (or
+      // settings) never picks up the wrong file
+      let mut key = Fnv1a::new();
+      key.write(&read_texture(device, queue, &environment.texture)?);
+      key.write(format!("{:?}", environment.texture.format()).as_bytes());
+      for value in [
+         CACHE_VERSION, settings.irradiance_size, settings.specular_size,
+         settings.specular_mips(), settings.brdf_lut_size, settings.sample_count,
+      ] {
+         key.write(&value.to_le_bytes());
+      }
+      let path = cache_dir.join(format!("{:016x}.ibl", key.finish()));
+
+      if path.exists() {
+         match Self::load(device, queue, samplers, &path, settings) {
+            Err(e) => log::warn!("Ignoring {}: {:#}", path.display(), e),
+            result => return result,
+         }
+      }
+
+      let ibl = Self::precompute(device, queue, samplers, environment, settings);
+      if let Err(e) = ibl.save(device, queue, &path) {
+         log::warn!("Couldn't save {}: {:#}", path.display(), e);
+      }
+      Ok(ibl)
+   }
+
+   fn precompute(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut texture::SamplerCache,
+      environment: &texture::Texture,
+      settings: &IblSettings,
+   ) -> Self {
+      let ibl = Self {
+         irradiance: create_cube(device, samplers, "irradiance", settings.irradiance_size, 1),
+         specular: create_cube(device, samplers, "specular", settings.specular_size, settings.specular_mips()),
+         brdf_lut: create_brdf_lut(device, samplers, settings.brdf_lut_size),
+      };
+
+      let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+         label: Some("IBL Shader"),
+         source: wgpu::ShaderSource::Wgsl(include_str!("ibl.wgsl").into()),
+      });
+      let pipeline = |entry_point, format| device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+         label: Some(entry_point),
+         layout: None,
+         vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+         },
+         fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point,
+            targets: &[Some(wgpu::ColorTargetState::from(format))],
+         }),
+         primitive: wgpu::PrimitiveState::default(),
+         depth_stencil: None,
+         multisample: wgpu::MultisampleState::default(),
+         multiview: None,
+      });
+
+      let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+         label: Some("IBL Encoder"),
+      });
+
+      // Every face of every mip is its own pass, with the face and
+      // roughness in a uniform
+      let mut draw = |pipeline: &wgpu::RenderPipeline, target: &wgpu::TextureView, params: Params, uses_environment: bool| {
+         let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("IBL Params Buffer"),
+            contents: bytemuck::cast_slice(&[params]),
+            usage: wgpu::BufferUsages::UNIFORM,
+         });
+         // The auto layout leaves out whatever the entry point doesn't
+         // use - the BRDF doesn't look at the environment at all
+         let layout = pipeline.get_bind_group_layout(0);
+         let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 2,
+            resource: params.as_entire_binding(),
+         }];
+         if uses_environment {
+            entries.push(wgpu::BindGroupEntry {
+               binding: 0,
+               resource: wgpu::BindingResource::TextureView(&environment.view),
+            });
+            entries.push(wgpu::BindGroupEntry {
+               binding: 1,
+               resource: wgpu::BindingResource::Sampler(&environment.sampler),
+            });
+         }
+         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &layout,
+            entries: &entries,
+         });
+
+         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("IBL Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+               view: target,
+               resolve_target: None,
+               ops: wgpu::Operations {
+                  load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                  store: true,
+               },
+            })],
+            depth_stencil_attachment: None,
+         });
+         render_pass.set_pipeline(pipeline);
+         render_pass.set_bind_group(0, &bind_group, &[]);
+         render_pass.draw(0..3, 0..1);
+      };
+      let sample_count = settings.sample_count.max(1);
+
+      let irradiance_pipeline = pipeline("fs_irradiance", CUBE_FORMAT);
+      let specular_pipeline = pipeline("fs_specular", CUBE_FORMAT);
+      let brdf_pipeline = pipeline("fs_brdf_lut", BRDF_LUT_FORMAT);
+
+      for face in 0..cubemap::FACE_COUNT {
+         let target = cubemap::face_view(&ibl.irradiance.texture, face, 0);
+         draw(&irradiance_pipeline, &target, Params { face, roughness: 0.0, sample_count, _padding: 0 }, true);
+      }
+      let mip_count = ibl.specular.texture.mip_level_count();
+      for mip_level in 0..mip_count {
+         let roughness = mip_level as f32 / (mip_count - 1).max(1) as f32;
+         for face in 0..cubemap::FACE_COUNT {
+            let target = cubemap::face_view(&ibl.specular.texture, face, mip_level);
+            draw(&specular_pipeline, &target, Params { face, roughness, sample_count, _padding: 0 }, true);
+         }
+      }
+      draw(&brdf_pipeline, &ibl.brdf_lut.view, Params { face: 0, roughness: 0.0, sample_count, _padding: 0 }, false);
+
+      queue.submit(std::iter::once(encoder.finish()));
+      ibl
+   }
+
+   // Cache files are a header (CACHE_MAGIC and the texture sizes) followed
+   // by every mip level of every face of the three textures, tightly packed
+   fn save(&self, device: &wgpu::Device, queue: &wgpu::Queue, path: &Path) -> Result<()> {
+      let mut bytes = self.header();
+      for texture in [&self.irradiance, &self.specular, &self.brdf_lut] {
+         bytes.extend(read_texture(device, queue, &texture.texture)?);
+      }
+      if let Some(dir) = path.parent() {
+         std::fs::create_dir_all(dir)?;
+      }
+      // Written to the side and renamed into place, so a crash halfway
+      // through can't leave half a file for next time
+      let partial = path.with_extension("partial");
+      std::fs::write(&partial, bytes)?;
+      std::fs::rename(&partial, path)?;
+      Ok(())
+   }
+
+   fn load(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut texture::SamplerCache,
+      path: &Path,
+      settings: &IblSettings,
+   ) -> Result<Self> {
+      let bytes = std::fs::read(path)?;
+      let ibl = Self {
+         irradiance: create_cube(device, samplers, "irradiance", settings.irradiance_size, 1),
+         specular: create_cube(device, samplers, "specular", settings.specular_size, settings.specular_mips()),
+         brdf_lut: create_brdf_lut(device, samplers, settings.brdf_lut_size),
+      };
+
+      let header = ibl.header();
+      ensure!(bytes.starts_with(&header), "not a cache file for these settings");
+      let mut rest = &bytes[header.len()..];
+      for texture in [&ibl.irradiance, &ibl.specular, &ibl.brdf_lut] {
+         rest = write_texture(queue, &texture.texture, rest)?;
+      }
+      ensure!(rest.is_empty(), "{} bytes too long", rest.len());
+      Ok(ibl)
+   }
+
+   fn header(&self) -> Vec<u8> {
+      let mut header = CACHE_MAGIC.to_vec();
+      for value in [
+         CACHE_VERSION,
+         self.irradiance.texture.width(),
+         self.specular.texture.width(),
+         self.specular.texture.mip_level_count(),
+         self.brdf_lut.texture.width(),
+      ] {
+         header.extend(value.to_le_bytes());
+      }
+      header
+   }
+}
+
+fn create_cube(
+   device: &wgpu::Device,
+   samplers: &mut texture::SamplerCache,
+   label: &str,
+   face_size: u32,
+   mip_level_count: u32,
+) -> texture::Texture {
+   let texture = cubemap::create_cube_texture(
+      device, Some(label), face_size.max(1), CUBE_FORMAT, mip_level_count, texture_usages(),
+   );
+   texture::Texture::cube(device, samplers, texture, &texture_options())
+}
+
+fn create_brdf_lut(device: &wgpu::Device, samplers: &mut texture::SamplerCache, size: u32) -> texture::Texture {
+   let texture = device.create_texture(&wgpu::TextureDescriptor {
+      label: Some("brdf_lut"),
+      size: wgpu::Extent3d { width: size.max(1), height: size.max(1), depth_or_array_layers: 1 },
+      mip_level_count: 1,
+      sample_count: 1,
+      dimension: wgpu::TextureDimension::D2,
+      format: BRDF_LUT_FORMAT,
+      usage: texture_usages(),
+      view_formats: &[],
+   });
+   let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+   let sampler = samplers.get(device, &texture_options().sampler);
+   texture::Texture { texture, view, sampler }
+}
+
+// Rendered into, then copied out to the cache or back in from it
+fn texture_usages() -> wgpu::TextureUsages {
+   wgpu::TextureUsages::TEXTURE_BINDING
+      | wgpu::TextureUsages::RENDER_ATTACHMENT
+      | wgpu::TextureUsages::COPY_SRC
+      | wgpu::TextureUsages::COPY_DST
+}
+
+// Trilinear, so the shader can blend between the specular roughness mips.
+// The BRDF LUT is clamped so roughness 0 and 1 don't wrap into each other
+fn texture_options() -> texture::TextureOptions {
+   texture::TextureOptions::default()
+      .sampler(texture::SamplerOptions::default().filter(wgpu::FilterMode::Linear))
+}
+
+// Copies every mip level of every layer of texture back from the gpu,
+// tightly packed - mip 0's layers first, then mip 1's and so on
+fn read_texture(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture) -> Result<Vec<u8>> {
+   let block_size = texture.format().block_size(None)
+      .ok_or_else(|| anyhow!("can't read back {:?} textures", texture.format()))?;
+   let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+      label: Some("Read Texture Encoder"),
+   });
+
+   // copy_texture_to_buffer needs padded rows, like OffscreenRenderer::render
+   let mut copies = Vec::new();
+   for mip_level in 0..texture.mip_level_count() {
+      let size = texture.size().mip_level_size(mip_level, texture.dimension());
+      let unpadded_bytes_per_row = block_size * size.width;
+      let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+      let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+      for layer in 0..size.depth_or_array_layers {
+         let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Read Texture Buffer"),
+            size: (padded_bytes_per_row * size.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+         });
+         encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+               aspect: wgpu::TextureAspect::All,
+               texture,
+               mip_level,
+               origin: wgpu::Origin3d { x: 0, y: 0, z: layer },
+            },
+            wgpu::ImageCopyBuffer {
+               buffer: &buffer,
+               layout: wgpu::ImageDataLayout {
+                  offset: 0,
+                  bytes_per_row: Some(padded_bytes_per_row),
+                  rows_per_image: Some(size.height),
+               },
+            },
+            wgpu::Extent3d { width: size.width, height: size.height, depth_or_array_layers: 1 },
+         );
+         copies.push((buffer, unpadded_bytes_per_row as usize, padded_bytes_per_row as usize));
+      }
+   }
+   queue.submit(std::iter::once(encoder.finish()));
+
+   let (tx, rx) = std::sync::mpsc::channel();
+   for (buffer, _, _) in &copies {
+      let tx = tx.clone();
+      buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+         tx.send(result).ok();
+      });
+   }
+   device.poll(wgpu::Maintain::Wait);
+   for _ in &copies {
+      rx.recv()??;
+   }
+
+   let mut bytes = Vec::new();
+   for (buffer, unpadded_bytes_per_row, padded_bytes_per_row) in copies {
+      {
+         let data = buffer.slice(..).get_mapped_range();
+         for row in data.chunks(padded_bytes_per_row) {
+            bytes.extend_from_slice(&row[..unpadded_bytes_per_row]);
+         }
+      }
+      buffer.unmap();
+   }
+   Ok(bytes)
+}
+
+// The other way round from read_texture. Returns whatever's left of bytes
+// after the texture has had its share
+fn write_texture<'a>(queue: &wgpu::Queue, texture: &wgpu::Texture, mut bytes: &'a [u8]) -> Result<&'a [u8]> {
+   let block_size = texture.format().block_size(None).unwrap();
+   for mip_level in 0..texture.mip_level_count() {
+      let size = texture.size().mip_level_size(mip_level, texture.dimension());
+      let layer_size = (block_size * size.width * size.height) as usize;
+      for layer in 0..size.depth_or_array_layers {
+         ensure!(bytes.len() >= layer_size, "ends partway through {:?}", texture);
+         let (layer_bytes, rest) = bytes.split_at(layer_size);
+         queue.write_texture(
+            wgpu::ImageCopyTexture {
+               aspect: wgpu::TextureAspect::All,
+               texture,
+               mip_level,
+               origin: wgpu::Origin3d { x: 0, y: 0, z: layer },
+            },
+            layer_bytes,
+            wgpu::ImageDataLayout {
+               offset: 0,
+               bytes_per_row: Some(block_size * size.width),
+               rows_per_image: Some(size.height),
+            },
+            wgpu::Extent3d { width: size.width, height: size.height, depth_or_array_layers: 1 },
+         );
+         bytes = rest;
+      }
+   }
+   Ok(bytes)
+}
+
+// 64 bit FNV-1a. std's hashers are allowed to change between Rust
+// releases, which would quietly throw the cache away
+struct Fnv1a(u64);
+
+impl Fnv1a {
+   fn new() -> Self {
+      Self(0xcbf29ce484222325)
+   }
+
+   fn write(&mut self, bytes: &[u8]) {
+      for &byte in bytes {
+         self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+      }
+   }
+
+   fn finish(&self) -> u64 {
+      self.0
+   }
+}
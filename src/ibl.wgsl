@@ -0,0 +1,153 @@
+// Precomputes the textures for image based lighting - see ibl.rs. Each
+// fragment entry point fills in one face of one mip level (or the BRDF
+// lookup table) per draw
+
+struct Params {
+   // Which face we're drawing, in wgpu's order: +x, -x, +y, -y, +z, -z
+   face: u32,
+   // For fs_specular, 0 (mirror) to 1
+   roughness: f32,
+   sample_count: u32,
+   _padding: u32,
+};
+@group(0) @binding(2)
+var<uniform> params: Params;
+
+@group(0) @binding(0)
+var t_environment: texture_cube<f32>;
+@group(0) @binding(1)
+var s_environment: sampler;
+
+struct VertexOutput {
+   @builtin(position) clip_position: vec4<f32>,
+   // -1 to 1 across the face, y pointing down like texture coordinates
+   @location(0) face_coords: vec2<f32>,
+};
+
+// The same full screen triangle as equirect.wgsl
+@vertex
+fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> VertexOutput {
+   var out: VertexOutput;
+   let x = f32(i32(in_vertex_index & 1u) * 4 - 1);
+   let y = f32(1 - i32(in_vertex_index & 2u) * 2);
+   out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+   out.face_coords = vec2<f32>(x, -y);
+   return out;
+}
+
+const PI: f32 = 3.14159265359;
+
+// The direction a texel of a cubemap face looks in, as in equirect.wgsl
+fn face_direction(index: u32, uv: vec2<f32>) -> vec3<f32> {
+   switch index {
+      case 0u: { return vec3<f32>(1.0, -uv.y, -uv.x); }
+      case 1u: { return vec3<f32>(-1.0, -uv.y, uv.x); }
+      case 2u: { return vec3<f32>(uv.x, 1.0, uv.y); }
+      case 3u: { return vec3<f32>(uv.x, -1.0, -uv.y); }
+      case 4u: { return vec3<f32>(uv.x, -uv.y, 1.0); }
+      default: { return vec3<f32>(-uv.x, -uv.y, -1.0); }
+   }
+}
+
+// The i-th of n points spread evenly (but not in a grid) over the unit
+// square. The bit reversal is done by hand, reverseBits isn't in older GLSL
+fn hammersley(i: u32, n: u32) -> vec2<f32> {
+   var bits = i;
+   bits = (bits << 16u) | (bits >> 16u);
+   bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+   bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+   bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+   bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+   return vec2<f32>(f32(i) / f32(n), f32(bits) * 2.3283064365386963e-10);
+}
+
+// Turns a direction around +z into the same direction around normal
+fn tangent_to_world(v: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+   let up = select(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), abs(normal.y) > 0.999);
+   let tangent = normalize(cross(up, normal));
+   let bitangent = cross(normal, tangent);
+   return tangent * v.x + bitangent * v.y + normal * v.z;
+}
+
+// A half vector picked with the GGX distribution, so the samples bunch up
+// where the specular lobe is - roughness is squared like in pbr.wgsl
+fn importance_sample_ggx(xi: vec2<f32>, normal: vec3<f32>, roughness: f32) -> vec3<f32> {
+   let a = roughness * roughness;
+   let phi = 2.0 * PI * xi.x;
+   let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+   let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+   return tangent_to_world(vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta), normal);
+}
+
+// Irradiance is the cosine weighted average of the light over the
+// hemisphere around the normal. Picking samples with that same cosine
+// weighting means the plain average of the samples is the answer (the
+// 1/pi of the diffuse BRDF cancels out too)
+@fragment
+fn fs_irradiance(in: VertexOutput) -> @location(0) vec4<f32> {
+   let normal = normalize(face_direction(params.face, in.face_coords));
+   var sum = vec3<f32>(0.0);
+   for (var i = 0u; i < params.sample_count; i += 1u) {
+      let xi = hammersley(i, params.sample_count);
+      let phi = 2.0 * PI * xi.x;
+      let sin_theta = sqrt(xi.y);
+      let cos_theta = sqrt(1.0 - xi.y);
+      let dir = tangent_to_world(vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta), normal);
+      sum += textureSampleLevel(t_environment, s_environment, dir, 0.0).rgb;
+   }
+   return vec4<f32>(sum / f32(params.sample_count), 1.0);
+}
+
+// The environment seen through a GGX lobe of params.roughness. We don't
+// know where the camera will be, so like everyone else we assume it's
+// looking straight down the normal - which loses the stretched highlights
+// at grazing angles, but makes it a cubemap lookup
+@fragment
+fn fs_specular(in: VertexOutput) -> @location(0) vec4<f32> {
+   let normal = normalize(face_direction(params.face, in.face_coords));
+   var sum = vec3<f32>(0.0);
+   var weight = 0.0;
+   for (var i = 0u; i < params.sample_count; i += 1u) {
+      let half_dir = importance_sample_ggx(hammersley(i, params.sample_count), normal, params.roughness);
+      let to_light = reflect(-normal, half_dir);
+      let n_dot_l = dot(normal, to_light);
+      if n_dot_l > 0.0 {
+         sum += textureSampleLevel(t_environment, s_environment, to_light, 0.0).rgb * n_dot_l;
+         weight += n_dot_l;
+      }
+   }
+   return vec4<f32>(sum / max(weight, 0.0001), 1.0);
+}
+
+// The specular integral with a white environment, split into a scale (r)
+// and bias (g) for f0, so the shader can do f0 * r + g. x across the table
+// is n_dot_v, y down it is roughness. Geometry uses k = a / 2, the IBL
+// version of geometry_smith in pbr.wgsl
+@fragment
+fn fs_brdf_lut(in: VertexOutput) -> @location(0) vec4<f32> {
+   let uv = in.face_coords * 0.5 + 0.5;
+   let n_dot_v = max(uv.x, 0.0001);
+   let roughness = uv.y;
+   let to_view = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+   let normal = vec3<f32>(0.0, 0.0, 1.0);
+   let k = roughness * roughness / 2.0;
+
+   var scale = 0.0;
+   var bias = 0.0;
+   for (var i = 0u; i < params.sample_count; i += 1u) {
+      let half_dir = importance_sample_ggx(hammersley(i, params.sample_count), normal, roughness);
+      let to_light = reflect(-to_view, half_dir);
+      let n_dot_l = max(to_light.z, 0.0);
+      let n_dot_h = max(half_dir.z, 0.0);
+      let v_dot_h = max(dot(to_view, half_dir), 0.0);
+      if n_dot_l > 0.0 {
+         let g = (n_dot_v / (n_dot_v * (1.0 - k) + k)) * (n_dot_l / (n_dot_l * (1.0 - k) + k));
+         let visibility = g * v_dot_h / max(n_dot_h * n_dot_v, 0.0001);
+         let fresnel = pow(1.0 - v_dot_h, 5.0);
+         scale += (1.0 - fresnel) * visibility;
+         bias += fresnel * visibility;
+      }
+   }
+   let n = f32(params.sample_count);
+   return vec4<f32>(scale / n, bias / n, 0.0, 1.0);
+}
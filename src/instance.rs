@@ -0,0 +1,97 @@
+use cgmath::prelude::*;
+
+// An Instance is one copy of the mesh in the world. We keep the transform
+// split up into position/rotation/scale here since that's easy to work
+// with, and only turn it into a matrix when uploading it to the gpu
+#[derive(Copy, Clone, Debug)]
+pub struct Instance {
+   pub position: cgmath::Vector3<f32>,
+   pub rotation: cgmath::Quaternion<f32>,
+   pub scale: f32,
+   // Multiplied with the texture color in the fragment shader, so white
+   // leaves the texture as it is
+   pub color: [f32; 4],
+}
+
+impl Instance {
+   pub fn new(position: cgmath::Vector3<f32>) -> Self {
+      Self {
+         position,
+         ..Default::default()
+      }
+   }
+
+   pub fn to_raw(&self) -> InstanceRaw {
+      let model = cgmath::Matrix4::from_translation(self.position)
+         * cgmath::Matrix4::from(self.rotation)
+         * cgmath::Matrix4::from_scale(self.scale);
+      InstanceRaw {
+         model: model.into(),
+         color: self.color,
+      }
+   }
+}
+
+impl Default for Instance {
+   fn default() -> Self {
+      Self {
+         position: cgmath::Vector3::zero(),
+         rotation: cgmath::Quaternion::one(),
+         scale: 1.0,
+         color: [1.0, 1.0, 1.0, 1.0],
+      }
+   }
+}
+
+// What actually goes in the instance buffer
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct InstanceRaw {
+   model: [[f32; 4]; 4],
+   color: [f32; 4],
+}
+
+impl InstanceRaw {
+   // 1. step_mode Instance means the shader only moves on to the next
+   //       InstanceRaw when it starts drawing a new instance, rather than
+   //       for every vertex
+   //
+   // 2. A mat4x4 takes up 4 vertex slots - vertex attributes are at most
+   //       a vec4, so we pass the matrix as 4 columns and put it back
+   //       together in the shader. We start at location 5 to leave room
+   //       for the Vertex attributes to grow
+   pub fn desc() -> wgpu::VertexBufferLayout<'static> {
+      use std::mem;
+      wgpu::VertexBufferLayout {
+         array_stride: mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
+         step_mode: wgpu::VertexStepMode::Instance, // 1.
+         attributes: &[
+            wgpu::VertexAttribute { // 2.
+               offset: 0,
+               shader_location: 5,
+               format: wgpu::VertexFormat::Float32x4,
+            },
+            wgpu::VertexAttribute {
+               offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+               shader_location: 6,
+               format: wgpu::VertexFormat::Float32x4,
+            },
+            wgpu::VertexAttribute {
+               offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+               shader_location: 7,
+               format: wgpu::VertexFormat::Float32x4,
+            },
+            wgpu::VertexAttribute {
+               offset: mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
+               shader_location: 8,
+               format: wgpu::VertexFormat::Float32x4,
+            },
+            wgpu::VertexAttribute {
+               offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+               shader_location: 9,
+               format: wgpu::VertexFormat::Float32x4,
+            },
+         ],
+      }
+   }
+}
@@ -1,13 +1,39 @@
+use cgmath::prelude::*;
 use winit::{
     event::*,
     event_loop::{ ControlFlow, EventLoop },
     window::{ WindowBuilder, Window },
-    dpi::PhysicalSize
 };
-use rand::prelude::*;
-use wgpu::util::DeviceExt;
 
-mod texture;
+pub mod texture;
+pub mod camera;
+pub mod camera_controller;
+pub mod instance;
+pub mod light;
+pub mod model;
+mod gltf_import;
+mod compressed;
+mod decompress;
+mod cubemap;
+mod mipmap;
+mod renderer;
+mod offscreen;
+mod output;
+mod skybox;
+mod shader;
+mod preprocessor;
+mod reflect;
+mod ibl;
+mod shadow;
+#[cfg(not(target_arch = "wasm32"))]
+mod file_watcher;
+
+pub use offscreen::OffscreenRenderer;
+pub use renderer::DepthSettings;
+pub use preprocessor::ShaderDefs;
+pub use ibl::IblSettings;
+pub use shadow::{ CascadeSplit, ShadowSettings };
+use camera_controller::CameraController;
 
 #[cfg(target_arch="wasm32")]
 use wasm_bindgen::prelude::*;
@@ -50,12 +76,13 @@ pub async fn run() {
    }
 
    let mut state = State::new(window).await;
+   let mut last_render_time = instant::Instant::now();
 
    event_loop.run(move |event, _, control_flow| match event {
       Event::WindowEvent { 
          window_id, 
          ref event 
-      } if window_id == state.window.id() => if !state.input(event) {
+      } if window_id == state.window.id() && !state.input(event) => {
          match event {
             WindowEvent::CloseRequested |
             WindowEvent::KeyboardInput {
@@ -78,7 +105,10 @@ pub async fn run() {
       },
       Event::RedrawRequested(window_id) 
       if window_id == state.window().id() => {
-         state.update();
+         let now = instant::Instant::now();
+         let dt = now - last_render_time;
+         last_render_time = now;
+         state.update(dt);
          match state.render() {
             Ok(_) => {},
             // Reconfigure the surface if it is lost
@@ -100,8 +130,6 @@ pub async fn run() {
 }
 
 struct State {
-   instance: wgpu::Instance,
-   adapter: wgpu::Adapter,
    surface: wgpu::Surface,
    device: wgpu::Device,
    queue: wgpu::Queue,
@@ -109,37 +137,15 @@ struct State {
    size: winit::dpi::PhysicalSize<u32>,
    window: Window,
    clear_color: wgpu::Color,
-   render_pipeline: wgpu::RenderPipeline,
-   vertex_buffer: wgpu::Buffer,
-   // num_vertices: u32,
-   index_buffer: wgpu::Buffer,
-   num_indices: u32,
-   diffuse_bind_group: wgpu::BindGroup,
-   diffuse_texture: texture::Texture,
+   renderer: renderer::Renderer,
+   // Gets frames gamma encoded properly whatever the surface format is
+   output: output::Output,
+   camera_controller: Box<dyn CameraController>,
+   // Tells us when texture and shader files change so they can be reloaded
+   #[cfg(not(target_arch = "wasm32"))]
+   watcher: Option<file_watcher::FileWatcher>,
 }
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-struct Vertex {
-   position: [f32; 3],
-   tex_coords: [f32; 2]
-}
-
-const VERTICES: &[Vertex] = &[
-   Vertex { position: [-0.0868241, 0.49240386, 0.0], tex_coords: [0.4131759, 0.00759614] }, // A
-   Vertex { position: [-0.49513406, 0.06958647, 0.0], tex_coords: [0.0048659444, 0.43041354] }, // B
-   Vertex { position: [-0.21918549, -0.44939706, 0.0], tex_coords: [0.28081453, 0.949397] }, // C
-   Vertex { position: [0.35966998, -0.3473291, 0.0], tex_coords: [0.85967, 0.84732914] }, // D
-   Vertex { position: [0.44147372, 0.2347359, 0.0], tex_coords: [0.9414737, 0.2652641] }, // E
-];
-// vertices are arranged in counter-clockwise fashion
-
-const INDICES: &[u16] = &[
-   0, 1, 4,
-   1, 2, 4,
-   2, 3, 4
-];
-
 impl State {
    // Creating some wgpu types requires async code
    async fn new(window: Window) -> Self {
@@ -178,8 +184,9 @@ impl State {
          &wgpu::DeviceDescriptor {
             // WebGL doesn't support all of wgpu's features, so if
             // we're building for the web we'll have to disable some.
-            // Available features may be dependent on device's GPU card
-            features: wgpu::Features::empty(),
+            // Available features may be dependent on device's GPU card,
+            // so we only ask for the optional ones the adapter has
+            features: adapter.features() & texture::OPTIONAL_FEATURES,
             // Available limits (describes limit of certain types of resources)
             // may be dependent on device's GPU card
             limits: if cfg!(target_arch = "wasm32") {
@@ -195,12 +202,19 @@ impl State {
       let surface_caps = surface.get_capabilities(&adapter);
 
       // Shader code in this tutorial assumes an sRGB surface texture. Using a different
-      // one will result all the colors coming out darker. If you want to support
-      // non sRGB surfaces, you'll need to account for that when drawing to the frame
+      // one would make all the colors come out darker, so we prefer an sRGB format.
+      // If the surface doesn't have one, Output makes up for it - either by viewing
+      // the surface as sRGB, or by gamma encoding in a final pass
       let surface_format = surface_caps.formats.iter()
          .copied()
          .find(|f| f.is_srgb())
          .unwrap_or(surface_caps.formats[0]);
+      let allow_srgb_view = adapter.get_downlevel_capabilities().flags
+         .contains(wgpu::DownlevelFlags::SURFACE_VIEW_FORMATS);
+      // One sampler cache for the whole device, the renderer takes it over
+      // once the output is set up
+      let mut samplers = texture::SamplerCache::new();
+      let output = output::Output::new(&device, &mut samplers, surface_format, allow_srgb_view, size.width, size.height);
 
       // We define a config for our surface - how the surface creates its
       // underlying SurfaceTextures
@@ -211,173 +225,52 @@ impl State {
          height: size.height,
          present_mode: surface_caps.present_modes[0],
          alpha_mode: surface_caps.alpha_modes[0],
-         view_formats: vec![]
+         view_formats: output.view_formats(),
       };
       surface.configure(&device, &config);
 
-      let diffuse_bytes = include_bytes!("kirbyface.png");
-      let diffuse_texture = texture::Texture::from_bytes(&device, &queue, diffuse_bytes, "kirbyface.png").unwrap();
-
-      // texture_bind_group_layout- BindGroup describes a set of resources and how they can be 
-      // accessed by a shader. Our texture bindgroup layout has 2 entries:
-      //    one for a sampled texture at binding 0
-      //    another foor a sampler at binding 1
-      // both are only visible to the fragment shader (this will be the case most of the time)
-      let texture_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-         label: Some("texture_bind_group_layout"),
-         entries: &[
-            wgpu::BindGroupLayoutEntry {
-               binding: 0,
-               visibility: wgpu::ShaderStages::FRAGMENT,
-               ty: wgpu::BindingType::Texture { 
-                  sample_type: wgpu::TextureSampleType::Float { filterable: true }, 
-                  view_dimension: wgpu::TextureViewDimension::D2, 
-                  multisampled: false,
-               },
-                count: None,
-            },
-            wgpu::BindGroupLayoutEntry {
-                binding: 1,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                count: None,
-            }
-         ],
-      });
-
-      let diffuse_bind_group = device.create_bind_group(
-         &wgpu::BindGroupDescriptor {
-            label: Some("diffuse_bind_group"),
-            layout: &texture_bind_group_layout,
-            entries: &[
-               wgpu::BindGroupEntry {
-                  binding: 0,
-                  resource: wgpu::BindingResource::TextureView(&diffuse_texture.view)
-               },
-               wgpu::BindGroupEntry {
-                  binding: 1,
-                  resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler)
-               },
-
-            ],
-        }
+      // Everything past this point (textures, bind groups, the pipeline and
+      // our buffers) doesn't care whether we draw to a window or not, so it
+      // lives in the renderer module
+      let mut renderer = renderer::Renderer::new(
+         &device,
+         &queue,
+         output.render_format(config.format),
+         config.width,
+         config.height,
+         renderer::DepthSettings::default(),
+         samplers,
       );
-      // The reason why the above code for the BindGroup is so descriptive - it allows us to
-      // swap out BindGroups on the fly as long as they all share the same BindGroupLayout
-      // 
-      // Each texture and sampler we create will need to be added to a BindGroup
-
 
-      // SET UP PIPELINE
-
-      let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-         label: Some("Shader"),
-         source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
-      });
-
-      let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-         label: Some("Render Pipeline Layout"),
-         bind_group_layouts: &[&texture_bind_group_layout],
-         push_constant_ranges: &[]
-      });
-
-      let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor { 
-         label: Some("Render Pipeline"),
-         layout: Some(&render_pipeline_layout), 
-         vertex: wgpu::VertexState {
-            module: &shader,
-            entry_point: "vs_main", // 1.
-            buffers: &[ Vertex::desc(), ] // 2.
-         }, 
-         fragment: Some(wgpu::FragmentState { // 3.
-            module: &shader,
-            entry_point: "fs_main",
-            targets: &[Some(wgpu::ColorTargetState { // 4.
-               format: config.format,
-               blend: Some(wgpu::BlendState::REPLACE),
-               write_mask: wgpu::ColorWrites::ALL
-            })]
-         }), 
-         primitive: wgpu::PrimitiveState {
-            topology: wgpu::PrimitiveTopology::TriangleList, // 5.
-            strip_index_format: None,
-            front_face: wgpu::FrontFace::Ccw, // 6.
-            cull_mode: Some(wgpu::Face::Back),
-            // below: Setting polygon_mode to anything other than Fill requires 
-            //          Features::NON_FILL_POLYGON_MODE
-            polygon_mode: wgpu::PolygonMode::Fill,
-            // below: requires Features::DEPTH_CLIP_CONTROL
-            unclipped_depth: false,
-            // below: requires Features::CONSERVATIVE_RASTERIZATION
-            conservative: false,
-         }, 
-         depth_stencil: None, // 7.
-         multisample: wgpu::MultisampleState {
-            count: 1, // 8.
-            mask: !0, // 9.
-            alpha_to_coverage_enabled: false, // 10.
-         }, 
-         multiview: None, // 11.
-      });
-
-      // 1. Specify which function inside the shader should be the entry_point:
-      //       functions we marked with @vertex and @fragment
-      // 
-      // 2. buffers tells the wgpu what type of vertices we want to pass to the
-      //       vertex shader - we're specifying the vertices in the shader itself
-      //       so this can be empty
-      // 
-      // 3. Fragment is technically optional so we wrap it in Some()
-      //       needed if we want to store color data to surface
-      // 
-      // 4. targets field tells wgpu what color outputs it should set up.
-      //       We only need one for the surface. We use the surface's format
-      //       so copying to the surface is easy.
-      //       We specify that the blending should replace old pixel data with new
-      //       We tell wgpu to write to R,G,B, and A (all colors)
-      // 
-      // 5. Using PrimitiveTopology::TriangleList means that every three vertices
-      //       will correspond to one triangle
-      // 
-      // 6. front_face and cull_mode tell wgpu how to determine whether a given
-      //       triangle is facing forward or noot
-      //       FrontFace::Ccw means that a triangle is facing forward if the
-      //       vertices are arranged in a counter-clockwise direction -
-      //       triangles not facing forward are culled (not included in render)
-      //       as specified by CullMode::Back
-      // 
-      // 7. We're not using a depth/stencil buffer so we leave this as None for now
-      // 
-      // 8. count field determines how many samples the pipeline will use
-      // 
-      // 9. mask field specifies which samples should be active
-      // 
-      // 10. alpha_to_coverage_enabled - anti-aliasing-related
-      // 
-      // 11. multiview - how many array layers the render attachments can have
-      //       We won't be rendering to array textures so we can set this as None
-
-      let vertex_buffer = device.create_buffer_init(
-         &wgpu::util::BufferInitDescriptor {
-            label: Some("Vertex Buffer"),
-            contents: bytemuck::cast_slice(VERTICES),
-            usage: wgpu::BufferUsages::VERTEX
-         }
-      );
-      // let num_vertices = VERTICES.len() as u32;
-
-      let index_buffer = device.create_buffer_init(
-         &wgpu::util::BufferInitDescriptor {
-            label: Some("Index Buffer"),
-            contents: bytemuck::cast_slice(INDICES),
-            usage: wgpu::BufferUsages::INDEX,
-        }
-      );
-      let num_indices = INDICES.len() as u32;
+      // Back the camera off far enough to see the whole grid
+      renderer.instances = instance_grid();
+      renderer.camera.eye = (0.0, 6.0, 12.0).into();
+
+      // A little of everything: a dim sun, a blue point light that circles
+      // the grid (see update) and a spot light on the middle
+      renderer.ambient = [0.1, 0.1, 0.1];
+      renderer.lights = scene_lights();
+      // The sun casts shadows across the grid, in cascades out to the
+      // default 50 units away
+      renderer.set_shadows(&device, Some(ShadowSettings::cascaded()));
+
+      // A simple sky to look at while moving around, K toggles it
+      let sky = texture::Texture::from_image(&device, &queue, renderer.samplers_mut(), &sky_panorama(), Some("sky")).unwrap();
+      renderer.set_skybox_panorama(&device, &queue, &sky, 256).unwrap();
+      // It lights the scene too. Natively the precomputed lighting is kept
+      // in the temp dir, so only the first run has to wait for it
+      let ibl_settings = IblSettings::default();
+      #[cfg(not(target_arch = "wasm32"))]
+      let ibl_settings = ibl_settings.cache_dir(std::env::temp_dir().join("wgpu_tutorial_ibl"));
+      renderer.set_environment_panorama(&device, &queue, &sky, 128, &ibl_settings).unwrap();
+
+      #[cfg(not(target_arch = "wasm32"))]
+      let watcher = watch_assets(&device, &queue, &mut renderer);
+
+      let mut camera_controller: Box<dyn CameraController> = Box::new(camera_controller::OrbitController::new());
+      camera_controller.resize(config.width, config.height);
 
       Self {
-         instance,
-         adapter,
          window,
          surface,
          device,
@@ -385,13 +278,11 @@ impl State {
          config,
          size,
          clear_color: wgpu::Color::BLACK,
-         render_pipeline,
-         vertex_buffer,
-         // num_vertices,
-         index_buffer,
-         num_indices,
-         diffuse_bind_group,
-         diffuse_texture
+         renderer,
+         output,
+         camera_controller,
+         #[cfg(not(target_arch = "wasm32"))]
+         watcher,
       }
    }
 
@@ -405,85 +296,160 @@ impl State {
          self.config.width = new_size.width;
          self.config.height = new_size.height;
          self.surface.configure(&self.device, &self.config);
+         self.renderer.resize(&self.device, new_size.width, new_size.height);
+         self.output.resize(&self.device, new_size.width, new_size.height);
+         self.camera_controller.resize(new_size.width, new_size.height);
       }
    }
 
+   // Swaps in a different camera controller. The camera itself is left
+   // where it is, so the new controller picks up from the current view
+   pub fn set_camera_controller(&mut self, mut controller: Box<dyn CameraController>) {
+      controller.resize(self.size.width, self.size.height);
+      self.camera_controller = controller;
+   }
+
    fn input(&mut self, event: &WindowEvent) -> bool {
+      if self.camera_controller.process_event(event) {
+         return true;
+      }
+
       match event {
          WindowEvent::CursorMoved { position, ..} => {
             self.clear_color = wgpu::Color {
-               r: position.x as f64 / self.size.width as f64,
-               g: position.y as f64 / self.size.height as f64,
+               r: position.x / self.size.width as f64,
+               g: position.y / self.size.height as f64,
                b: 1.0,
                a: 1.0
             };
             true
          },
+         // 1, 2 and 3 switch between the orbit, fly and pan controllers
+         WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+               state: ElementState::Pressed,
+               virtual_keycode: Some(keycode @ (VirtualKeyCode::Key1 | VirtualKeyCode::Key2 | VirtualKeyCode::Key3)),
+               ..
+            },
+            ..
+         } => {
+            let controller: Box<dyn CameraController> = match keycode {
+               VirtualKeyCode::Key1 => Box::new(camera_controller::OrbitController::new()),
+               VirtualKeyCode::Key2 => Box::new(camera_controller::FlyController::new()),
+               _ => Box::new(camera_controller::PanController::new()),
+            };
+            self.set_camera_controller(controller);
+            true
+         },
+         // K shows/hides the skybox (and with it the clear color)
+         WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+               state: ElementState::Pressed,
+               virtual_keycode: Some(VirtualKeyCode::K),
+               ..
+            },
+            ..
+         } => {
+            self.renderer.show_skybox = !self.renderer.show_skybox;
+            true
+         },
+         // N switches to the shader variant that shows normals as colors
+         WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+               state: ElementState::Pressed,
+               virtual_keycode: Some(VirtualKeyCode::N),
+               ..
+            },
+            ..
+         } => {
+            let defs = self.renderer.shader_defs().clone();
+            let defs = if defs.contains("DEBUG_NORMALS") {
+               defs.undefine("DEBUG_NORMALS")
+            } else {
+               defs.define("DEBUG_NORMALS")
+            };
+            if let Err(e) = self.renderer.set_shader_defs(&self.device, defs) {
+               log::error!("{:#}", e);
+            }
+            true
+         },
+         // C tints everything by the shadow cascade it's in
+         WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+               state: ElementState::Pressed,
+               virtual_keycode: Some(VirtualKeyCode::C),
+               ..
+            },
+            ..
+         } => {
+            let defs = self.renderer.shader_defs().clone();
+            let defs = if defs.contains("DEBUG_CASCADES") {
+               defs.undefine("DEBUG_CASCADES")
+            } else {
+               defs.define("DEBUG_CASCADES")
+            };
+            if let Err(e) = self.renderer.set_shader_defs(&self.device, defs) {
+               log::error!("{:#}", e);
+            }
+            true
+         },
          _ => false
       }
    }
 
-   fn update(&mut self) {
-      // todo!()
+   fn update(&mut self, dt: std::time::Duration) {
+      self.camera_controller.update_camera(&mut self.renderer.camera, dt);
+
+      // Spin every instance around its own y axis
+      let spin = cgmath::Quaternion::from_angle_y(cgmath::Rad(dt.as_secs_f32() * 0.5));
+      for instance in &mut self.renderer.instances {
+         instance.rotation = spin * instance.rotation;
+      }
+
+      // and send the point lights around the grid the other way
+      let orbit = cgmath::Quaternion::from_angle_y(cgmath::Rad(-dt.as_secs_f32()));
+      for light in &mut self.renderer.lights {
+         if let light::Light::Point { position, .. } = light {
+            *position = orbit.rotate_point(*position);
+         }
+      }
+
+      #[cfg(not(target_arch = "wasm32"))]
+      if let Some(watcher) = &mut self.watcher {
+         let changed = watcher.changed();
+         if !changed.is_empty() {
+            self.renderer.reload_textures(&self.device, &self.queue, &changed);
+            // The shader might include files it didn't before
+            if self.renderer.reload_shader(&self.device, &changed) {
+               for path in self.renderer.shader_paths() {
+                  if let Err(e) = watcher.watch(path) {
+                     log::warn!("{:#}", e);
+                  }
+               }
+            }
+         }
+      }
+
+      self.renderer.update(&self.device, &self.queue);
    }
 
    fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
       // 1. get_current_texture will wait for surface to provide a new 
       //    SurfaceTexture that we will render to
       // 
-      // 2. Createt a TextureView with default settings - to control 
-      //    how render code interacts with the texture
+      // 2. Output creates the TextureView (to control how render code
+      //    interacts with the texture) - an sRGB one if the surface needs it
       // 
       // 3. Create a CommandEncoder to create actual commands to send 
       //    to the gpu. Most modern graphics frameworks expect commands 
       //    to be stored in a command buffer before they are sent to gpu - 
       //    the encoder builds that command buffer
       let output = self.surface.get_current_texture()?;
-      let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
       let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
          label: Some("Render Encoder"),
       });
 
-      // Now we can clear the screen - we need to use the encoder to create
-      // a RenderPass - this has all the methods for actual drawing
-      // 
-      // begin_render_pass borrows encoder mutably and we can't call
-      //    encoder.finish() until we release that mutable borrow
-      // 
-      // RenderPassColorAttachment fields - 
-      //    view - tells wgpu what texture to save the colors to
-      // 
-      //    resolve_target - texture that will receive resolved output
-      // 
-      //    ops - takes wgpu::Operations object; tells wgpu what to do
-      //          with the colors on the texture
-      let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor { 
-         label:Some("Render Pass"),
-         color_attachments: &[
-            // This is what @location(0) in the fragment shader targets
-            Some(wgpu::RenderPassColorAttachment {
-               view: &view,
-               resolve_target: None,
-               ops: wgpu::Operations {
-                  load: wgpu::LoadOp::Clear(self.clear_color),
-                  store:true,
-               }
-            }
-         )], 
-         depth_stencil_attachment: None, 
-      });
-
-      // After we set the pipeline to our built render pipeline, we can 
-      //    tell wgpu too draw smoething with 3 vertices and 1 instance
-      // 
-      // Note: You can have multiple vertex buffers set at once. You can only have one index buffer set at once.
-      render_pass.set_pipeline(&self.render_pipeline);
-      render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
-      render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-      render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-      render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
-
-      drop(render_pass);
+      self.output.render(&self.renderer, &mut encoder, &output.texture, self.clear_color);
 
       // Finish the command buffer and send to gpu's render queue
       self.queue.submit(std::iter::once(encoder.finish()));
@@ -493,24 +459,108 @@ impl State {
    }
 }
 
-impl Vertex {
-   fn desc() -> wgpu::VertexBufferLayout<'static> {
-      use std::mem;
-      wgpu::VertexBufferLayout {
-        array_stride: mem::size_of::<Vertex>() as wgpu::BufferAddress,
-        step_mode: wgpu::VertexStepMode::Vertex,
-        attributes: &[
-            wgpu::VertexAttribute {
-               format: wgpu::VertexFormat::Float32x3,
-               offset: 0,
-               shader_location: 0,
-            },
-            wgpu::VertexAttribute {
-               format: wgpu::VertexFormat::Float32x2,
-               offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
-               shader_location: 1,
-            }
-        ],
-    }
+const NUM_INSTANCES_PER_ROW: u32 = 10;
+const INSTANCE_SPACING: f32 = 1.2;
+
+// A NUM_INSTANCES_PER_ROW x NUM_INSTANCES_PER_ROW grid of pentagons on the
+// xz plane, centred on the origin, tinted from red to blue across the grid
+fn instance_grid() -> Vec<instance::Instance> {
+   let half = (NUM_INSTANCES_PER_ROW - 1) as f32 * INSTANCE_SPACING / 2.0;
+   (0..NUM_INSTANCES_PER_ROW).flat_map(|z| {
+      (0..NUM_INSTANCES_PER_ROW).map(move |x| {
+         let position = cgmath::Vector3 {
+            x: x as f32 * INSTANCE_SPACING - half,
+            y: 0.0,
+            z: z as f32 * INSTANCE_SPACING - half,
+         };
+         let t = x as f32 / (NUM_INSTANCES_PER_ROW - 1) as f32;
+         instance::Instance {
+            color: [1.0 - t * 0.5, 1.0, 0.5 + t * 0.5, 1.0],
+            ..instance::Instance::new(position)
+         }
+      })
+   }).collect()
+}
+
+// Cook-Torrance's diffuse term divides by pi, so the intensities are a
+// bit over 3 times what the same brightness took with Blinn-Phong
+fn scene_lights() -> Vec<light::Light> {
+   vec![
+      light::Light::Directional {
+         direction: (-0.3, -0.5, -1.0).into(),
+         color: [1.0, 0.95, 0.85],
+         intensity: 1.5,
+      },
+      light::Light::Point {
+         position: (5.0, 2.0, 0.0).into(),
+         color: [0.4, 0.6, 1.0],
+         intensity: 36.0,
+         range: 10.0,
+      },
+      light::Light::Spot {
+         position: (0.0, 4.0, 6.0).into(),
+         direction: (0.0, -4.0, -6.0).into(),
+         color: [1.0, 1.0, 1.0],
+         intensity: 120.0,
+         range: 20.0,
+         inner_angle: cgmath::Deg(8.0),
+         outer_angle: cgmath::Deg(15.0),
+      },
+   ]
+}
+
+// When we're run from the source tree, use kirbyface.png and shader.wgsl
+// from there rather than the copies baked into the binary, and watch them
+// (and any other texture files) for changes - edit and save one and the
+// pentagon updates straight away. Anything going wrong here just means no
+// hot reloading
+#[cfg(not(target_arch = "wasm32"))]
+fn watch_assets(
+   device: &wgpu::Device,
+   queue: &wgpu::Queue,
+   renderer: &mut renderer::Renderer,
+) -> Option<file_watcher::FileWatcher> {
+   let src = std::path::Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src"));
+   let kirbyface = src.join("kirbyface.png");
+   if kirbyface.exists() {
+      if let Err(e) = renderer.load_texture(device, queue, &kirbyface) {
+         log::warn!("Couldn't load {}: {:#}", kirbyface.display(), e);
+      }
+   }
+   let shader = src.join("shader.wgsl");
+   if shader.exists() {
+      if let Err(e) = renderer.load_shader(device, &shader) {
+         log::error!("{:#}", e);
+      }
+   }
+
+   let mut watcher = file_watcher::FileWatcher::new()
+      .map_err(|e| log::warn!("Hot reloading is off: {:#}", e))
+      .ok()?;
+   let paths = renderer.texture_paths().into_iter()
+      .chain(renderer.shader_paths().iter().cloned());
+   for path in paths {
+      if let Err(e) = watcher.watch(&path) {
+         log::warn!("{:#}", e);
+      }
    }
-}
\ No newline at end of file
+   Some(watcher)
+}
+
+// An equirectangular panorama of a plain sky: deep blue overhead, fading
+// to a pale horizon, with brown ground below it
+fn sky_panorama() -> image::DynamicImage {
+   let (width, height) = (256, 128);
+   let lerp = |a: [f32; 3], b: [f32; 3], t: f32| [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * t);
+   let img = image::RgbaImage::from_fn(width, height, |_, y| {
+      // 0 straight up, 1 at the horizon, 2 straight down
+      let t = y as f32 / (height - 1) as f32 * 2.0;
+      let color = if t < 1.0 {
+         lerp([40.0, 90.0, 200.0], [200.0, 220.0, 240.0], t.powf(2.0))
+      } else {
+         lerp([110.0, 90.0, 60.0], [60.0, 45.0, 30.0], t - 1.0)
+      };
+      image::Rgba([color[0] as u8, color[1] as u8, color[2] as u8, 255])
+   });
+   image::DynamicImage::ImageRgba8(img)
+}
@@ -0,0 +1,118 @@
+use cgmath::prelude::*;
+
+// The light buffer is a uniform rather than a storage buffer so it works on
+// WebGL too, which means it has a fixed size. Lights past this are ignored
+pub const MAX_LIGHTS: usize = 16;
+
+// Colors are linear, and intensity scales them. Point and spot lights fade
+// out with the inverse square of the distance, reaching nothing at range
+#[derive(Copy, Clone, Debug)]
+pub enum Light {
+   // Infinitely far away, like the sun. direction is the way the light
+   // travels, not the way to the light
+   Directional {
+      direction: cgmath::Vector3<f32>,
+      color: [f32; 3],
+      intensity: f32,
+   },
+   // Shines equally in every direction from position
+   Point {
+      position: cgmath::Point3<f32>,
+      color: [f32; 3],
+      intensity: f32,
+      range: f32,
+   },
+   // A point light that only shines in a cone around direction. Full
+   // brightness inside inner_angle, fading to nothing at outer_angle (both
+   // measured from the middle of the cone)
+   Spot {
+      position: cgmath::Point3<f32>,
+      direction: cgmath::Vector3<f32>,
+      color: [f32; 3],
+      intensity: f32,
+      range: f32,
+      inner_angle: cgmath::Deg<f32>,
+      outer_angle: cgmath::Deg<f32>,
+   },
+}
+
+// Matches kind in the Light struct in lighting.wgsl
+const DIRECTIONAL: u32 = 0;
+const POINT: u32 = 1;
+const SPOT: u32 = 2;
+
+impl Light {
+   pub fn to_raw(&self) -> LightRaw {
+      match *self {
+         Light::Directional { direction, color, intensity } => LightRaw {
+            kind: DIRECTIONAL,
+            direction: direction.normalize().into(),
+            color,
+            intensity,
+            ..Default::default()
+         },
+         Light::Point { position, color, intensity, range } => LightRaw {
+            position: position.into(),
+            kind: POINT,
+            range,
+            color,
+            intensity,
+            ..Default::default()
+         },
+         Light::Spot { position, direction, color, intensity, range, inner_angle, outer_angle } => LightRaw {
+            position: position.into(),
+            kind: SPOT,
+            direction: direction.normalize().into(),
+            range,
+            color,
+            intensity,
+            // The shader compares against the cosine of the angle to the
+            // light, which saves it an acos per fragment
+            cos_inner: inner_angle.cos(),
+            cos_outer: outer_angle.cos(),
+            ..Default::default()
+         },
+      }
+   }
+}
+
+// One light as the shader sees it. Fields are arranged so each vec3 is
+// followed by a 4 byte field, filling out the 16 byte rows uniform buffers
+// are laid out in
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightRaw {
+   position: [f32; 3],
+   kind: u32,
+   direction: [f32; 3],
+   range: f32,
+   color: [f32; 3],
+   intensity: f32,
+   cos_inner: f32,
+   cos_outer: f32,
+   _padding: [f32; 2],
+}
+
+// Everything in the light buffer. ambient is added to every fragment
+// regardless of the lights, so nothing is ever completely black
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightsUniform {
+   ambient: [f32; 3],
+   count: u32,
+   lights: [LightRaw; MAX_LIGHTS],
+}
+
+impl LightsUniform {
+   pub fn new(ambient: [f32; 3], lights: &[Light]) -> Self {
+      let mut uniform = Self {
+         ambient,
+         count: lights.len().min(MAX_LIGHTS) as u32,
+         lights: [LightRaw::default(); MAX_LIGHTS],
+      };
+      for (raw, light) in uniform.lights.iter_mut().zip(lights) {
+         *raw = light.to_raw();
+      }
+      uniform
+   }
+}
@@ -0,0 +1,100 @@
+// Blinn-Phong lighting for directional, point and spot lights - see
+// light.rs for the Rust side of these structs. One directional light can
+// cast shadows, see shadow.wgsl
+//
+// Define SHININESS before including this to change how tight the specular
+// highlights are
+
+#define MAX_LIGHTS 16u
+#define LIGHT_DIRECTIONAL 0u
+#define LIGHT_POINT 1u
+#define LIGHT_SPOT 2u
+
+#ifndef SHININESS
+#define SHININESS 32.0
+#endif
+#define SPECULAR_STRENGTH 0.5
+
+#include "shadow.wgsl"
+
+struct Light {
+   position: vec3<f32>,
+   kind: u32,
+   // The way the light travels, for directional and spot lights
+   direction: vec3<f32>,
+   range: f32,
+   color: vec3<f32>,
+   intensity: f32,
+   cos_inner: f32,
+   cos_outer: f32,
+};
+
+struct Lights {
+   ambient: vec3<f32>,
+   count: u32,
+   lights: array<Light, MAX_LIGHTS>,
+};
+@group(2) @binding(0)
+var<uniform> lights: Lights;
+
+// Inverse square falloff, windowed so it reaches exactly 0 at range rather
+// than going on forever
+fn attenuation(distance: f32, range: f32) -> f32 {
+   let falloff = 1.0 / max(distance * distance, 0.0001);
+   let window = clamp(1.0 - pow(distance / range, 4.0), 0.0, 1.0);
+   return falloff * window * window;
+}
+
+// Where one light is and how much of it reaches world_position
+struct LightSample {
+   // Normalized
+   to_light: vec3<f32>,
+   radiance: vec3<f32>,
+};
+
+// Spot lights fade out between the inner and outer cone, smoothstep on the
+// cosines gives a soft edge
+fn sample_light(light: Light, world_position: vec3<f32>) -> LightSample {
+   var sample: LightSample;
+   sample.radiance = light.color * light.intensity;
+   if light.kind == LIGHT_DIRECTIONAL {
+      sample.to_light = -light.direction;
+   } else {
+      let offset = light.position - world_position;
+      let distance = length(offset);
+      sample.to_light = offset / distance;
+      sample.radiance *= attenuation(distance, light.range);
+      if light.kind == LIGHT_SPOT {
+         let cos_angle = dot(-sample.to_light, light.direction);
+         sample.radiance *= smoothstep(light.cos_outer, light.cos_inner, cos_angle);
+      }
+   }
+   return sample;
+}
+
+// The light reaching us directly from every light - ambient is left to the
+// caller
+//
+// 1. Diffuse light depends on the angle between the normal and the
+//       direction to the light: surfaces facing it head on get all of it
+//
+// 2. Specular uses the half vector, halfway between the directions to the
+//       light and to the camera. The closer the normal is to it, the
+//       closer we are to seeing the light reflected straight at us
+fn blinn_phong(albedo: vec3<f32>, normal: vec3<f32>, world_position: vec3<f32>, view_position: vec3<f32>) -> vec3<f32> {
+   let to_view = normalize(view_position - world_position);
+   var color = vec3<f32>(0.0);
+
+   for (var i = 0u; i < min(lights.count, MAX_LIGHTS); i += 1u) {
+      let sample = sample_light(lights.lights[i], world_position);
+      let radiance = sample.radiance * shadow_factor(i, world_position, normal, sample.to_light);
+
+      let diffuse = max(dot(normal, sample.to_light), 0.0); // 1.
+      let half_dir = normalize(sample.to_light + to_view); // 2.
+      // No highlights on the side facing away from the light
+      let specular = select(0.0, pow(max(dot(normal, half_dir), 0.0), SHININESS), diffuse > 0.0);
+      color += (albedo * diffuse + SPECULAR_STRENGTH * specular) * radiance;
+   }
+
+   return color;
+}
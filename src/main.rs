@@ -1,5 +1,20 @@
-use wgpu_tutorial::run;
+use wgpu_tutorial::{ run, OffscreenRenderer };
 
 fn main() {
+    // `wgpu_tutorial --headless out.png [width height]` renders a single
+    // frame without opening a window and writes it to out.png
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--headless") {
+        env_logger::init();
+        let path = args.get(2).map(String::as_str).unwrap_or("frame.png");
+        let width = args.get(3).and_then(|w| w.parse().ok()).unwrap_or(800);
+        let height = args.get(4).and_then(|h| h.parse().ok()).unwrap_or(600);
+
+        let mut renderer = pollster::block_on(OffscreenRenderer::new(width, height))
+            .expect("Couldn't create offscreen renderer");
+        renderer.save_png(path).expect("Couldn't write frame");
+        return;
+    }
+
     pollster::block_on(run());
 }
@@ -0,0 +1,221 @@
+use crate::texture::{ SamplerCache, SamplerOptions };
+
+// How many mip levels it takes to get from width x height down to 1x1
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+   32 - width.max(height).max(1).leading_zeros()
+}
+
+// The gpu path renders each level from the one above, which needs the
+// format to be usable as a render attachment and to be filterable
+pub fn can_generate_on_gpu(device: &wgpu::Device, format: wgpu::TextureFormat) -> bool {
+   let features = format.guaranteed_format_features(device.features());
+   features.allowed_usages.contains(wgpu::TextureUsages::RENDER_ATTACHMENT)
+      && features.flags.contains(wgpu::TextureFormatFeatureFlags::FILTERABLE)
+}
+
+// The pipeline generate_gpu draws each level with. It only depends on the
+// format, so texture::SamplerCache keeps one per format rather than every
+// texture building its own
+pub struct MipmapPipeline {
+   pipeline: wgpu::RenderPipeline,
+   bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl MipmapPipeline {
+   pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+      let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+         label: Some("Blit Shader"),
+         source: wgpu::ShaderSource::Wgsl(include_str!("blit.wgsl").into()),
+      });
+
+      // layout: None lets wgpu work out the bind group layout from the shader
+      let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+         label: Some("Mipmap Pipeline"),
+         layout: None,
+         vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+         },
+         fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(format.into())],
+         }),
+         primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            ..Default::default()
+         },
+         depth_stencil: None,
+         multisample: wgpu::MultisampleState::default(),
+         multiview: None,
+      });
+      let bind_group_layout = pipeline.get_bind_group_layout(0);
+
+      Self { pipeline, bind_group_layout }
+   }
+
+   // Linear min/mag filtering averages the 2x2 block of pixels each new
+   // pixel covers.
+   //
+   // The gl backend ignores the view's mip range when sampling, so the lod
+   // is pinned to 0 or it reads the (still empty) level we're filling in
+   pub fn sampler_options() -> SamplerOptions {
+      SamplerOptions::default()
+         .filters(wgpu::FilterMode::Linear, wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest)
+         .lod_clamp(0.0, 0.0)
+   }
+}
+
+// Fills in mip levels 1.. of texture from level 0, on the gpu.
+//
+// For each level we draw a full screen triangle, sampling the level above
+// with a linear filter. The pipeline and sampler come from samplers, see
+// MipmapPipeline.
+//
+// We'd like to sample one level of texture while rendering into the next,
+// but the gl backend can't bind a view that starts past mip 0. So instead
+// each level is rendered into a small scratch texture, which becomes the
+// source for the next level, and copied into place. That way texture only
+// needs COPY_DST and TEXTURE_BINDING usage
+pub fn generate_gpu(
+   device: &wgpu::Device,
+   queue: &wgpu::Queue,
+   samplers: &mut SamplerCache,
+   texture: &wgpu::Texture,
+   format: wgpu::TextureFormat,
+   mip_level_count: u32,
+) {
+   let sampler = samplers.get(device, &MipmapPipeline::sampler_options());
+   let MipmapPipeline { pipeline, bind_group_layout } = samplers.mipmap_pipeline(device, format);
+
+   let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+      label: Some("Mipmap Encoder"),
+   });
+
+   // Level 0 of the real texture is where we start reading from
+   let mut source_view = texture.create_view(&wgpu::TextureViewDescriptor {
+      label: Some("Mip Source View"),
+      base_mip_level: 0,
+      mip_level_count: Some(1),
+      ..Default::default()
+   });
+   // Keeps each scratch texture alive until the encoder is submitted
+   let mut scratch_textures = Vec::with_capacity(mip_level_count as usize);
+
+   for mip in 1..mip_level_count {
+      let size = texture.size().mip_level_size(mip, wgpu::TextureDimension::D2);
+      let scratch = device.create_texture(&wgpu::TextureDescriptor {
+         label: Some("Mip Scratch Texture"),
+         size,
+         mip_level_count: 1,
+         sample_count: 1,
+         dimension: wgpu::TextureDimension::D2,
+         format,
+         usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::COPY_SRC,
+         view_formats: &[],
+      });
+      let scratch_view = scratch.create_view(&wgpu::TextureViewDescriptor::default());
+
+      let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+         label: None,
+         layout: bind_group_layout,
+         entries: &[
+            wgpu::BindGroupEntry {
+               binding: 0,
+               resource: wgpu::BindingResource::TextureView(&source_view),
+            },
+            wgpu::BindGroupEntry {
+               binding: 1,
+               resource: wgpu::BindingResource::Sampler(&sampler),
+            },
+         ],
+      });
+
+      {
+         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Mipmap Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+               view: &scratch_view,
+               resolve_target: None,
+               ops: wgpu::Operations {
+                  load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                  store: true,
+               },
+            })],
+            depth_stencil_attachment: None,
+         });
+         render_pass.set_pipeline(pipeline);
+         render_pass.set_bind_group(0, &bind_group, &[]);
+         render_pass.draw(0..3, 0..1);
+      }
+
+      encoder.copy_texture_to_texture(
+         scratch.as_image_copy(),
+         wgpu::ImageCopyTexture {
+            texture,
+            mip_level: mip,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+         },
+         size,
+      );
+
+      source_view = scratch_view;
+      scratch_textures.push(scratch);
+   }
+
+   queue.submit(std::iter::once(encoder.finish()));
+}
+
+// The cpu fallback - halves the image one level at a time, averaging each
+// 2x2 block like the gpu's linear filter does, and returns levels 1..
+// (level 0 is the image itself).
+//
+// Note: this filters the raw values. For sRGB textures those are still
+// sRGB encoded, so it comes out a touch darker than the gpu path, which
+// filters in linear space. Linear textures come out the same either way
+pub fn generate_cpu(rgba: &image::RgbaImage, mip_level_count: u32) -> Vec<image::RgbaImage> {
+   generate_levels(rgba, mip_level_count, |sum: [f32; 4]| image::Rgba(sum.map(|total| (total / 4.0).round() as u8)))
+}
+
+// generate_cpu for hdr images, which keeps values above 1.0
+pub fn generate_cpu_hdr(rgba: &image::Rgba32FImage, mip_level_count: u32) -> Vec<image::Rgba32FImage> {
+   generate_levels(rgba, mip_level_count, |sum: [f32; 4]| image::Rgba(sum.map(|total| total / 4.0)))
+}
+
+// Averages 2x2 blocks by hand rather than using image's resize, whose
+// filters reach further than the block (so don't match the gpu) and which
+// clamps floats to 0.0-1.0. average turns the sum of a block into a pixel
+fn generate_levels<P, F>(
+   base: &image::ImageBuffer<P, Vec<P::Subpixel>>,
+   mip_level_count: u32,
+   average: F,
+) -> Vec<image::ImageBuffer<P, Vec<P::Subpixel>>>
+where
+   P: image::Pixel,
+   P::Subpixel: Into<f32>,
+   F: Fn([f32; 4]) -> P,
+{
+   let mut levels: Vec<image::ImageBuffer<P, Vec<P::Subpixel>>> = Vec::new();
+   for _ in 1..mip_level_count {
+      let previous = levels.last().unwrap_or(base);
+      let width = (previous.width() / 2).max(1);
+      let height = (previous.height() / 2).max(1);
+      levels.push(image::ImageBuffer::from_fn(width, height, |x, y| {
+         // Clamp so odd sized (and 1 pixel wide) levels don't read past the edge
+         let xs = [(x * 2).min(previous.width() - 1), (x * 2 + 1).min(previous.width() - 1)];
+         let ys = [(y * 2).min(previous.height() - 1), (y * 2 + 1).min(previous.height() - 1)];
+         let mut sum = [0.0; 4];
+         for (sx, sy) in xs.iter().flat_map(|&sx| ys.iter().map(move |&sy| (sx, sy))) {
+            for (total, value) in sum.iter_mut().zip(previous.get_pixel(sx, sy).channels()) {
+               *total += (*value).into();
+            }
+         }
+         average(sum)
+      }));
+   }
+   levels
+}
@@ -0,0 +1,786 @@
+use std::{
+   io::{ BufReader, Cursor },
+   ops::Range,
+   path::{ Path, PathBuf },
+};
+
+use anyhow::*;
+use cgmath::prelude::*;
+use wgpu::util::DeviceExt;
+
+use crate::texture;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Vertex {
+   pub position: [f32; 3],
+   pub tex_coords: [f32; 2],
+   pub normal: [f32; 3],
+}
+
+impl Vertex {
+   pub fn desc() -> wgpu::VertexBufferLayout<'static> {
+      use std::mem;
+      wgpu::VertexBufferLayout {
+        array_stride: mem::size_of::<Vertex>() as wgpu::BufferAddress,
+        step_mode: wgpu::VertexStepMode::Vertex,
+        attributes: &[
+            wgpu::VertexAttribute {
+               format: wgpu::VertexFormat::Float32x3,
+               offset: 0,
+               shader_location: 0,
+            },
+            wgpu::VertexAttribute {
+               format: wgpu::VertexFormat::Float32x2,
+               offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+               shader_location: 1,
+            },
+            wgpu::VertexAttribute {
+               format: wgpu::VertexFormat::Float32x3,
+               offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+               shader_location: 2,
+            }
+        ],
+    }
+   }
+}
+
+// The textures a material is made of, in the order they're bound in
+// @group(0) (the sampler sits at binding 1, between the first two)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TextureSlot {
+   // RGB is the albedo, A is opacity
+   BaseColor,
+   // glTF packing: G is roughness, B is metallic
+   MetallicRoughness,
+   // Tangent space, +Y (green) pointing up the image
+   Normal,
+   // R is how much ambient light reaches the surface
+   Occlusion,
+   Emissive,
+}
+
+impl TextureSlot {
+   pub const ALL: [TextureSlot; 5] = [
+      TextureSlot::BaseColor,
+      TextureSlot::MetallicRoughness,
+      TextureSlot::Normal,
+      TextureSlot::Occlusion,
+      TextureSlot::Emissive,
+   ];
+
+   // Colors are sRGB, everything else is data and has to stay linear
+   pub fn color_space(self) -> texture::ColorSpace {
+      match self {
+         TextureSlot::BaseColor | TextureSlot::Emissive => texture::ColorSpace::Srgb,
+         _ => texture::ColorSpace::Linear,
+      }
+   }
+
+   fn binding(self) -> u32 {
+      match self {
+         TextureSlot::BaseColor => 0,
+         TextureSlot::MetallicRoughness => 2,
+         TextureSlot::Normal => 3,
+         TextureSlot::Occlusion => 4,
+         TextureSlot::Emissive => 5,
+      }
+   }
+
+   // The 1x1 texture used when a material doesn't have one in this slot.
+   // White leaves the factors as they are, and the normal points straight
+   // out of the surface
+   fn default_color(self) -> [u8; 4] {
+      match self {
+         TextureSlot::Normal => [128, 128, 255, 255],
+         _ => [255, 255, 255, 255],
+      }
+   }
+}
+
+// One of a material's textures
+pub struct MaterialTexture {
+   pub texture: texture::Texture,
+   // Where the texture was loaded from, so it can be loaded again when the
+   // file changes. None for textures that aren't a file of their own (1x1
+   // colors, images embedded in a .glb...)
+   pub source: Option<TextureSource>,
+}
+
+impl MaterialTexture {
+   pub fn new(texture: texture::Texture) -> Self {
+      Self { texture, source: None }
+   }
+
+   // Loads the texture from a file, remembering where from for
+   // Material::reload
+   pub fn from_file(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut texture::SamplerCache,
+      source: TextureSource,
+   ) -> Result<Self> {
+      let texture = source.load(device, queue, samplers)?;
+      Ok(Self { texture, source: Some(source) })
+   }
+
+   pub fn with_source(mut self, source: TextureSource) -> Self {
+      self.source = Some(source);
+      self
+   }
+}
+
+#[derive(Clone, Debug)]
+pub struct TextureSource {
+   pub path: PathBuf,
+   pub options: texture::TextureOptions,
+}
+
+// The metallic-roughness model's constants, which the textures are
+// multiplied by. Colors are linear
+#[derive(Copy, Clone, Debug)]
+pub struct MaterialFactors {
+   pub base_color: [f32; 4],
+   // 0 for dielectrics (plastic, wood, stone...), 1 for bare metal
+   pub metallic: f32,
+   // 0 is a perfect mirror, 1 is completely matte
+   pub roughness: f32,
+   // How strongly the normal map bends the normal
+   pub normal_scale: f32,
+   // 0 ignores the occlusion texture, 1 uses it fully
+   pub occlusion_strength: f32,
+   pub emissive: [f32; 3],
+}
+
+// A white, slightly shiny plastic
+impl Default for MaterialFactors {
+   fn default() -> Self {
+      Self {
+         base_color: [1.0, 1.0, 1.0, 1.0],
+         metallic: 0.0,
+         roughness: 0.5,
+         normal_scale: 1.0,
+         occlusion_strength: 1.0,
+         emissive: [0.0, 0.0, 0.0],
+      }
+   }
+}
+
+// MaterialFactors as the Material struct in pbr.wgsl sees them, padded out
+// to a whole number of 16 byte rows
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialUniform {
+   base_color: [f32; 4],
+   emissive: [f32; 3],
+   metallic: f32,
+   roughness: f32,
+   normal_scale: f32,
+   occlusion_strength: f32,
+   _padding: f32,
+}
+
+impl MaterialUniform {
+   fn new(factors: &MaterialFactors, has_normal_map: bool) -> Self {
+      Self {
+         base_color: factors.base_color,
+         emissive: factors.emissive,
+         metallic: factors.metallic,
+         roughness: factors.roughness,
+         // The default flat normal texture isn't quite flat in 8 bits (128
+         // is just over half way), scaling it to nothing makes it exact
+         normal_scale: if has_normal_map { factors.normal_scale } else { 0.0 },
+         occlusion_strength: factors.occlusion_strength,
+         _padding: 0.0,
+      }
+   }
+}
+
+// Everything Material::new needs besides the device. Textures that are
+// left out get 1x1 stand ins, e.g.
+//
+//    MaterialDesc::default()
+//       .texture(TextureSlot::BaseColor, albedo)
+//       .factors(MaterialFactors { metallic: 1.0, ..Default::default() })
+#[derive(Default)]
+pub struct MaterialDesc {
+   textures: [Option<MaterialTexture>; 5],
+   factors: MaterialFactors,
+}
+
+impl MaterialDesc {
+   pub fn texture(mut self, slot: TextureSlot, texture: MaterialTexture) -> Self {
+      self.textures[slot as usize] = Some(texture);
+      self
+   }
+
+   pub fn factors(mut self, factors: MaterialFactors) -> Self {
+      self.factors = factors;
+      self
+   }
+}
+
+pub struct Material {
+   pub name: String,
+   // One per TextureSlot, in TextureSlot::ALL order
+   textures: Vec<MaterialTexture>,
+   // Whether textures has a real normal map, rather than the stand in
+   has_normal_map: bool,
+   factors: MaterialFactors,
+   factors_buffer: wgpu::Buffer,
+   // Laid out like the renderer's texture_bind_group_layout:
+   //    textures at bindings 0 and 2-5 (see TextureSlot::binding), the
+   //    base color's sampler at binding 1 and the factors at binding 6.
+   // The other textures share the base color's sampler - glTF lets each
+   // have its own, but they're hardly ever different
+   pub bind_group: wgpu::BindGroup,
+}
+
+impl Material {
+   pub fn new(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut texture::SamplerCache,
+      name: &str,
+      desc: MaterialDesc,
+      layout: &wgpu::BindGroupLayout,
+   ) -> Self {
+      let has_normal_map = desc.textures[TextureSlot::Normal as usize].is_some();
+      let textures = desc.textures.into_iter().zip(TextureSlot::ALL).map(|(texture, slot)| {
+         texture.unwrap_or_else(|| {
+            let img = image::DynamicImage::ImageRgba8(
+               image::RgbaImage::from_pixel(1, 1, image::Rgba(slot.default_color()))
+            );
+            let options = texture::TextureOptions::default().color_space(slot.color_space());
+            // Only decoding can fail, and there's nothing to decode
+            let texture = texture::Texture::from_image_with_options(
+               device, queue, samplers, &img, Some(name), options
+            ).unwrap();
+            MaterialTexture::new(texture)
+         })
+      }).collect::<Vec<_>>();
+
+      let factors_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+         label: Some(&format!("{} Material Buffer", name)),
+         contents: bytemuck::cast_slice(&[MaterialUniform::new(&desc.factors, has_normal_map)]),
+         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+      });
+      let bind_group = Self::create_bind_group(device, name, &textures, &factors_buffer, layout);
+
+      Self {
+         name: name.to_string(),
+         textures,
+         has_normal_map,
+         factors: desc.factors,
+         factors_buffer,
+         bind_group,
+      }
+   }
+
+   pub fn texture(&self, slot: TextureSlot) -> &MaterialTexture {
+      &self.textures[slot as usize]
+   }
+
+   // Every texture that was loaded from a file of its own
+   pub fn sources(&self) -> impl Iterator<Item = (TextureSlot, &TextureSource)> {
+      TextureSlot::ALL.into_iter()
+         .zip(&self.textures)
+         .filter_map(|(slot, texture)| Some((slot, texture.source.as_ref()?)))
+   }
+
+   pub fn factors(&self) -> MaterialFactors {
+      self.factors
+   }
+
+   // The factors live in a buffer, so changing them doesn't need a new
+   // bind group
+   pub fn set_factors(&mut self, queue: &wgpu::Queue, factors: MaterialFactors) {
+      self.factors = factors;
+      let uniform = MaterialUniform::new(&factors, self.has_normal_map);
+      queue.write_buffer(&self.factors_buffer, 0, bytemuck::cast_slice(&[uniform]));
+   }
+
+   // Swaps in a different texture. The bind group points at the old one,
+   // so it gets rebuilt too
+   pub fn set_texture(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      slot: TextureSlot,
+      texture: MaterialTexture,
+      layout: &wgpu::BindGroupLayout,
+   ) {
+      self.textures[slot as usize] = texture;
+      if slot == TextureSlot::Normal && !self.has_normal_map {
+         self.has_normal_map = true;
+         self.set_factors(queue, self.factors);
+      }
+      self.bind_group = Self::create_bind_group(device, &self.name, &self.textures, &self.factors_buffer, layout);
+   }
+
+   // Loads the texture in slot from its file again. If that fails - say the
+   // file is still being written - we keep the texture we had
+   pub fn reload(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut texture::SamplerCache,
+      slot: TextureSlot,
+      layout: &wgpu::BindGroupLayout,
+   ) -> Result<()> {
+      let source = self.texture(slot).source.clone().ok_or_else(|| {
+         anyhow!("material {}'s {:?} texture wasn't loaded from a file", self.name, slot)
+      })?;
+      let texture = MaterialTexture::from_file(device, queue, samplers, source)?;
+      self.set_texture(device, queue, slot, texture, layout);
+      Ok(())
+   }
+
+   fn create_bind_group(
+      device: &wgpu::Device,
+      name: &str,
+      textures: &[MaterialTexture],
+      factors_buffer: &wgpu::Buffer,
+      layout: &wgpu::BindGroupLayout,
+   ) -> wgpu::BindGroup {
+      let base_color = &textures[TextureSlot::BaseColor as usize].texture;
+      let mut entries = TextureSlot::ALL.into_iter().zip(textures).map(|(slot, texture)| {
+         wgpu::BindGroupEntry {
+            binding: slot.binding(),
+            resource: wgpu::BindingResource::TextureView(&texture.texture.view),
+         }
+      }).collect::<Vec<_>>();
+      entries.push(wgpu::BindGroupEntry {
+         binding: 1,
+         resource: wgpu::BindingResource::Sampler(&base_color.sampler),
+      });
+      entries.push(wgpu::BindGroupEntry {
+         binding: 6,
+         resource: factors_buffer.as_entire_binding(),
+      });
+
+      device.create_bind_group(&wgpu::BindGroupDescriptor {
+         label: Some(name),
+         layout,
+         entries: &entries,
+      })
+   }
+}
+
+impl TextureSource {
+   pub fn load(
+      &self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut texture::SamplerCache,
+   ) -> Result<texture::Texture> {
+      let bytes = std::fs::read(&self.path)
+         .with_context(|| format!("Couldn't read {}", self.path.display()))?;
+      let label = self.path.to_string_lossy();
+      texture::Texture::from_bytes_with_options(device, queue, samplers, &bytes, &label, self.options)
+         .with_context(|| format!("Couldn't load {}", self.path.display()))
+   }
+}
+
+pub struct Mesh {
+   pub name: String,
+   pub vertex_buffer: wgpu::Buffer,
+   pub index_buffer: wgpu::Buffer,
+   pub num_elements: u32,
+   // Uint16 or Uint32, whichever the index buffer was built with
+   pub index_format: wgpu::IndexFormat,
+   // Index into Model::materials
+   pub material: usize,
+}
+
+impl Mesh {
+   // 16 bit indices take half the memory, but can only address 65535
+   // vertices - 0xFFFF is the primitive restart value, which strip
+   // pipelines treat as "start a new strip" rather than a vertex. We use
+   // them whenever the mesh is small enough and switch to 32 bit indices
+   // for anything bigger
+   pub fn new(
+      device: &wgpu::Device,
+      name: &str,
+      vertices: &[Vertex],
+      indices: &[u32],
+      material: usize,
+   ) -> Self {
+      let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+         label: Some(&format!("{} Vertex Buffer", name)),
+         contents: bytemuck::cast_slice(vertices),
+         usage: wgpu::BufferUsages::VERTEX,
+      });
+
+      debug_assert!(
+         indices.iter().all(|&i| (i as usize) < vertices.len()),
+         "{} has indices past its last vertex", name
+      );
+      let index_format = Self::index_format_for(vertices.len());
+      let index_buffer = match index_format {
+         wgpu::IndexFormat::Uint16 => {
+            // Buffers created with contents have to be a multiple of 4
+            // bytes long, so an odd number of u16s gets one unused index
+            // on the end
+            let mut indices = indices.iter().map(|&i| i as u16).collect::<Vec<_>>();
+            if indices.len() % 2 == 1 {
+               indices.push(0);
+            }
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+               label: Some(&format!("{} Index Buffer", name)),
+               contents: bytemuck::cast_slice(&indices),
+               usage: wgpu::BufferUsages::INDEX,
+            })
+         },
+         wgpu::IndexFormat::Uint32 => device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Index Buffer", name)),
+            contents: bytemuck::cast_slice(indices),
+            usage: wgpu::BufferUsages::INDEX,
+         }),
+      };
+
+      Self {
+         name: name.to_string(),
+         vertex_buffer,
+         index_buffer,
+         num_elements: indices.len() as u32,
+         index_format,
+         material,
+      }
+   }
+
+   pub fn index_format_for(num_vertices: usize) -> wgpu::IndexFormat {
+      if num_vertices <= u16::MAX as usize {
+         wgpu::IndexFormat::Uint16
+      } else {
+         wgpu::IndexFormat::Uint32
+      }
+   }
+}
+
+// One entry in a model's node hierarchy. Only formats that have one
+// (glTF) fill these in
+#[derive(Clone, Debug)]
+pub struct Node {
+   pub name: Option<String>,
+   // Relative to the parent node
+   pub transform: cgmath::Matrix4<f32>,
+   // Indices into Model::nodes
+   pub children: Vec<usize>,
+   // Indices into Model::meshes that were built for this node
+   pub meshes: Vec<usize>,
+}
+
+pub struct Model {
+   pub meshes: Vec<Mesh>,
+   pub materials: Vec<Material>,
+   pub nodes: Vec<Node>,
+   // Indices into nodes for the top of the hierarchy
+   pub root_nodes: Vec<usize>,
+}
+
+impl Model {
+   // Loads an OBJ file from disk. Any MTL files and textures it refers to
+   // are looked up relative to the OBJ file's directory, and loaded with
+   // options (apart from the color space, which each slot picks)
+   pub fn load<P: AsRef<Path>>(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      layout: &wgpu::BindGroupLayout,
+      samplers: &mut texture::SamplerCache,
+      options: texture::TextureOptions,
+      path: P,
+   ) -> Result<Self> {
+      let path = path.as_ref();
+      let dir = path.parent().unwrap_or_else(|| Path::new(""));
+      let obj_bytes = std::fs::read(path)
+         .with_context(|| format!("Couldn't read {}", path.display()))?;
+
+      let mut model = Self::from_obj_bytes(device, queue, layout, samplers, options, &obj_bytes, |name| {
+         let file = dir.join(name);
+         std::fs::read(&file).with_context(|| format!("Couldn't read {}", file.display()))
+      })?;
+      model.resolve_texture_paths(dir);
+      Ok(model)
+   }
+
+   // The loaders fill in each material texture's source with its name as
+   // the model file gives it. Once we know which directory those names are
+   // relative to, they can be turned into real paths
+   pub(crate) fn resolve_texture_paths(&mut self, dir: &Path) {
+      let textures = self.materials.iter_mut().flat_map(|m| m.textures.iter_mut());
+      for source in textures.filter_map(|t| t.source.as_mut()) {
+         source.path = dir.join(&source.path);
+      }
+   }
+
+   // Builds a model from the contents of an OBJ file. load_file is called
+   // with the names of the MTL files and textures the OBJ refers to, so
+   // callers can get them from wherever they live (disk, include_bytes!,
+   // a web request...)
+   //
+   // Materials' texture source paths are those names as they are, see
+   // resolve_texture_paths
+   pub fn from_obj_bytes<F>(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      layout: &wgpu::BindGroupLayout,
+      samplers: &mut texture::SamplerCache,
+      options: texture::TextureOptions,
+      obj_bytes: &[u8],
+      load_file: F,
+   ) -> Result<Self>
+   where
+      F: Fn(&str) -> Result<Vec<u8>>,
+   {
+      // 1. triangulate turns quads and other polygons into triangles
+      //
+      // 2. single_index makes positions, tex coords and normals share one
+      //       index buffer - which is what wgpu expects
+      let (models, obj_materials) = tobj::load_obj_buf(
+         &mut BufReader::new(Cursor::new(obj_bytes)),
+         &tobj::LoadOptions {
+            triangulate: true, // 1.
+            single_index: true, // 2.
+            ..Default::default()
+         },
+         |mtl_path| {
+            let name = mtl_path.to_string_lossy();
+            let mtl_bytes = load_file(&name).map_err(|e| {
+               log::warn!("{:#}", e);
+               tobj::LoadError::OpenFileFailed
+            })?;
+            tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mtl_bytes)))
+         },
+      )?;
+
+      // A missing or broken MTL file shouldn't stop us from showing the
+      // geometry, the meshes just fall back to the default material
+      let obj_materials = obj_materials.unwrap_or_else(|e| {
+         log::warn!("Couldn't load materials: {}", e);
+         Vec::new()
+      });
+
+      // MTL comes from before metallic-roughness, so:
+      //
+      // 1. map_Kd is the base color. Without one, or if it can't be loaded,
+      //       the diffuse color (Kd) becomes a 1x1 texture instead
+      //
+      // 2. map_Bump is taken to be a normal map, since that's what most
+      //       exporters put there these days. One that can't be loaded is
+      //       left out
+      //
+      // 3. The specular exponent (Ns) is turned into a roughness, using the
+      //       usual Blinn-Phong to GGX approximation. Nothing is metallic
+      let load_texture = |file_name: &str, slot: TextureSlot, samplers: &mut texture::SamplerCache| -> Result<MaterialTexture> {
+         let bytes = load_file(file_name)?;
+         let options = options.color_space(slot.color_space());
+         let texture = texture::Texture::from_bytes_with_options(
+            device, queue, samplers, &bytes, file_name, options
+         )?;
+         Ok(MaterialTexture::new(texture).with_source(TextureSource {
+            path: PathBuf::from(file_name),
+            options,
+         }))
+      };
+
+      let mut materials = Vec::with_capacity(obj_materials.len() + 1);
+      for m in obj_materials {
+         // Like a missing MTL file, a missing texture shouldn't stop us from
+         // showing the model
+         let mut load_optional_texture = |file_name: &Option<String>, slot: TextureSlot| {
+            let file_name = file_name.as_ref()?;
+            load_texture(file_name, slot, samplers)
+               .map_err(|e| log::warn!("Couldn't load {:?} texture for material {}: {:#}", slot, m.name, e))
+               .ok()
+         };
+         let normal = load_optional_texture(&m.normal_texture, TextureSlot::Normal); // 2.
+         let base_color = match load_optional_texture(&m.diffuse_texture, TextureSlot::BaseColor) {
+            Some(texture) => texture, // 1.
+            None => {
+               let [r, g, b] = m.diffuse.unwrap_or([1.0, 1.0, 1.0]);
+               let color = [r, g, b].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+               MaterialTexture::new(texture::Texture::from_color(
+                  device, queue, samplers, [color[0], color[1], color[2], 255], &m.name
+               ))
+            },
+         };
+         let mut desc = MaterialDesc::default()
+            .texture(TextureSlot::BaseColor, base_color)
+            .factors(MaterialFactors {
+               roughness: m.shininess.map_or(0.5, |ns| (2.0 / (ns.max(0.0) + 2.0)).sqrt()), // 3.
+               ..Default::default()
+            });
+         if let Some(normal) = normal {
+            desc = desc.texture(TextureSlot::Normal, normal);
+         }
+         materials.push(Material::new(device, queue, samplers, &m.name, desc, layout));
+      }
+
+      // Meshes without a material get a plain white one, added on the end
+      let mut default_material = None;
+
+      let meshes = models.into_iter().map(|m| {
+         let vertices = mesh_vertices(&m.mesh);
+         let material = match m.mesh.material_id {
+            Some(id) if id < materials.len() => id,
+            _ => *default_material.get_or_insert_with(|| {
+               materials.push(Material::new(device, queue, samplers, "default", MaterialDesc::default(), layout));
+               materials.len() - 1
+            }),
+         };
+
+         Mesh::new(device, &m.name, &vertices, &m.mesh.indices, material)
+      }).collect();
+
+      Ok(Self {
+         meshes,
+         materials,
+         nodes: Vec::new(),
+         root_nodes: Vec::new(),
+      })
+   }
+}
+
+// Interleaves tobj's separate position/tex coord/normal arrays into our
+// Vertex layout
+fn mesh_vertices(mesh: &tobj::Mesh) -> Vec<Vertex> {
+   let mut vertices = (0..mesh.positions.len() / 3).map(|i| {
+      // OBJ puts v = 0 at the bottom of the image, wgpu puts it at the top
+      let tex_coords = if mesh.texcoords.len() >= i * 2 + 2 {
+         [mesh.texcoords[i * 2], 1.0 - mesh.texcoords[i * 2 + 1]]
+      } else {
+         [0.0, 0.0]
+      };
+      let normal = if mesh.normals.len() >= i * 3 + 3 {
+         [mesh.normals[i * 3], mesh.normals[i * 3 + 1], mesh.normals[i * 3 + 2]]
+      } else {
+         [0.0, 0.0, 0.0]
+      };
+      Vertex {
+         position: [mesh.positions[i * 3], mesh.positions[i * 3 + 1], mesh.positions[i * 3 + 2]],
+         tex_coords,
+         normal,
+      }
+   }).collect::<Vec<_>>();
+
+   if mesh.normals.is_empty() {
+      compute_normals(&mut vertices, &mesh.indices);
+   }
+   vertices
+}
+
+// Smooth normals for meshes that don't come with any: every vertex gets the
+// average of the normals of the triangles that use it, weighted by area
+// (the cross product's length is twice the triangle's area)
+pub(crate) fn compute_normals(vertices: &mut [Vertex], indices: &[u32]) {
+   let mut normals = vec![cgmath::Vector3::<f32>::zero(); vertices.len()];
+   for triangle in indices.chunks_exact(3) {
+      let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|i| i as usize);
+      let pa: cgmath::Vector3<f32> = vertices[a].position.into();
+      let pb: cgmath::Vector3<f32> = vertices[b].position.into();
+      let pc: cgmath::Vector3<f32> = vertices[c].position.into();
+      let face_normal = (pb - pa).cross(pc - pa);
+      normals[a] += face_normal;
+      normals[b] += face_normal;
+      normals[c] += face_normal;
+   }
+   for (vertex, normal) in vertices.iter_mut().zip(normals) {
+      if normal.magnitude2() > 0.0 {
+         vertex.normal = normal.normalize().into();
+      }
+   }
+}
+
+// Lets us call render_pass.draw_model_instanced(...) like any other draw call.
+// The caller is responsible for setting the pipeline and any bind groups
+// other than the material's (which goes in group 0). The _geometry versions
+// leave the materials out, for passes that only want the depth (like the
+// shadow pass)
+pub trait DrawModel<'a> {
+   fn draw_mesh_instanced(
+      &mut self,
+      mesh: &'a Mesh,
+      material: &'a Material,
+      instances: Range<u32>,
+   );
+
+   fn draw_model_instanced(
+      &mut self,
+      model: &'a Model,
+      instances: Range<u32>,
+   );
+
+   fn draw_mesh_geometry_instanced(
+      &mut self,
+      mesh: &'a Mesh,
+      instances: Range<u32>,
+   );
+
+   fn draw_model_geometry_instanced(
+      &mut self,
+      model: &'a Model,
+      instances: Range<u32>,
+   );
+}
+
+impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
+where
+   'b: 'a,
+{
+   fn draw_mesh_instanced(
+      &mut self,
+      mesh: &'b Mesh,
+      material: &'b Material,
+      instances: Range<u32>,
+   ) {
+      self.set_bind_group(0, &material.bind_group, &[]);
+      self.draw_mesh_geometry_instanced(mesh, instances);
+   }
+
+   fn draw_model_instanced(
+      &mut self,
+      model: &'b Model,
+      instances: Range<u32>,
+   ) {
+      for mesh in &model.meshes {
+         let material = &model.materials[mesh.material];
+         self.draw_mesh_instanced(mesh, material, instances.clone());
+      }
+   }
+
+   fn draw_mesh_geometry_instanced(
+      &mut self,
+      mesh: &'b Mesh,
+      instances: Range<u32>,
+   ) {
+      self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+      self.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
+      self.draw_indexed(0..mesh.num_elements, 0, instances);
+   }
+
+   fn draw_model_geometry_instanced(
+      &mut self,
+      model: &'b Model,
+      instances: Range<u32>,
+   ) {
+      for mesh in &model.meshes {
+         self.draw_mesh_geometry_instanced(mesh, instances.clone());
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn index_format_switches_to_u32_at_65536_vertices() {
+      assert_eq!(Mesh::index_format_for(0), wgpu::IndexFormat::Uint16);
+      // The last vertex's index is 65534, short of the restart value
+      assert_eq!(Mesh::index_format_for(65535), wgpu::IndexFormat::Uint16);
+      // Index 65535 would be 0xFFFF
+      assert_eq!(Mesh::index_format_for(65536), wgpu::IndexFormat::Uint32);
+      assert_eq!(Mesh::index_format_for(65537), wgpu::IndexFormat::Uint32);
+   }
+}
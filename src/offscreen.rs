@@ -0,0 +1,316 @@
+use std::path::{ Path, PathBuf };
+use anyhow::*;
+
+use crate::{ camera, ibl::IblSettings, instance::Instance, light::Light, model, output, preprocessor::ShaderDefs, renderer, shadow::ShadowSettings, texture };
+
+// OffscreenRenderer is the headless counterpart to State: instead of a
+// window and a wgpu::Surface, it owns a texture that it renders into and
+// copies back to the cpu. Nothing here touches winit, so it can run on
+// machines without a display (CI, scripts) using a software adapter.
+pub struct OffscreenRenderer {
+   adapter: wgpu::Adapter,
+   device: wgpu::Device,
+   queue: wgpu::Queue,
+   texture: wgpu::Texture,
+   output: output::Output,
+   width: u32,
+   height: u32,
+   pub clear_color: wgpu::Color,
+   renderer: renderer::Renderer,
+}
+
+impl OffscreenRenderer {
+   // The same format State prefers for its surface, so that headless
+   // output matches what shows up in the window
+   pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+   pub async fn new(width: u32, height: u32) -> Result<Self> {
+      Self::create(width, height, Self::FORMAT, renderer::DepthSettings::default(), wgpu::Features::empty()).await
+   }
+
+   pub async fn with_depth_settings(
+      width: u32,
+      height: u32,
+      depth_settings: renderer::DepthSettings,
+   ) -> Result<Self> {
+      Self::create(width, height, Self::FORMAT, depth_settings, wgpu::Features::empty()).await
+   }
+
+   // Renders into a texture of the given format instead of FORMAT. Output
+   // handles formats that aren't sRGB the same way it does for surfaces, so
+   // the image read back should look the same either way. Only the 8 bit
+   // RGBA formats can be read back
+   pub async fn with_format(width: u32, height: u32, format: wgpu::TextureFormat) -> Result<Self> {
+      Self::create(width, height, format, renderer::DepthSettings::default(), wgpu::Features::empty()).await
+   }
+
+   // Leaves features out of the device even when the adapter has them, to
+   // try out the fallbacks for adapters that don't - e.g. decompressing
+   // compressed textures on the cpu
+   pub async fn without_features(width: u32, height: u32, features: wgpu::Features) -> Result<Self> {
+      Self::create(width, height, Self::FORMAT, renderer::DepthSettings::default(), features).await
+   }
+
+   async fn create(
+      width: u32,
+      height: u32,
+      format: wgpu::TextureFormat,
+      depth_settings: renderer::DepthSettings,
+      disabled_features: wgpu::Features,
+   ) -> Result<Self> {
+      ensure!(width > 0 && height > 0, "offscreen target must be at least 1x1, got {}x{}", width, height);
+      ensure!(
+         matches!(format, wgpu::TextureFormat::Rgba8UnormSrgb | wgpu::TextureFormat::Rgba8Unorm),
+         "offscreen target must be Rgba8UnormSrgb or Rgba8Unorm, got {:?}", format
+      );
+
+      let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+         backends: wgpu::Backends::all(),
+         dx12_shader_compiler: Default::default(),
+      });
+
+      // We prefer the fallback (software) adapter so that output is the
+      // same from machine to machine. Not every platform ships one though,
+      // so if there isn't one we take whatever adapter is available
+      let adapter = match instance.request_adapter(&wgpu::RequestAdapterOptions {
+         power_preference: wgpu::PowerPreference::default(),
+         compatible_surface: None,
+         force_fallback_adapter: true,
+      }).await {
+         Some(adapter) => adapter,
+         None => {
+            log::warn!("No fallback adapter found, using the default adapter instead");
+            instance.request_adapter(&wgpu::RequestAdapterOptions {
+               power_preference: wgpu::PowerPreference::default(),
+               compatible_surface: None,
+               force_fallback_adapter: false,
+            }).await.ok_or_else(|| anyhow!("Couldn't find an adapter to render with"))?
+         }
+      };
+      log::info!("Rendering offscreen with {:?}", adapter.get_info());
+
+      let (device, queue) = adapter.request_device(
+         &wgpu::DeviceDescriptor {
+            features: (adapter.features() & texture::OPTIONAL_FEATURES).difference(disabled_features),
+            limits: wgpu::Limits::downlevel_defaults(),
+            label: None,
+         },
+         None,
+      ).await?;
+
+      // Always goes through the gamma pass for Rgba8Unorm, so that it gets
+      // tested even on machines whose surfaces are all sRGB
+      let mut samplers = texture::SamplerCache::new();
+      let output = output::Output::new(&device, &mut samplers, format, false, width, height);
+
+      // RENDER_ATTACHMENT so we can draw to it, COPY_SRC so we can copy
+      // the result into a buffer the cpu can read
+      let texture = device.create_texture(&wgpu::TextureDescriptor {
+         label: Some("Offscreen Texture"),
+         size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+         mip_level_count: 1,
+         sample_count: 1,
+         dimension: wgpu::TextureDimension::D2,
+         format,
+         usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+         view_formats: &output.view_formats(),
+      });
+
+      let renderer = renderer::Renderer::new(
+         &device, &queue, output.render_format(format), width, height, depth_settings, samplers
+      );
+
+      Ok(Self {
+         adapter,
+         device,
+         queue,
+         texture,
+         output,
+         width,
+         height,
+         clear_color: wgpu::Color::BLACK,
+         renderer,
+      })
+   }
+
+   pub fn size(&self) -> (u32, u32) {
+      (self.width, self.height)
+   }
+
+   // What the device was created from, e.g. for texture::hdr_format
+   pub fn adapter(&self) -> &wgpu::Adapter {
+      &self.adapter
+   }
+
+   // For making textures and other resources of our own on the same
+   // device the renderer draws with
+   pub fn device(&self) -> &wgpu::Device {
+      &self.device
+   }
+
+   pub fn queue(&self) -> &wgpu::Queue {
+      &self.queue
+   }
+
+   pub fn camera(&self) -> &camera::Camera {
+      &self.renderer.camera
+   }
+
+   pub fn camera_mut(&mut self) -> &mut camera::Camera {
+      &mut self.renderer.camera
+   }
+
+   pub fn instances_mut(&mut self) -> &mut Vec<Instance> {
+      &mut self.renderer.instances
+   }
+
+   pub fn lights_mut(&mut self) -> &mut Vec<Light> {
+      &mut self.renderer.lights
+   }
+
+   pub fn set_ambient(&mut self, ambient: [f32; 3]) {
+      self.renderer.ambient = ambient;
+   }
+
+   // What load_model and load_texture load textures with from now on
+   pub fn set_texture_options(&mut self, options: texture::TextureOptions) {
+      self.renderer.texture_options = options;
+   }
+
+   // Draws the given OBJ or glTF file instead of the pentagon
+   pub fn load_model<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+      self.renderer.load_model(&self.device, &self.queue, path)
+   }
+
+   // The model load_model loaded, if there is one
+   pub fn model(&self) -> Option<&model::Model> {
+      self.renderer.model.as_ref()
+   }
+
+   // Uses an image file as the pentagon's texture
+   pub fn load_texture<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+      self.renderer.load_texture(&self.device, &self.queue, path)
+   }
+
+   // Reloads textures from any of the changed files, keeping the old
+   // texture for any that fail. Returns how many were reloaded
+   pub fn reload_textures(&mut self, changed: &[PathBuf]) -> usize {
+      self.renderer.reload_textures(&self.device, &self.queue, changed)
+   }
+
+   // Draws with the WGSL in path instead of the built in shader. On an
+   // error the previous shader stays in use
+   pub fn load_shader<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+      self.renderer.load_shader(&self.device, path)
+   }
+
+   // Switches to the variant of the shader built with these defs
+   pub fn set_shader_defs(&mut self, shader_defs: ShaderDefs) -> Result<()> {
+      self.renderer.set_shader_defs(&self.device, shader_defs)
+   }
+
+   // Draws an equirectangular panorama (converted to a cubemap with
+   // face_size x face_size faces) behind the scene
+   pub fn load_skybox<P: AsRef<Path>>(&mut self, path: P, face_size: u32) -> Result<()> {
+      self.renderer.load_skybox(&self.device, &self.queue, path, face_size)
+   }
+
+   // Draws a cubemap made of six images (+x, -x, +y, -y, +z, -z) behind
+   // the scene
+   pub fn load_skybox_faces<P: AsRef<Path>>(&mut self, paths: &[P]) -> Result<()> {
+      self.renderer.load_skybox_faces(&self.device, &self.queue, paths)
+   }
+
+   // Lights PBR materials with an equirectangular panorama, precomputing
+   // (or loading from settings.cache_dir) the image based lighting for it
+   pub fn load_environment<P: AsRef<Path>>(&mut self, path: P, face_size: u32, settings: &IblSettings) -> Result<()> {
+      self.renderer.load_environment(&self.device, &self.queue, path, face_size, settings)
+   }
+
+   pub fn set_environment_intensity(&mut self, intensity: f32) {
+      self.renderer.environment_intensity = intensity;
+   }
+
+   // Shadows from the first directional light, or none with None
+   pub fn set_shadows(&mut self, settings: Option<ShadowSettings>) {
+      self.renderer.set_shadows(&self.device, settings);
+   }
+
+   pub fn shadow_settings(&self) -> Option<&ShadowSettings> {
+      self.renderer.shadow_settings()
+   }
+
+   // Renders one frame and reads it back into an image
+   pub fn render(&mut self) -> Result<image::RgbaImage> {
+      self.renderer.update(&self.device, &self.queue);
+
+      // copy_texture_to_buffer needs every row to start on a multiple of
+      // COPY_BYTES_PER_ROW_ALIGNMENT (256) bytes, so the buffer rows are
+      // padded and we strip the padding when building the image
+      let unpadded_bytes_per_row = 4 * self.width;
+      let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+      let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+
+      let output_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+         label: Some("Offscreen Output Buffer"),
+         size: (padded_bytes_per_row * self.height) as wgpu::BufferAddress,
+         usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+         mapped_at_creation: false,
+      });
+
+      let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+         label: Some("Offscreen Render Encoder"),
+      });
+
+      self.output.render(&self.renderer, &mut encoder, &self.texture, self.clear_color);
+
+      encoder.copy_texture_to_buffer(
+         wgpu::ImageCopyTexture {
+            aspect: wgpu::TextureAspect::All,
+            texture: &self.texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+         },
+         wgpu::ImageCopyBuffer {
+            buffer: &output_buffer,
+            layout: wgpu::ImageDataLayout {
+               offset: 0,
+               bytes_per_row: Some(padded_bytes_per_row),
+               rows_per_image: Some(self.height),
+            },
+         },
+         wgpu::Extent3d { width: self.width, height: self.height, depth_or_array_layers: 1 },
+      );
+
+      self.queue.submit(std::iter::once(encoder.finish()));
+
+      // map_async only calls back once the device has been polled, so we
+      // block on poll until the copy has finished
+      let buffer_slice = output_buffer.slice(..);
+      let (tx, rx) = std::sync::mpsc::channel();
+      buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+         tx.send(result).ok();
+      });
+      self.device.poll(wgpu::Maintain::Wait);
+      rx.recv()??;
+
+      let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * self.height) as usize);
+      {
+         let data = buffer_slice.get_mapped_range();
+         for row in data.chunks(padded_bytes_per_row as usize) {
+            pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+         }
+      }
+      output_buffer.unmap();
+
+      image::RgbaImage::from_raw(self.width, self.height, pixels)
+         .ok_or_else(|| anyhow!("Read back buffer doesn't match the texture size"))
+   }
+
+   // Renders one frame and writes it out as a png
+   pub fn save_png<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+      let image = self.render()?;
+      image.save_with_format(path, image::ImageFormat::Png)?;
+      Ok(())
+   }
+}
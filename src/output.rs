@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use crate::{ renderer::Renderer, texture::{ SamplerCache, SamplerOptions } };
+
+// Our shaders work in linear color and rely on the color target to gamma
+// encode what they write - which only sRGB formats do. Output works out how
+// to get correctly encoded frames into a target of any format:
+//
+// 1. Direct - the target is sRGB, or floating point (which is linear
+//       anyway), so the renderer draws straight into it
+//
+// 2. SrgbView - the target isn't sRGB, but can be viewed through its sRGB
+//       twin (e.g. Bgra8Unorm as Bgra8UnormSrgb). The renderer draws into
+//       that view and the hardware does the encoding
+//
+// 3. GammaPass - neither works, so the renderer draws into an intermediate
+//       linear texture, and a final full screen pass gamma encodes it into
+//       the target
+pub enum Output {
+   Direct,
+   SrgbView(wgpu::TextureFormat),
+   GammaPass(GammaPass),
+}
+
+impl Output {
+   // allow_srgb_view says whether the target can be created with an sRGB
+   // view format - for surfaces that needs DownlevelFlags::SURFACE_VIEW_FORMATS
+   pub fn new(
+      device: &wgpu::Device,
+      samplers: &mut SamplerCache,
+      target_format: wgpu::TextureFormat,
+      allow_srgb_view: bool,
+      width: u32,
+      height: u32,
+   ) -> Self {
+      let srgb_format = target_format.add_srgb_suffix();
+      if !needs_encoding(target_format) {
+         Self::Direct
+      } else if allow_srgb_view && srgb_format != target_format {
+         Self::SrgbView(srgb_format)
+      } else {
+         log::info!("{:?} isn't sRGB, gamma encoding in a final pass", target_format);
+         Self::GammaPass(GammaPass::new(device, samplers, target_format, width, height))
+      }
+   }
+
+   // The format the renderer's pipelines have to draw into
+   pub fn render_format(&self, target_format: wgpu::TextureFormat) -> wgpu::TextureFormat {
+      match self {
+         Self::Direct => target_format,
+         Self::SrgbView(format) => *format,
+         Self::GammaPass(_) => GammaPass::FORMAT,
+      }
+   }
+
+   // What the target has to list in its view_formats
+   pub fn view_formats(&self) -> Vec<wgpu::TextureFormat> {
+      match self {
+         Self::SrgbView(format) => vec![*format],
+         _ => Vec::new(),
+      }
+   }
+
+   pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+      if let Self::GammaPass(pass) = self {
+         pass.resize(device, width, height);
+      }
+   }
+
+   // Renders a frame into target (a surface texture or one of our own)
+   pub fn render(
+      &self,
+      renderer: &Renderer,
+      encoder: &mut wgpu::CommandEncoder,
+      target: &wgpu::Texture,
+      clear_color: wgpu::Color,
+   ) {
+      let target_view = target.create_view(&wgpu::TextureViewDescriptor {
+         format: match self {
+            Self::SrgbView(format) => Some(*format),
+            _ => None,
+         },
+         ..Default::default()
+      });
+
+      match self {
+         Self::Direct | Self::SrgbView(_) => renderer.render(encoder, &target_view, clear_color),
+         Self::GammaPass(pass) => {
+            renderer.render(encoder, &pass.view, clear_color);
+            pass.encode(encoder, &target_view);
+         },
+      }
+   }
+}
+
+// sRGB formats are encoded by the hardware and float formats hold linear
+// values, everything else needs us to do the encoding
+fn needs_encoding(format: wgpu::TextureFormat) -> bool {
+   use wgpu::TextureFormat::*;
+   !format.is_srgb() && !matches!(format, Rgba16Float | Rgba32Float | Rg11b10Float)
+}
+
+// The intermediate texture and pipeline for Output::GammaPass
+pub struct GammaPass {
+   pipeline: wgpu::RenderPipeline,
+   sampler: Arc<wgpu::Sampler>,
+   view: wgpu::TextureView,
+   bind_group: wgpu::BindGroup,
+}
+
+impl GammaPass {
+   // Half floats so nothing is lost before the encoding, and it's
+   // renderable everywhere
+   const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+   fn new(
+      device: &wgpu::Device,
+      samplers: &mut SamplerCache,
+      target_format: wgpu::TextureFormat,
+      width: u32,
+      height: u32,
+   ) -> Self {
+      let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+         label: Some("Blit Shader"),
+         source: wgpu::ShaderSource::Wgsl(include_str!("blit.wgsl").into()),
+      });
+
+      let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+         label: Some("Gamma Pipeline"),
+         layout: None,
+         vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+         },
+         fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_encode_srgb",
+            targets: &[Some(target_format.into())],
+         }),
+         primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            ..Default::default()
+         },
+         depth_stencil: None,
+         multisample: wgpu::MultisampleState::default(),
+         multiview: None,
+      });
+
+      // Same size as the target, so nearest picks exactly one texel
+      let sampler = samplers.get(device, &SamplerOptions::default().filter(wgpu::FilterMode::Nearest));
+
+      let (view, bind_group) = Self::create_target(device, &pipeline, &sampler, width, height);
+
+      Self {
+         pipeline,
+         sampler,
+         view,
+         bind_group,
+      }
+   }
+
+   fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+      (self.view, self.bind_group) = Self::create_target(device, &self.pipeline, &self.sampler, width, height);
+   }
+
+   fn create_target(
+      device: &wgpu::Device,
+      pipeline: &wgpu::RenderPipeline,
+      sampler: &wgpu::Sampler,
+      width: u32,
+      height: u32,
+   ) -> (wgpu::TextureView, wgpu::BindGroup) {
+      let texture = device.create_texture(&wgpu::TextureDescriptor {
+         label: Some("Gamma Texture"),
+         size: wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: 1,
+         },
+         mip_level_count: 1,
+         sample_count: 1,
+         dimension: wgpu::TextureDimension::D2,
+         format: Self::FORMAT,
+         usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+         view_formats: &[],
+      });
+      let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+      let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+         label: Some("Gamma Bind Group"),
+         layout: &pipeline.get_bind_group_layout(0),
+         entries: &[
+            wgpu::BindGroupEntry {
+               binding: 0,
+               resource: wgpu::BindingResource::TextureView(&view),
+            },
+            wgpu::BindGroupEntry {
+               binding: 1,
+               resource: wgpu::BindingResource::Sampler(sampler),
+            },
+         ],
+      });
+
+      (view, bind_group)
+   }
+
+   fn encode(&self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) {
+      let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+         label: Some("Gamma Pass"),
+         color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: target,
+            resolve_target: None,
+            ops: wgpu::Operations {
+               load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+               store: true,
+            },
+         })],
+         depth_stencil_attachment: None,
+      });
+      render_pass.set_pipeline(&self.pipeline);
+      render_pass.set_bind_group(0, &self.bind_group, &[]);
+      render_pass.draw(0..3, 0..1);
+   }
+}
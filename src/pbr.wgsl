@@ -0,0 +1,168 @@
+// Metallic-roughness materials, lit by the lights in lighting.wgsl with a
+// Cook-Torrance BRDF - see model.rs for the Rust side of Material
+
+#include "lighting.wgsl"
+
+#define PI 3.14159265359
+
+struct Material {
+   base_color: vec4<f32>,
+   emissive: vec3<f32>,
+   metallic: f32,
+   roughness: f32,
+   normal_scale: f32,
+   occlusion_strength: f32,
+};
+
+// Image based lighting, precomputed from an environment map by ibl.rs.
+// Lives in @group(2) next to the lights. Without an environment, the
+// textures are all black
+struct Environment {
+   intensity: f32,
+   max_specular_mip: f32,
+};
+@group(2) @binding(1)
+var<uniform> environment: Environment;
+@group(2) @binding(2)
+var t_irradiance: texture_cube<f32>;
+@group(2) @binding(3)
+var t_specular: texture_cube<f32>;
+@group(2) @binding(4)
+var t_brdf_lut: texture_2d<f32>;
+@group(2) @binding(5)
+var s_environment: sampler;
+
+// How many microfacets line up with the half vector (GGX/Trowbridge-Reitz).
+// Squaring roughness first makes it look perceptually linear
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+   let a = roughness * roughness;
+   let a2 = a * a;
+   let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+   return a2 / (PI * d * d);
+}
+
+// How many of those microfacets are hidden from the light or the camera by
+// their neighbours (Smith's method with Schlick-GGX for each direction)
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+   let r = roughness + 1.0;
+   let k = r * r / 8.0;
+   let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+   let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+   return g_v * g_l;
+}
+
+// How much light is reflected rather than refracted, which goes up at
+// grazing angles (Schlick's approximation). f0 is the reflectance head on
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+   return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// The light reaching us directly from every light - ambient is left to the
+// caller, like blinn_phong
+//
+// 1. Dielectrics all reflect about 4% head on, and color it with nothing.
+//       Metals reflect their albedo
+//
+// 2. Very low roughness turns the highlights of point lights into single
+//       pixels (or nothing at all), so it's kept above 0.04
+//
+// 3. Whatever isn't reflected is refracted and comes back out as diffuse
+//       light - unless the surface is metal, which absorbs it
+fn cook_torrance(
+   albedo: vec3<f32>,
+   metallic: f32,
+   roughness: f32,
+   normal: vec3<f32>,
+   world_position: vec3<f32>,
+   view_position: vec3<f32>,
+) -> vec3<f32> {
+   let to_view = normalize(view_position - world_position);
+   let n_dot_v = max(dot(normal, to_view), 0.0001);
+   let f0 = mix(vec3<f32>(0.04), albedo, metallic); // 1.
+   let clamped_roughness = clamp(roughness, 0.04, 1.0); // 2.
+   var color = vec3<f32>(0.0);
+
+   for (var i = 0u; i < min(lights.count, MAX_LIGHTS); i += 1u) {
+      let sample = sample_light(lights.lights[i], world_position);
+      let radiance = sample.radiance * shadow_factor(i, world_position, normal, sample.to_light);
+      let n_dot_l = max(dot(normal, sample.to_light), 0.0);
+      let half_dir = normalize(sample.to_light + to_view);
+
+      let f = fresnel_schlick(max(dot(half_dir, to_view), 0.0), f0);
+      let d = distribution_ggx(max(dot(normal, half_dir), 0.0), clamped_roughness);
+      let g = geometry_smith(n_dot_v, n_dot_l, clamped_roughness);
+      let specular = d * g * f / (4.0 * n_dot_v * max(n_dot_l, 0.0001));
+      let diffuse = (1.0 - f) * (1.0 - metallic) * albedo / PI; // 3.
+
+      color += (diffuse + specular) * radiance * n_dot_l;
+   }
+
+   return color;
+}
+
+// Fresnel for light coming from everywhere at once. Rough surfaces don't
+// get as bright at grazing angles, since their microfacets mostly face
+// other ways
+fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+   return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// The light reaching us from the environment, like cook_torrance does for
+// the lights:
+//
+// 1. Diffuse is the irradiance in the direction of the normal
+//
+// 2. Specular is the prefiltered environment in the mirror direction, from
+//       the mip level for this roughness, scaled and biased by the BRDF
+//       lookup table
+//
+// Everything uses textureSampleLevel, so it works outside of uniform
+// control flow
+fn image_based_lighting(
+   albedo: vec3<f32>,
+   metallic: f32,
+   roughness: f32,
+   normal: vec3<f32>,
+   to_view: vec3<f32>,
+) -> vec3<f32> {
+   let n_dot_v = max(dot(normal, to_view), 0.0001);
+   let f0 = mix(vec3<f32>(0.04), albedo, metallic);
+   let f = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+
+   let irradiance = textureSampleLevel(t_irradiance, s_environment, normal, 0.0).rgb; // 1.
+   let diffuse = (1.0 - f) * (1.0 - metallic) * albedo * irradiance;
+
+   let reflected = reflect(-to_view, normal); // 2.
+   let prefiltered = textureSampleLevel(t_specular, s_environment, reflected, roughness * environment.max_specular_mip).rgb;
+   let brdf = textureSampleLevel(t_brdf_lut, s_environment, vec2<f32>(n_dot_v, roughness), 0.0).rg;
+   let specular = prefiltered * (f * brdf.x + brdf.y);
+
+   return (diffuse + specular) * environment.intensity;
+}
+
+// Bends normal by a tangent space normal from a normal map. Our vertices
+// don't have tangents, so the tangent frame is worked out per pixel from
+// how the world position (dp) and uv (duv) change across the screen, as
+// given by dpdx and dpdy (a "cotangent frame"). The derivatives are taken
+// by the caller, since fragment shaders are the only place they exist.
+// The bitangent is flipped because our v runs down the image, while normal
+// maps have +Y going up it
+fn perturb_normal(
+   normal: vec3<f32>,
+   dp_dx: vec3<f32>,
+   dp_dy: vec3<f32>,
+   duv_dx: vec2<f32>,
+   duv_dy: vec2<f32>,
+   tangent_normal: vec3<f32>,
+) -> vec3<f32> {
+   let dp_dy_perp = cross(dp_dy, normal);
+   let dp_dx_perp = cross(normal, dp_dx);
+   let tangent = dp_dy_perp * duv_dx.x + dp_dx_perp * duv_dy.x;
+   let bitangent = dp_dy_perp * duv_dx.y + dp_dx_perp * duv_dy.y;
+
+   // Scales both by the same amount so the frame keeps its shape. The
+   // epsilon stops meshes without uvs dividing by zero
+   let scale = inverseSqrt(max(max(dot(tangent, tangent), dot(bitangent, bitangent)), 1e-12));
+   let tbn = mat3x3<f32>(tangent * scale, -bitangent * scale, normal);
+   return normalize(tbn * tangent_normal);
+}
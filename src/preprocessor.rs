@@ -0,0 +1,295 @@
+use std::{
+   collections::{ BTreeMap, HashSet },
+   path::{ Component, Path, PathBuf },
+};
+
+use anyhow::*;
+
+// Shader defs are the #defines a pipeline is built with, on top of any the
+// shader makes itself. The same WGSL built with different defs gives
+// different pipeline variants, e.g. with and without DEBUG_NORMALS
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ShaderDefs {
+   // Name -> value. Flags that are only tested with #ifdef have an empty value
+   defs: BTreeMap<String, String>,
+}
+
+impl ShaderDefs {
+   pub fn new() -> Self {
+      Self::default()
+   }
+
+   pub fn define(mut self, name: &str) -> Self {
+      self.defs.insert(name.to_string(), String::new());
+      self
+   }
+
+   // Every NAME in the shader is replaced by value
+   pub fn define_value(mut self, name: &str, value: &str) -> Self {
+      self.defs.insert(name.to_string(), value.to_string());
+      self
+   }
+
+   pub fn undefine(mut self, name: &str) -> Self {
+      self.defs.remove(name);
+      self
+   }
+
+   pub fn contains(&self, name: &str) -> bool {
+      self.defs.contains_key(name)
+   }
+}
+
+// The WGSL that comes out of preprocess(), plus where each line of it came
+// from so errors can point at the file that was actually written
+pub struct Preprocessed {
+   pub source: String,
+   // The shader and everything it included, as the names given to load
+   pub files: Vec<String>,
+   // (index into files, 1-based line) for each line of source
+   lines: Vec<(usize, u32)>,
+}
+
+impl Preprocessed {
+   // Maps a 1-based line of source back to the file and line it came from
+   pub fn map_line(&self, line: u32) -> Option<(&str, u32)> {
+      let &(file, line) = self.lines.get(line.checked_sub(1)? as usize)?;
+      Some((&self.files[file], line))
+   }
+}
+
+// Runs the preprocessor over the shader called name. load is called with
+// the names of the shader and of anything it #includes (relative to the
+// file including them, like paths), so they can come from disk or be
+// built into the binary (see shader::builtin). Supports:
+//
+//    #include "name.wgsl"   pastes in another file, only the first time
+//                           it's included so shared structs aren't
+//                           declared twice
+//    #define NAME [value]   NAME is defined from here on, and replaced by
+//                           value wherever it appears (if there is one)
+//    #ifdef NAME / #ifndef NAME / #else / #endif
+//
+// Directive lines are dropped rather than blanked, so the output's line
+// numbers don't match any one file - use Preprocessed::map_line
+pub fn preprocess(
+   name: &str,
+   defs: &ShaderDefs,
+   mut load: impl FnMut(&str) -> Result<String>,
+) -> Result<Preprocessed> {
+   let mut state = State {
+      defs: defs.defs.clone(),
+      included: HashSet::new(),
+      output: Preprocessed { source: String::new(), files: Vec::new(), lines: Vec::new() },
+   };
+   state.include(name, &mut load)?;
+   Ok(state.output)
+}
+
+struct State {
+   defs: BTreeMap<String, String>,
+   included: HashSet<String>,
+   output: Preprocessed,
+}
+
+// One #ifdef/#ifndef that we're inside of
+struct Conditional {
+   // Whether the lines under the current branch get used
+   active: bool,
+   // Whether the enclosing block is active at all - if not, neither
+   // branch is
+   parent_active: bool,
+   seen_else: bool,
+}
+
+impl State {
+   fn include(&mut self, name: &str, load: &mut impl FnMut(&str) -> Result<String>) -> Result<()> {
+      if !self.included.insert(name.to_string()) {
+         return Ok(());
+      }
+      let source = load(name)?;
+      let file = self.output.files.len();
+      self.output.files.push(name.to_string());
+
+      let mut conditionals: Vec<Conditional> = Vec::new();
+      for (i, line) in source.lines().enumerate() {
+         let line_number = i as u32 + 1;
+         let at = || format!("{}:{}", name, line_number);
+         let active = conditionals.last().is_none_or(|c| c.active);
+
+         let Some(directive) = line.trim_start().strip_prefix('#') else {
+            if active {
+               let line = self.substitute(line);
+               self.output.source.push_str(&line);
+               self.output.source.push('\n');
+               self.output.lines.push((file, line_number));
+            }
+            continue;
+         };
+
+         let (keyword, rest) = directive.split_once(char::is_whitespace).unwrap_or((directive, ""));
+         let rest = rest.trim();
+         match keyword {
+            "ifdef" | "ifndef" => {
+               let defined = self.defs.contains_key(identifier(rest).with_context(at)?);
+               conditionals.push(Conditional {
+                  active: active && defined == (keyword == "ifdef"),
+                  parent_active: active,
+                  seen_else: false,
+               });
+            },
+            "else" => {
+               let conditional = conditionals.last_mut()
+                  .filter(|c| !c.seen_else)
+                  .ok_or_else(|| anyhow!("{}: #else without #ifdef", at()))?;
+               conditional.active = conditional.parent_active && !conditional.active;
+               conditional.seen_else = true;
+            },
+            "endif" => {
+               conditionals.pop().ok_or_else(|| anyhow!("{}: #endif without #ifdef", at()))?;
+            },
+            _ if !active => {},
+            "define" => {
+               let (define, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+               let define = identifier(define).with_context(at)?;
+               self.defs.insert(define.to_string(), value.trim().to_string());
+            },
+            "include" => {
+               let included = rest.strip_prefix('"')
+                  .and_then(|rest| rest.strip_suffix('"'))
+                  .ok_or_else(|| anyhow!("{}: expected #include \"file\"", at()))?;
+               // Relative to the file doing the including
+               let included = normalize(&Path::new(name).with_file_name(included));
+               self.include(&included.to_string_lossy(), load).with_context(at)?;
+            },
+            _ => bail!("{}: unknown directive #{}", at(), keyword),
+         }
+      }
+
+      ensure!(conditionals.is_empty(), "{}: #ifdef without #endif", name);
+      Ok(())
+   }
+
+   // Swaps defined names for their values, leaving everything else alone
+   fn substitute(&self, line: &str) -> String {
+      let mut output = String::with_capacity(line.len());
+      let mut rest = line;
+      while let Some(start) = rest.find(is_identifier_char) {
+         output.push_str(&rest[..start]);
+         rest = &rest[start..];
+         let end = rest.find(|c: char| !is_identifier_char(c)).unwrap_or(rest.len());
+         // Numbers like 1e5 look like they contain names, so anything that
+         // starts with a digit is left as it is
+         let word = &rest[..end];
+         match self.defs.get(word) {
+            Some(value) if !value.is_empty() && word.starts_with(is_identifier_start) => output.push_str(value),
+            _ => output.push_str(word),
+         }
+         rest = &rest[end..];
+      }
+      output.push_str(rest);
+      output
+   }
+}
+
+// Resolves . and .. without touching the filesystem (names don't have to
+// be files), so a file included by two different routes - lib/x.wgsl and
+// lib/../lib/x.wgsl - is only included once
+fn normalize(path: &Path) -> PathBuf {
+   let mut normalized = PathBuf::new();
+   for component in path.components() {
+      match component {
+         Component::CurDir => {},
+         Component::ParentDir if matches!(normalized.components().next_back(), Some(Component::Normal(_))) => {
+            normalized.pop();
+         },
+         component => normalized.push(component),
+      }
+   }
+   normalized
+}
+
+fn is_identifier_start(c: char) -> bool {
+   c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_identifier_char(c: char) -> bool {
+   c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn identifier(text: &str) -> Result<&str> {
+   let valid = text.starts_with(is_identifier_start) && text.chars().all(is_identifier_char);
+   ensure!(valid, "expected a name, found {:?}", text);
+   Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   // Preprocesses main.wgsl, loading it and its includes from files
+   fn run(files: &[(&str, &str)], defs: &ShaderDefs) -> Result<Preprocessed> {
+      preprocess("main.wgsl", defs, |name| {
+         files.iter()
+            .find(|(file, _)| *file == name)
+            .map(|(_, source)| source.to_string())
+            .ok_or_else(|| anyhow!("no file called {}", name))
+      })
+   }
+
+   #[test]
+   fn include_paths_are_normalized() {
+      let output = run(&[
+         ("main.wgsl", "#include \"lib/x.wgsl\"\n#include \"lib/../lib/x.wgsl\"\n#include \"./lib/y.wgsl\""),
+         ("lib/x.wgsl", "x\n#include \"../lib/y.wgsl\""),
+         ("lib/y.wgsl", "y"),
+      ], &ShaderDefs::new()).unwrap();
+      assert_eq!(output.source, "x\ny\n");
+      assert_eq!(output.files, ["main.wgsl", "lib/x.wgsl", "lib/y.wgsl"]);
+   }
+
+   #[test]
+   fn conditionals_inside_an_inactive_parent_stay_inactive() {
+      let main = "\
+#ifdef A
+#ifdef B
+a_and_b
+#else
+a_not_b
+#endif
+#ifndef B
+a_not_b
+#else
+a_and_b
+#endif
+#else
+not_a
+#endif";
+      let output = run(&[("main.wgsl", main)], &ShaderDefs::new().define("B")).unwrap();
+      assert_eq!(output.source, "not_a\n");
+      let output = run(&[("main.wgsl", main)], &ShaderDefs::new().define("A")).unwrap();
+      assert_eq!(output.source, "a_not_b\na_not_b\n");
+   }
+
+   #[test]
+   fn second_else_is_an_error() {
+      let main = "#ifdef A\na\n#else\nnot_a\n#else\na_again\n#endif";
+      let error = run(&[("main.wgsl", main)], &ShaderDefs::new()).err().unwrap();
+      assert_eq!(error.to_string(), "main.wgsl:5: #else without #ifdef");
+   }
+
+   #[test]
+   fn map_line_across_includes() {
+      let output = run(&[
+         ("main.wgsl", "main_1\n#include \"lib/x.wgsl\"\nmain_3"),
+         ("lib/x.wgsl", "#define X\nx_2\n#ifdef X\nx_4\n#endif"),
+      ], &ShaderDefs::new()).unwrap();
+      assert_eq!(output.source, "main_1\nx_2\nx_4\nmain_3\n");
+      assert_eq!(output.map_line(1), Some(("main.wgsl", 1)));
+      assert_eq!(output.map_line(2), Some(("lib/x.wgsl", 2)));
+      assert_eq!(output.map_line(3), Some(("lib/x.wgsl", 4)));
+      assert_eq!(output.map_line(4), Some(("main.wgsl", 3)));
+      assert_eq!(output.map_line(0), None);
+      assert_eq!(output.map_line(5), None);
+   }
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+
+use anyhow::*;
+
+// Works out from a shader (parsed and validated by naga, see
+// shader::validate_wgsl) what the pipeline around it has to look like, so
+// bind group layouts don't have to be kept in sync with the WGSL by hand
+
+// The layout entries for every @group the shader uses, indexed by group.
+// Each binding is visible to the entry points that use it. The shader
+// can't tell us whether a texture will be filterable, so float textures
+// are assumed to be - which every texture we load is
+pub fn bind_group_layouts(
+   module: &naga::Module,
+   info: &naga::valid::ModuleInfo,
+) -> Result<Vec<Vec<wgpu::BindGroupLayoutEntry>>> {
+   let mut groups = BTreeMap::<u32, Vec<wgpu::BindGroupLayoutEntry>>::new();
+
+   for (handle, variable) in module.global_variables.iter() {
+      let Some(binding) = &variable.binding else { continue };
+
+      let mut visibility = wgpu::ShaderStages::NONE;
+      for (i, entry_point) in module.entry_points.iter().enumerate() {
+         if !info.get_entry_point(i)[handle].is_empty() {
+            visibility |= match entry_point.stage {
+               naga::ShaderStage::Vertex => wgpu::ShaderStages::VERTEX,
+               naga::ShaderStage::Fragment => wgpu::ShaderStages::FRAGMENT,
+               naga::ShaderStage::Compute => wgpu::ShaderStages::COMPUTE,
+            };
+         }
+      }
+
+      let name = variable.name.as_deref().unwrap_or("?");
+      let ty = binding_type(module, variable)
+         .with_context(|| format!("@group({}) @binding({}) {}", binding.group, binding.binding, name))?;
+      groups.entry(binding.group).or_default().push(wgpu::BindGroupLayoutEntry {
+         binding: binding.binding,
+         visibility,
+         ty,
+         count: None,
+      });
+   }
+
+   // Groups the shader skips still need an (empty) layout
+   let group_count = groups.keys().next_back().map_or(0, |group| group + 1);
+   Ok((0..group_count).map(|group| groups.remove(&group).unwrap_or_default()).collect())
+}
+
+fn binding_type(module: &naga::Module, variable: &naga::GlobalVariable) -> Result<wgpu::BindingType> {
+   let inner = &module.types[variable.ty].inner;
+   let ty = match variable.space {
+      naga::AddressSpace::Uniform => wgpu::BindingType::Buffer {
+         ty: wgpu::BufferBindingType::Uniform,
+         has_dynamic_offset: false,
+         // Lets wgpu catch buffers that are too small when the bind group
+         // is made, rather than the shader reading past the end
+         min_binding_size: wgpu::BufferSize::new(inner.size(&module.constants) as u64),
+      },
+      naga::AddressSpace::Storage { access } => wgpu::BindingType::Buffer {
+         ty: wgpu::BufferBindingType::Storage { read_only: !access.contains(naga::StorageAccess::STORE) },
+         has_dynamic_offset: false,
+         min_binding_size: None,
+      },
+      naga::AddressSpace::Handle => match *inner {
+         naga::TypeInner::Sampler { comparison: true } => {
+            wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison)
+         },
+         naga::TypeInner::Sampler { comparison: false } => {
+            wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering)
+         },
+         naga::TypeInner::Image { dim, arrayed, class } => {
+            let view_dimension = match (dim, arrayed) {
+               (naga::ImageDimension::D1, false) => wgpu::TextureViewDimension::D1,
+               (naga::ImageDimension::D2, false) => wgpu::TextureViewDimension::D2,
+               (naga::ImageDimension::D2, true) => wgpu::TextureViewDimension::D2Array,
+               (naga::ImageDimension::D3, false) => wgpu::TextureViewDimension::D3,
+               (naga::ImageDimension::Cube, false) => wgpu::TextureViewDimension::Cube,
+               (naga::ImageDimension::Cube, true) => wgpu::TextureViewDimension::CubeArray,
+               (dim, arrayed) => bail!("unsupported texture dimension {:?} (arrayed: {})", dim, arrayed),
+            };
+            let (sample_type, multisampled) = match class {
+               naga::ImageClass::Sampled { kind, multi } => {
+                  let sample_type = match kind {
+                     naga::ScalarKind::Float => wgpu::TextureSampleType::Float { filterable: true },
+                     naga::ScalarKind::Sint => wgpu::TextureSampleType::Sint,
+                     naga::ScalarKind::Uint => wgpu::TextureSampleType::Uint,
+                     naga::ScalarKind::Bool => bail!("textures can't hold bools"),
+                  };
+                  (sample_type, multi)
+               },
+               naga::ImageClass::Depth { multi } => (wgpu::TextureSampleType::Depth, multi),
+               naga::ImageClass::Storage { .. } => bail!("storage textures aren't supported"),
+            };
+            wgpu::BindingType::Texture { sample_type, view_dimension, multisampled }
+         },
+         _ => bail!("unsupported handle type {:?}", inner),
+      },
+      space => bail!("{:?} variables can't be bound", space),
+   };
+   Ok(ty)
+}
+
+// One @location input to a vertex shader
+struct VertexInput {
+   // e.g. VertexInput.normal
+   name: String,
+   location: u32,
+   kind: naga::ScalarKind,
+   components: u32,
+}
+
+// Checks that buffers (e.g. Vertex::desc and InstanceRaw::desc) give the
+// entry point every @location it takes, with the same number and kind of
+// components. wgpu would catch a missing location too, but only as a
+// panic with none of the names involved
+pub fn check_vertex_buffers(
+   module: &naga::Module,
+   entry_point: &str,
+   buffers: &[wgpu::VertexBufferLayout],
+) -> Result<()> {
+   let function = &module.entry_points.iter()
+      .find(|e| e.stage == naga::ShaderStage::Vertex && e.name == entry_point)
+      .ok_or_else(|| anyhow!("no vertex shader called {}", entry_point))?
+      .function;
+
+   let mut inputs = Vec::new();
+   for argument in &function.arguments {
+      let name = argument.name.as_deref().unwrap_or("?");
+      match &module.types[argument.ty].inner {
+         naga::TypeInner::Struct { members, .. } => {
+            let struct_name = module.types[argument.ty].name.as_deref().unwrap_or(name);
+            for member in members {
+               let member_name = format!("{}.{}", struct_name, member.name.as_deref().unwrap_or("?"));
+               inputs.extend(vertex_input(module, member_name, &member.binding, member.ty)?);
+            }
+         },
+         _ => inputs.extend(vertex_input(module, name.to_string(), &argument.binding, argument.ty)?),
+      }
+   }
+
+   for input in inputs {
+      let mut attributes = buffers.iter()
+         .flat_map(|buffer| buffer.attributes)
+         .filter(|attribute| attribute.shader_location == input.location);
+      let attribute = attributes.next().ok_or_else(|| anyhow!(
+         "{} (@location({})) isn't in any of the vertex buffers", input.name, input.location
+      ))?;
+      ensure!(
+         attributes.next().is_none(),
+         "more than one vertex attribute has shader_location {}", input.location
+      );
+
+      let (kind, components) = format_components(attribute.format);
+      ensure!(
+         kind == input.kind && components == input.components,
+         "{} (@location({})) is {}, but the vertex attribute is {:?}",
+         input.name, input.location, type_name(input.kind, input.components), attribute.format
+      );
+   }
+   Ok(())
+}
+
+fn vertex_input(
+   module: &naga::Module,
+   name: String,
+   binding: &Option<naga::Binding>,
+   ty: naga::Handle<naga::Type>,
+) -> Result<Option<VertexInput>> {
+   // Builtins like vertex_index don't come from a buffer
+   let Some(naga::Binding::Location { location, .. }) = *binding else { return Ok(None) };
+   let (kind, components) = match module.types[ty].inner {
+      naga::TypeInner::Scalar { kind, .. } => (kind, 1),
+      naga::TypeInner::Vector { kind, size, .. } => (kind, size as u32),
+      ref inner => bail!("{} has a type vertex buffers can't provide: {:?}", name, inner),
+   };
+   Ok(Some(VertexInput { name, location, kind, components }))
+}
+
+// What a vertex format looks like to the shader. Normalized formats are
+// read as floats
+fn format_components(format: wgpu::VertexFormat) -> (naga::ScalarKind, u32) {
+   use naga::ScalarKind::{ Float, Sint, Uint };
+   use wgpu::VertexFormat as F;
+
+   match format {
+      F::Uint8x2 | F::Uint16x2 | F::Uint32x2 => (Uint, 2),
+      F::Uint8x4 | F::Uint16x4 | F::Uint32x4 => (Uint, 4),
+      F::Uint32 => (Uint, 1),
+      F::Uint32x3 => (Uint, 3),
+      F::Sint8x2 | F::Sint16x2 | F::Sint32x2 => (Sint, 2),
+      F::Sint8x4 | F::Sint16x4 | F::Sint32x4 => (Sint, 4),
+      F::Sint32 => (Sint, 1),
+      F::Sint32x3 => (Sint, 3),
+      F::Float32 | F::Float64 => (Float, 1),
+      F::Float32x3 | F::Float64x3 => (Float, 3),
+      F::Unorm8x2 | F::Snorm8x2 | F::Unorm16x2 | F::Snorm16x2 | F::Float16x2 | F::Float32x2 | F::Float64x2 => (Float, 2),
+      F::Unorm8x4 | F::Snorm8x4 | F::Unorm16x4 | F::Snorm16x4 | F::Float16x4 | F::Float32x4 | F::Float64x4 => (Float, 4),
+   }
+}
+
+fn type_name(kind: naga::ScalarKind, components: u32) -> String {
+   let scalar = match kind {
+      naga::ScalarKind::Float => "f32",
+      naga::ScalarKind::Sint => "i32",
+      naga::ScalarKind::Uint => "u32",
+      naga::ScalarKind::Bool => "bool",
+   };
+   match components {
+      1 => scalar.to_string(),
+      n => format!("vec{}<{}>", n, scalar),
+   }
+}
+
+// Checks a shader's bind groups (from bind_group_layouts) against the
+// layouts a pipeline already has, which bind groups have been made with.
+// The shader may leave bindings out, or use them in fewer stages, but
+// everything it does use has to be there as the same type
+pub fn check_bind_group_layouts(
+   shader: &[Vec<wgpu::BindGroupLayoutEntry>],
+   pipeline: &[Vec<wgpu::BindGroupLayoutEntry>],
+) -> Result<()> {
+   for (group, entries) in shader.iter().enumerate() {
+      for entry in entries {
+         let at = format!("@group({}) @binding({})", group, entry.binding);
+         let expected = pipeline.get(group)
+            .and_then(|entries| entries.iter().find(|e| e.binding == entry.binding))
+            .ok_or_else(|| anyhow!("the shader uses {}, which isn't in the pipeline layout", at))?;
+         ensure!(
+            entry.ty == expected.ty,
+            "the shader's {} is {:?}, but the pipeline layout has {:?}", at, entry.ty, expected.ty
+         );
+         ensure!(
+            expected.visibility.contains(entry.visibility),
+            "the shader uses {} in {:?}, but the pipeline layout only makes it visible to {:?}",
+            at, entry.visibility, expected.visibility
+         );
+      }
+   }
+   Ok(())
+}
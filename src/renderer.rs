@@ -0,0 +1,930 @@
+use std::path::{ Path, PathBuf };
+
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+   camera,
+   ibl::{ EnvironmentUniform, Ibl, IblSettings },
+   instance::{ Instance, InstanceRaw },
+   light::{ Light, LightsUniform },
+   model::{ self, DrawModel, Vertex },
+   preprocessor::{ self, ShaderDefs },
+   reflect,
+   shader,
+   shadow::{ ShadowMap, ShadowSettings },
+   skybox::{ self, Skybox },
+   texture,
+};
+
+pub const VERTICES: &[Vertex] = &[
+   Vertex { position: [-0.0868241, 0.49240386, 0.0], tex_coords: [0.4131759, 0.00759614], normal: [0.0, 0.0, 1.0] }, // A
+   Vertex { position: [-0.49513406, 0.06958647, 0.0], tex_coords: [0.0048659444, 0.43041354], normal: [0.0, 0.0, 1.0] }, // B
+   Vertex { position: [-0.21918549, -0.44939706, 0.0], tex_coords: [0.28081453, 0.949397], normal: [0.0, 0.0, 1.0] }, // C
+   Vertex { position: [0.35966998, -0.3473291, 0.0], tex_coords: [0.85967, 0.84732914], normal: [0.0, 0.0, 1.0] }, // D
+   Vertex { position: [0.44147372, 0.2347359, 0.0], tex_coords: [0.9414737, 0.2652641], normal: [0.0, 0.0, 1.0] }, // E
+];
+// vertices are arranged in counter-clockwise fashion
+
+pub const INDICES: &[u32] = &[
+   0, 1, 4,
+   1, 2, 4,
+   2, 3, 4
+];
+
+// How the pipeline tests fragments against the depth buffer.
+//
+// The default (Depth32Float + Less) keeps the fragment closest to the
+// camera. Reverse-z setups use Greater/GreaterEqual, in which case we
+// clear the depth buffer to 0.0 instead of 1.0
+#[derive(Copy, Clone, Debug)]
+pub struct DepthSettings {
+   pub format: wgpu::TextureFormat,
+   pub compare: wgpu::CompareFunction,
+}
+
+impl Default for DepthSettings {
+   fn default() -> Self {
+      Self {
+         format: texture::Texture::DEPTH_FORMAT,
+         compare: wgpu::CompareFunction::Less,
+      }
+   }
+}
+
+impl DepthSettings {
+   fn clear_value(&self) -> f32 {
+      match self.compare {
+         wgpu::CompareFunction::Greater | wgpu::CompareFunction::GreaterEqual => 0.0,
+         _ => 1.0,
+      }
+   }
+}
+
+// Renderer owns everything needed to draw the scene, but not the thing we
+// draw into. State hands it a view of the current surface texture, while
+// OffscreenRenderer hands it a view of a texture it owns - so both the
+// windowed and the headless paths share exactly the same pipeline.
+pub struct Renderer {
+   render_pipeline: wgpu::RenderPipeline,
+   // Kept so render_pipeline can be rebuilt when the shader changes
+   render_pipeline_layout: wgpu::PipelineLayout,
+   // What render_pipeline_layout was made from, for checking new shaders
+   // against
+   bind_group_layouts: Vec<Vec<wgpu::BindGroupLayoutEntry>>,
+   // Where the shader came from, if it wasn't the built in shader.wgsl
+   shader_path: Option<PathBuf>,
+   // shader_path and everything it includes, to watch for changes
+   shader_files: Vec<PathBuf>,
+   // The defs render_pipeline's shader was built with
+   shader_defs: ShaderDefs,
+   // The kirbyface pentagon from VERTICES/INDICES, drawn when there's no model
+   pentagon: model::Model,
+   // Kept around so we can build bind groups for models' materials later
+   texture_bind_group_layout: wgpu::BindGroupLayout,
+   // Shared by every texture the renderer loads
+   samplers: texture::SamplerCache,
+   // What load_model and load_texture load textures with. Turn on
+   // mipmaps or set a sampler here for e.g. repeating, trilinear filtered
+   // textures
+   pub texture_options: texture::TextureOptions,
+   // Drawn instead of the pentagon when set
+   pub model: Option<model::Model>,
+   pub camera: camera::Camera,
+   camera_uniform: camera::CameraUniform,
+   camera_buffer: wgpu::Buffer,
+   camera_bind_group: wgpu::BindGroup,
+   // Edit these freely - they get uploaded to lights_buffer in update().
+   // Only the first light::MAX_LIGHTS lights are used
+   pub lights: Vec<Light>,
+   // Light every surface gets, however it faces
+   pub ambient: [f32; 3],
+   lights_buffer: wgpu::Buffer,
+   // The lights, the image based lighting and the shadow map share
+   // @group(2), so setting a new environment (or new shadow settings)
+   // means a new bind group
+   lights_bind_group_layout: wgpu::BindGroupLayout,
+   lights_bind_group: wgpu::BindGroup,
+   ibl: Ibl,
+   // Scales the light from the environment, uploaded in update()
+   pub environment_intensity: f32,
+   environment_buffer: wgpu::Buffer,
+   // Off until set_shadows turns it on
+   shadow_map: ShadowMap,
+   // The color target format, needed to build pipelines after new()
+   format: wgpu::TextureFormat,
+   depth_settings: DepthSettings,
+   depth_texture: texture::Texture,
+   skybox: Option<Skybox>,
+   // Lets the clear color show through again without unloading the skybox
+   pub show_skybox: bool,
+   // Edit these freely - they get uploaded to instance_buffer in update()
+   pub instances: Vec<Instance>,
+   instance_buffer: wgpu::Buffer,
+   // How many instances instance_buffer has room for
+   instance_capacity: usize,
+}
+
+impl Renderer {
+   // format is the format of the color target we will be rendering into:
+   // the surface format for State, the owned texture format for
+   // OffscreenRenderer. width and height are its size in pixels. samplers
+   // becomes the renderer's sampler cache, so anything made with it
+   // beforehand (like Output's gamma pass) shares samplers with us
+   pub fn new(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      format: wgpu::TextureFormat,
+      width: u32,
+      height: u32,
+      depth_settings: DepthSettings,
+      mut samplers: texture::SamplerCache,
+   ) -> Self {
+      let diffuse_bytes = include_bytes!("kirbyface.png");
+      let diffuse_texture = texture::Texture::from_bytes_with_options(
+         device, queue, &mut samplers, diffuse_bytes, "kirbyface.png", texture::TextureOptions::default()
+      ).unwrap();
+
+      // The shader is preprocessed (for its #includes) and checked by naga
+      // up front, so we can look at what it expects from us. Its vertex
+      // inputs have to match Vertex::desc and InstanceRaw::desc
+      let shader_source = preprocessor::preprocess("shader.wgsl", &ShaderDefs::default(), shader::builtin).unwrap();
+      let (shader_module, shader_info) = shader::validate_wgsl(&shader_source).unwrap();
+      reflect::check_vertex_buffers(&shader_module, "vs_main", &Self::vertex_buffers()).unwrap();
+      let bind_group_layouts = reflect::bind_group_layouts(&shader_module, &shader_info).unwrap();
+
+      // texture_bind_group_layout- BindGroup describes a set of resources and how they can be
+      // accessed by a shader. Our texture bindgroup layout has an entry for each of a
+      // material's textures, one for the sampler they share at binding 1, and
+      // one for the material's factors at binding 6 (see model::Material)
+      // they're only visible to the fragment shader (this will be the case most of the time)
+      //
+      // Rather than writing the entries out here and keeping them in sync
+      // with @group(0) in shader.wgsl by hand, reflect builds them from it
+      let texture_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+         label: Some("texture_bind_group_layout"),
+         entries: &bind_group_layouts[0],
+      });
+
+      // Material::new builds the material's bind group from the texture (plus
+      // stand ins for the textures it doesn't have), using the layout above
+      let diffuse_material = model::Material::new(
+         device, queue, &mut samplers, "kirbyface",
+         model::MaterialDesc::default()
+            .texture(model::TextureSlot::BaseColor, model::MaterialTexture::new(diffuse_texture)),
+         &texture_bind_group_layout,
+      );
+      // The reason why the BindGroup layout is so descriptive - it allows us to
+      // swap out BindGroups on the fly as long as they all share the same BindGroupLayout
+      //
+      // Each texture and sampler we create will need to be added to a BindGroup
+
+
+      // SET UP CAMERA
+
+      // The camera's view-projection matrix lives in a uniform buffer. We write
+      // to it with queue.write_buffer in update(), so it needs COPY_DST
+      let camera = camera::Camera::new(width, height);
+      let mut camera_uniform = camera::CameraUniform::new();
+      camera_uniform.update_view_proj(&camera);
+
+      let camera_buffer = device.create_buffer_init(
+         &wgpu::util::BufferInitDescriptor {
+            label: Some("Camera Buffer"),
+            contents: bytemuck::cast_slice(&[camera_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+         }
+      );
+
+      // The vertex shader needs the camera to move the vertices into clip
+      // space, and the fragment shader needs its position for specular
+      // highlights - reflect sees both using @group(1)
+      let camera_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+         label: Some("camera_bind_group_layout"),
+         entries: &bind_group_layouts[1],
+      });
+
+      let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+         label: Some("camera_bind_group"),
+         layout: &camera_bind_group_layout,
+         entries: &[
+            wgpu::BindGroupEntry {
+               binding: 0,
+               resource: camera_buffer.as_entire_binding(),
+            }
+         ],
+      });
+
+
+
+      // SET UP LIGHTS
+
+      // With no lights and full ambient, everything looks exactly like its
+      // texture - lights are added by whoever owns the renderer
+      let lights = Vec::new();
+      let ambient = [1.0, 1.0, 1.0];
+      let lights_buffer = device.create_buffer_init(
+         &wgpu::util::BufferInitDescriptor {
+            label: Some("Lights Buffer"),
+            contents: bytemuck::cast_slice(&[LightsUniform::new(ambient, &lights)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+         }
+      );
+      let lights_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+         label: Some("lights_bind_group_layout"),
+         entries: &bind_group_layouts[2],
+      });
+
+      // No environment to begin with - set_environment adds one
+      let ibl = Ibl::empty(device, &mut samplers);
+      let environment_intensity = 1.0;
+      let environment_buffer = device.create_buffer_init(
+         &wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Buffer"),
+            contents: bytemuck::cast_slice(&[EnvironmentUniform::new(&ibl, environment_intensity)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+         }
+      );
+      // No shadows either, set_shadows turns them on
+      let shadow_map = ShadowMap::new(device, &mut samplers, None, &Self::vertex_buffers());
+      let lights_bind_group = Self::create_lights_bind_group(
+         device, &lights_bind_group_layout, &lights_buffer, &environment_buffer, &ibl, &shadow_map
+      );
+
+
+      let depth_texture = texture::Texture::create_depth_texture(
+         device, &mut samplers, width, height, depth_settings.format, "depth_texture"
+      );
+
+
+      // SET UP PIPELINE
+
+      let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+         label: Some("Shader"),
+         source: wgpu::ShaderSource::Wgsl(shader_source.source.into()),
+      });
+
+      let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+         label: Some("Render Pipeline Layout"),
+         bind_group_layouts: &[
+            &texture_bind_group_layout, // @group(0)
+            &camera_bind_group_layout,  // @group(1)
+            &lights_bind_group_layout,  // @group(2)
+         ],
+         push_constant_ranges: &[]
+      });
+
+      let render_pipeline = Self::create_render_pipeline(
+         device, &render_pipeline_layout, &shader, format, depth_settings
+      );
+
+      // Mesh::new picks Uint16 indices for us since the pentagon only has 5 vertices
+      let pentagon = model::Model {
+         meshes: vec![model::Mesh::new(device, "pentagon", VERTICES, INDICES, 0)],
+         materials: vec![diffuse_material],
+         nodes: Vec::new(),
+         root_nodes: Vec::new(),
+      };
+
+      // Start out with a single, untransformed copy of the mesh
+      let instances = vec![Instance::default()];
+      let instance_capacity = instances.len();
+      let instance_buffer = Self::create_instance_buffer(device, &instances);
+
+      Self {
+         render_pipeline,
+         render_pipeline_layout,
+         bind_group_layouts,
+         shader_path: None,
+         shader_files: Vec::new(),
+         shader_defs: ShaderDefs::default(),
+         pentagon,
+         texture_bind_group_layout,
+         samplers,
+         texture_options: texture::TextureOptions::default(),
+         model: None,
+         camera,
+         camera_uniform,
+         camera_buffer,
+         camera_bind_group,
+         lights,
+         ambient,
+         lights_buffer,
+         lights_bind_group_layout,
+         lights_bind_group,
+         ibl,
+         environment_intensity,
+         environment_buffer,
+         shadow_map,
+         format,
+         depth_settings,
+         depth_texture,
+         skybox: None,
+         show_skybox: true,
+         instances,
+         instance_buffer,
+         instance_capacity,
+      }
+   }
+
+   // Loads a model (and its materials) to draw instead of the pentagon.
+   // .gltf and .glb files go through the glTF importer, anything else is
+   // treated as OBJ
+   pub fn load_model<P: AsRef<Path>>(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      path: P,
+   ) -> Result<()> {
+      let path = path.as_ref();
+      let layout = &self.texture_bind_group_layout;
+      let is_gltf = path.extension()
+         .and_then(|ext| ext.to_str())
+         .is_some_and(|ext| ext.eq_ignore_ascii_case("gltf") || ext.eq_ignore_ascii_case("glb"));
+      let model = if is_gltf {
+         model::Model::load_gltf(device, queue, layout, &mut self.samplers, self.texture_options, path)?
+      } else {
+         model::Model::load(device, queue, layout, &mut self.samplers, self.texture_options, path)?
+      };
+      self.model = Some(model);
+      Ok(())
+   }
+
+   // Loads an image from disk as the pentagon's texture, instead of the
+   // kirbyface baked into the binary. Textures loaded from files can be
+   // hot reloaded, see reload_textures
+   pub fn load_texture<P: AsRef<Path>>(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      path: P,
+   ) -> Result<()> {
+      let source = model::TextureSource { path: path.as_ref().to_path_buf(), options: self.texture_options };
+      let diffuse_texture = model::MaterialTexture::from_file(device, queue, &mut self.samplers, source)?;
+      let material = model::Material::new(
+         device, queue, &mut self.samplers, "kirbyface",
+         model::MaterialDesc::default().texture(model::TextureSlot::BaseColor, diffuse_texture),
+         &self.texture_bind_group_layout,
+      );
+      self.pentagon.materials = vec![material];
+      Ok(())
+   }
+
+   // Every texture file in use, for a FileWatcher to keep an eye on
+   pub fn texture_paths(&self) -> Vec<PathBuf> {
+      self.materials()
+         .flat_map(|material| material.sources())
+         .map(|(_, source)| source.path.clone())
+         .collect()
+   }
+
+   // Reloads the textures that were loaded from any of the changed files,
+   // rebuilding their bind groups in place. A texture that fails to load
+   // (e.g. a half written file) is logged and the old one stays on screen.
+   // Returns how many textures were reloaded
+   pub fn reload_textures(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      changed: &[PathBuf],
+   ) -> usize {
+      // The watcher reports canonical paths, the materials have them however
+      // they were given to us
+      let changed = changed.iter()
+         .filter_map(|path| path.canonicalize().ok())
+         .collect::<Vec<_>>();
+      let layout = &self.texture_bind_group_layout;
+      let materials = self.pentagon.materials.iter_mut()
+         .chain(self.model.iter_mut().flat_map(|model| model.materials.iter_mut()));
+
+      let mut reloaded = 0;
+      for material in materials {
+         let stale = material.sources()
+            .filter_map(|(slot, source)| Some((slot, source.path.canonicalize().ok()?)))
+            .filter(|(_, path)| changed.contains(path))
+            .collect::<Vec<_>>();
+         for (slot, path) in stale {
+            if let Err(e) = material.reload(device, queue, &mut self.samplers, slot, layout) {
+               log::warn!("Couldn't reload {}: {:#}", path.display(), e);
+               continue;
+            }
+            log::info!("Reloaded {}", path.display());
+            reloaded += 1;
+         }
+      }
+      reloaded
+   }
+
+   fn materials(&self) -> impl Iterator<Item = &model::Material> {
+      self.pentagon.materials.iter()
+         .chain(self.model.iter().flat_map(|model| model.materials.iter()))
+   }
+
+   // Rebuilds render_pipeline with the WGSL in path instead of the built in
+   // shader.wgsl. If the shader doesn't compile, or doesn't fit the
+   // pipeline, we get an error (with file, line and column where naga
+   // knows them) and carry on drawing with the pipeline we had
+   pub fn load_shader<P: AsRef<Path>>(&mut self, device: &wgpu::Device, path: P) -> Result<()> {
+      let path = path.as_ref();
+      let (render_pipeline, shader_files) = self.build_pipeline(device, Some(path), &self.shader_defs)?;
+      self.render_pipeline = render_pipeline;
+      self.shader_path = Some(path.to_path_buf());
+      self.shader_files = shader_files;
+      Ok(())
+   }
+
+   // Rebuilds render_pipeline as the variant of the current shader with
+   // these defs (see preprocessor::ShaderDefs). Errors leave the old
+   // pipeline in place, like load_shader
+   pub fn set_shader_defs(&mut self, device: &wgpu::Device, shader_defs: ShaderDefs) -> Result<()> {
+      let shader_path = self.shader_path.clone();
+      let (render_pipeline, shader_files) = self.build_pipeline(device, shader_path.as_deref(), &shader_defs)?;
+      self.render_pipeline = render_pipeline;
+      self.shader_files = shader_files;
+      self.shader_defs = shader_defs;
+      Ok(())
+   }
+
+   pub fn shader_defs(&self) -> &ShaderDefs {
+      &self.shader_defs
+   }
+
+   // The shader file and everything it includes, empty when we're using
+   // the built in shader
+   pub fn shader_paths(&self) -> &[PathBuf] {
+      &self.shader_files
+   }
+
+   // Loads the shader again if any of its files are among the changed ones.
+   // Errors are logged rather than returned - while editing, a broken
+   // shader is expected and the last good one keeps running. Returns
+   // whether the pipeline was rebuilt
+   pub fn reload_shader(&mut self, device: &wgpu::Device, changed: &[PathBuf]) -> bool {
+      let Some(path) = self.shader_path.clone() else { return false };
+      let changed = changed.iter()
+         .filter_map(|path| path.canonicalize().ok())
+         .collect::<Vec<_>>();
+      let is_changed = self.shader_files.iter()
+         .any(|file| file.canonicalize().is_ok_and(|file| changed.contains(&file)));
+      if !is_changed {
+         return false;
+      }
+      if let Err(e) = self.load_shader(device, &path) {
+         log::error!("{:#}", e);
+         return false;
+      }
+      log::info!("Reloaded {}", path.display());
+      true
+   }
+
+   // Preprocesses and checks the shader at path (or the built in one),
+   // then builds a pipeline with it. Also returns the files that went into
+   // the shader
+   fn build_pipeline(
+      &self,
+      device: &wgpu::Device,
+      path: Option<&Path>,
+      shader_defs: &ShaderDefs,
+   ) -> Result<(wgpu::RenderPipeline, Vec<PathBuf>)> {
+      let shader_source = match path {
+         Some(path) => preprocessor::preprocess(&path.to_string_lossy(), shader_defs, |name| {
+            std::fs::read_to_string(name).with_context(|| format!("Couldn't read {}", name))
+         })?,
+         None => preprocessor::preprocess("shader.wgsl", shader_defs, shader::builtin)?,
+      };
+      // Bind groups have already been made with the layouts we have, so the
+      // shader has to fit them rather than the other way around
+      let (shader_module, shader_info) = shader::validate_wgsl(&shader_source)?;
+      reflect::check_vertex_buffers(&shader_module, "vs_main", &Self::vertex_buffers())?;
+      reflect::check_bind_group_layouts(
+         &reflect::bind_group_layouts(&shader_module, &shader_info)?, &self.bind_group_layouts
+      )?;
+
+      let label = shader_source.files[0].clone();
+      let shader_files = match path {
+         Some(_) => shader_source.files.iter().map(PathBuf::from).collect(),
+         None => Vec::new(),
+      };
+      let render_pipeline = shader::catch_validation_errors(device, || {
+         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&label),
+            source: wgpu::ShaderSource::Wgsl(shader_source.source.into()),
+         });
+         Self::create_render_pipeline(
+            device, &self.render_pipeline_layout, &shader, self.format, self.depth_settings
+         )
+      }).with_context(|| format!("{} doesn't fit the render pipeline", label))?;
+      Ok((render_pipeline, shader_files))
+   }
+
+   // Draws cubemap (see Texture::from_cube_faces/from_equirectangular)
+   // behind the scene, replacing any skybox there was before
+   pub fn set_skybox(&mut self, device: &wgpu::Device, cubemap: texture::Texture) {
+      self.skybox = Some(Skybox::new(device, cubemap, self.format, self.depth_settings.format));
+   }
+
+   // Loads an equirectangular panorama and turns it into the skybox.
+   // .hdr and .exr files keep their full range in an Rgba16Float cubemap
+   pub fn load_skybox<P: AsRef<Path>>(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      path: P,
+      face_size: u32,
+   ) -> Result<()> {
+      let equirect = self.load_panorama(device, queue, path.as_ref())?;
+      self.set_skybox_panorama(device, queue, &equirect, face_size)
+   }
+
+   // .hdr and .exr files keep their full range in Rgba16Float, anything
+   // else is loaded like any other image
+   fn load_panorama(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, path: &Path) -> Result<texture::Texture> {
+      let label = path.to_string_lossy();
+      let bytes = std::fs::read(path)
+         .with_context(|| format!("Couldn't read {}", path.display()))?;
+      let is_hdr = path.extension()
+         .and_then(|ext| ext.to_str())
+         .is_some_and(|ext| ext.eq_ignore_ascii_case("hdr") || ext.eq_ignore_ascii_case("exr"));
+
+      let options = skybox::texture_options();
+      if is_hdr {
+         texture::Texture::from_hdr_bytes(
+            device, queue, &mut self.samplers, &bytes, &label, wgpu::TextureFormat::Rgba16Float, options
+         )
+      } else {
+         texture::Texture::from_bytes_with_options(device, queue, &mut self.samplers, &bytes, &label, options)
+      }
+   }
+
+   // Converts an equirectangular panorama into a cubemap for the skybox
+   pub fn set_skybox_panorama(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      equirect: &texture::Texture,
+      face_size: u32,
+   ) -> Result<()> {
+      let cubemap = texture::Texture::from_equirectangular(
+         device, queue, &mut self.samplers, equirect, face_size, Some("skybox"), skybox::texture_options()
+      )?;
+      self.set_skybox(device, cubemap);
+      Ok(())
+   }
+
+   // Loads six images (+x, -x, +y, -y, +z, -z) as the skybox
+   pub fn load_skybox_faces<P: AsRef<Path>>(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      paths: &[P],
+   ) -> Result<()> {
+      let faces = paths.iter().map(|path| {
+         let path = path.as_ref();
+         image::open(path).with_context(|| format!("Couldn't load {}", path.display()))
+      }).collect::<Result<Vec<_>>>()?;
+
+      let cubemap = texture::Texture::from_cube_faces(
+         device, queue, &mut self.samplers, &faces, Some("skybox"), skybox::texture_options()
+      )?;
+      self.set_skybox(device, cubemap);
+      Ok(())
+   }
+
+   // Lights PBR materials with cubemap as well as the lights, see ibl.rs.
+   // This is separate from the skybox - use the same cubemap for both to
+   // see what's being reflected
+   pub fn set_environment(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      cubemap: &texture::Texture,
+      settings: &IblSettings,
+   ) -> Result<()> {
+      self.ibl = Ibl::new(device, queue, &mut self.samplers, cubemap, settings)?;
+      self.lights_bind_group = self.rebuild_lights_bind_group(device);
+      Ok(())
+   }
+
+   // Like set_skybox_panorama, for the environment. face_size is the size
+   // of the cubemap the panorama is turned into, which the IBL textures
+   // are made from
+   pub fn set_environment_panorama(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      equirect: &texture::Texture,
+      face_size: u32,
+      settings: &IblSettings,
+   ) -> Result<()> {
+      let cubemap = texture::Texture::from_equirectangular(
+         device, queue, &mut self.samplers, equirect, face_size, Some("environment"), skybox::texture_options()
+      )?;
+      self.set_environment(device, queue, &cubemap, settings)
+   }
+
+   // Loads an equirectangular panorama as the environment, like load_skybox
+   pub fn load_environment<P: AsRef<Path>>(
+      &mut self,
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      path: P,
+      face_size: u32,
+      settings: &IblSettings,
+   ) -> Result<()> {
+      let equirect = self.load_panorama(device, queue, path.as_ref())?;
+      self.set_environment_panorama(device, queue, &equirect, face_size, settings)
+   }
+
+   // Turns on shadows from the first directional light in lights (see
+   // shadow.rs), or with None turns them off again
+   pub fn set_shadows(&mut self, device: &wgpu::Device, settings: Option<ShadowSettings>) {
+      self.shadow_map = ShadowMap::new(device, &mut self.samplers, settings, &Self::vertex_buffers());
+      self.lights_bind_group = self.rebuild_lights_bind_group(device);
+   }
+
+   pub fn shadow_settings(&self) -> Option<&ShadowSettings> {
+      self.shadow_map.settings()
+   }
+
+   fn rebuild_lights_bind_group(&self, device: &wgpu::Device) -> wgpu::BindGroup {
+      Self::create_lights_bind_group(
+         device, &self.lights_bind_group_layout, &self.lights_buffer, &self.environment_buffer,
+         &self.ibl, &self.shadow_map,
+      )
+   }
+
+   fn create_lights_bind_group(
+      device: &wgpu::Device,
+      layout: &wgpu::BindGroupLayout,
+      lights_buffer: &wgpu::Buffer,
+      environment_buffer: &wgpu::Buffer,
+      ibl: &Ibl,
+      shadow_map: &ShadowMap,
+   ) -> wgpu::BindGroup {
+      device.create_bind_group(&wgpu::BindGroupDescriptor {
+         label: Some("lights_bind_group"),
+         layout,
+         entries: &[
+            wgpu::BindGroupEntry {
+               binding: 0,
+               resource: lights_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+               binding: 1,
+               resource: environment_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+               binding: 2,
+               resource: wgpu::BindingResource::TextureView(&ibl.irradiance.view),
+            },
+            wgpu::BindGroupEntry {
+               binding: 3,
+               resource: wgpu::BindingResource::TextureView(&ibl.specular.view),
+            },
+            wgpu::BindGroupEntry {
+               binding: 4,
+               resource: wgpu::BindingResource::TextureView(&ibl.brdf_lut.view),
+            },
+            // All three were made with the same sampler options
+            wgpu::BindGroupEntry {
+               binding: 5,
+               resource: wgpu::BindingResource::Sampler(&ibl.specular.sampler),
+            },
+            wgpu::BindGroupEntry {
+               binding: 6,
+               resource: shadow_map.buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+               binding: 7,
+               resource: wgpu::BindingResource::TextureView(&shadow_map.texture.view),
+            },
+            wgpu::BindGroupEntry {
+               binding: 8,
+               resource: wgpu::BindingResource::Sampler(&shadow_map.texture.sampler),
+            },
+         ],
+      })
+   }
+
+   fn create_render_pipeline(
+      device: &wgpu::Device,
+      layout: &wgpu::PipelineLayout,
+      shader: &wgpu::ShaderModule,
+      format: wgpu::TextureFormat,
+      depth_settings: DepthSettings,
+   ) -> wgpu::RenderPipeline {
+      let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+         label: Some("Render Pipeline"),
+         layout: Some(layout),
+         vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main", // 1.
+            buffers: &Self::vertex_buffers(), // 2.
+         },
+         fragment: Some(wgpu::FragmentState { // 3.
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState { // 4.
+               format,
+               blend: Some(wgpu::BlendState::REPLACE),
+               write_mask: wgpu::ColorWrites::ALL
+            })]
+         }),
+         primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList, // 5.
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw, // 6.
+            cull_mode: Some(wgpu::Face::Back),
+            // below: Setting polygon_mode to anything other than Fill requires
+            //          Features::NON_FILL_POLYGON_MODE
+            polygon_mode: wgpu::PolygonMode::Fill,
+            // below: requires Features::DEPTH_CLIP_CONTROL
+            unclipped_depth: false,
+            // below: requires Features::CONSERVATIVE_RASTERIZATION
+            conservative: false,
+         },
+         depth_stencil: Some(wgpu::DepthStencilState { // 7.
+            format: depth_settings.format,
+            depth_write_enabled: true,
+            depth_compare: depth_settings.compare,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+         }),
+         multisample: wgpu::MultisampleState {
+            count: 1, // 8.
+            mask: !0, // 9.
+            alpha_to_coverage_enabled: false, // 10.
+         },
+         multiview: None, // 11.
+      });
+
+      // 1. Specify which function inside the shader should be the entry_point:
+      //       functions we marked with @vertex and @fragment
+      //
+      // 2. buffers tells the wgpu what type of vertices we want to pass to the
+      //       vertex shader - one buffer stepped per vertex, and one stepped
+      //       per instance
+      //
+      // 3. Fragment is technically optional so we wrap it in Some()
+      //       needed if we want to store color data to surface
+      //
+      // 4. targets field tells wgpu what color outputs it should set up.
+      //       We only need one for the surface. We use the surface's format
+      //       so copying to the surface is easy.
+      //       We specify that the blending should replace old pixel data with new
+      //       We tell wgpu to write to R,G,B, and A (all colors)
+      //
+      // 5. Using PrimitiveTopology::TriangleList means that every three vertices
+      //       will correspond to one triangle
+      //
+      // 6. front_face and cull_mode tell wgpu how to determine whether a given
+      //       triangle is facing forward or noot
+      //       FrontFace::Ccw means that a triangle is facing forward if the
+      //       vertices are arranged in a counter-clockwise direction -
+      //       triangles not facing forward are culled (not included in render)
+      //       as specified by CullMode::Back
+      //
+      // 7. depth_compare tells wgpu when to keep a new fragment - with Less,
+      //       fragments behind what's already been drawn get discarded.
+      //       Every draw in the pass writes its depth so later draws test
+      //       against it
+      //
+      // 8. count field determines how many samples the pipeline will use
+      //
+      // 9. mask field specifies which samples should be active
+      //
+      // 10. alpha_to_coverage_enabled - anti-aliasing-related
+      //
+      // 11. multiview - how many array layers the render attachments can have
+      //       We won't be rendering to array textures so we can set this as None
+
+      render_pipeline
+   }
+
+   // One buffer stepped per vertex, one per instance
+   fn vertex_buffers() -> [wgpu::VertexBufferLayout<'static>; 2] {
+      [Vertex::desc(), InstanceRaw::desc()]
+   }
+
+   fn create_instance_buffer(device: &wgpu::Device, instances: &[Instance]) -> wgpu::Buffer {
+      let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+      device.create_buffer_init(
+         &wgpu::util::BufferInitDescriptor {
+            label: Some("Instance Buffer"),
+            contents: bytemuck::cast_slice(&instance_data),
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+         }
+      )
+   }
+
+   // The renderer's sampler cache, for loading textures of our own that
+   // share samplers with the renderer's
+   pub fn samplers_mut(&mut self) -> &mut texture::SamplerCache {
+      &mut self.samplers
+   }
+
+   pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+      self.camera.resize(width, height);
+      self.depth_texture = texture::Texture::create_depth_texture(
+         device, &mut self.samplers, width, height, self.depth_settings.format, "depth_texture"
+      );
+   }
+
+   // Pushes any changes to the camera, lights and instances to the gpu
+   pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+      self.camera_uniform.update_view_proj(&self.camera);
+      queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+      let lights_uniform = LightsUniform::new(self.ambient, &self.lights);
+      queue.write_buffer(&self.lights_buffer, 0, bytemuck::cast_slice(&[lights_uniform]));
+      let environment_uniform = EnvironmentUniform::new(&self.ibl, self.environment_intensity);
+      queue.write_buffer(&self.environment_buffer, 0, bytemuck::cast_slice(&[environment_uniform]));
+      self.shadow_map.update(queue, &self.lights, &self.camera);
+      if let Some(skybox) = &self.skybox {
+         skybox.update(queue, &self.camera);
+      }
+
+      // write_buffer can't grow a buffer, so if instances were added past
+      // what the buffer holds we make a new one (with some room to spare)
+      if self.instances.len() > self.instance_capacity {
+         self.instance_capacity = self.instances.len().next_power_of_two();
+         let mut padded = self.instances.clone();
+         padded.resize(self.instance_capacity, Instance::default());
+         self.instance_buffer = Self::create_instance_buffer(device, &padded);
+      } else if !self.instances.is_empty() {
+         let instance_data = self.instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+         queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+      }
+   }
+
+   // Records one frame into encoder, targeting view
+   pub fn render(
+      &self,
+      encoder: &mut wgpu::CommandEncoder,
+      view: &wgpu::TextureView,
+      clear_color: wgpu::Color,
+   ) {
+      // The shadow map has to be drawn before the pass that reads it. It
+      // does nothing when nothing casts shadows
+      let instances = 0..self.instances.len() as u32;
+      let model = self.model.as_ref().unwrap_or(&self.pentagon);
+      self.shadow_map.render(encoder, model, &self.instance_buffer, instances.clone());
+
+      // Now we can clear the screen - we need to use the encoder to create
+      // a RenderPass - this has all the methods for actual drawing
+      //
+      // begin_render_pass borrows encoder mutably and we can't call
+      //    encoder.finish() until we release that mutable borrow
+      //
+      // RenderPassColorAttachment fields -
+      //    view - tells wgpu what texture to save the colors to
+      //
+      //    resolve_target - texture that will receive resolved output
+      //
+      //    ops - takes wgpu::Operations object; tells wgpu what to do
+      //          with the colors on the texture
+      let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+         label:Some("Render Pass"),
+         color_attachments: &[
+            // This is what @location(0) in the fragment shader targets
+            Some(wgpu::RenderPassColorAttachment {
+               view,
+               resolve_target: None,
+               ops: wgpu::Operations {
+                  load: wgpu::LoadOp::Clear(clear_color),
+                  store:true,
+               }
+            }
+         )],
+         depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+            view: &self.depth_texture.view,
+            depth_ops: Some(wgpu::Operations {
+               load: wgpu::LoadOp::Clear(self.depth_settings.clear_value()),
+               store: true,
+            }),
+            // We don't use the stencil buffer yet, but if the depth format
+            // has one it still needs a defined value
+            stencil_ops: self.depth_settings.format.has_stencil_aspect().then_some(wgpu::Operations {
+               load: wgpu::LoadOp::Clear(0),
+               store: true,
+            }),
+         }),
+      });
+
+      // The skybox goes down first and everything else draws over it
+      if let Some(skybox) = self.skybox.as_ref().filter(|_| self.show_skybox) {
+         skybox.draw(&mut render_pass);
+      }
+
+      // After we set the pipeline to our built render pipeline, we can
+      //    tell wgpu too draw our indices once for every instance
+      //
+      // Note: You can have multiple vertex buffers set at once. You can only have one index buffer set at once.
+      render_pass.set_pipeline(&self.render_pipeline);
+      render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+      render_pass.set_bind_group(2, &self.lights_bind_group, &[]);
+      render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+      // draw_model_instanced sets the vertex/index buffers (with the right
+      // index format) and the material bind group for each mesh
+      render_pass.draw_model_instanced(model, instances);
+   }
+}
@@ -0,0 +1,82 @@
+use anyhow::*;
+
+use crate::preprocessor::Preprocessed;
+
+// The shaders (and the files they include) built into the binary, for
+// preprocessor::preprocess to load from
+pub fn builtin(name: &str) -> Result<String> {
+   let source = match name {
+      "shader.wgsl" => include_str!("shader.wgsl"),
+      "vertex.wgsl" => include_str!("vertex.wgsl"),
+      "lighting.wgsl" => include_str!("lighting.wgsl"),
+      "pbr.wgsl" => include_str!("pbr.wgsl"),
+      "shadow.wgsl" => include_str!("shadow.wgsl"),
+      "shadow_pass.wgsl" => include_str!("shadow_pass.wgsl"),
+      _ => bail!("no built in shader called {}", name),
+   };
+   Ok(source.to_string())
+}
+
+// Checks preprocessed WGSL the way wgpu would (returning what naga makes
+// of it, for reflect to look at), but returns the problem instead of
+// panicking, so a typo in a hot reloaded shader can't take the
+// app down. Errors say which file and line they're in before the
+// preprocessor got to it, e.g.
+//
+//    src/vertex.wgsl:12:5: expected ';', found '}'
+//
+// followed by the offending line with the span underlined
+pub fn validate_wgsl(shader: &Preprocessed) -> Result<(naga::Module, naga::valid::ModuleInfo)> {
+   let source = &shader.source;
+   let module = naga::front::wgsl::parse_str(source)
+      .map_err(|e| shader_error(shader, e.location(source), e.message().to_string()))?;
+
+   // wgpu checks again against what the device can actually do, this is
+   // just for the line and column
+   let mut validator = naga::valid::Validator::new(
+      naga::valid::ValidationFlags::all(),
+      naga::valid::Capabilities::all(),
+   );
+   let info = validator.validate(&module).map_err(|e| {
+      // The interesting part is usually at the bottom of the chain
+      let mut message = e.as_inner().to_string();
+      let mut cause = std::error::Error::source(e.as_inner());
+      while let Some(error) = cause {
+         message = format!("{}: {}", message, error);
+         cause = error.source();
+      }
+      shader_error(shader, e.location(source), message)
+   })?;
+
+   Ok((module, info))
+}
+
+fn shader_error(shader: &Preprocessed, location: Option<naga::SourceLocation>, message: String) -> Error {
+   let Some(location) = location else {
+      return anyhow!("{}: {}", shader.files[0], message);
+   };
+   let line = location.line_number;
+   let (file, file_line) = shader.map_line(line).unwrap_or((&shader.files[0], line));
+   let text = shader.source.lines().nth(line as usize - 1).unwrap_or("");
+
+   // Underline the span, which can't go past the end of the line
+   let column = location.line_position as usize;
+   let width = (location.length as usize).clamp(1, (text.len() + 1).saturating_sub(column).max(1));
+   anyhow!(
+      "{}:{}:{}: {}\n   | {}\n   | {}{}",
+      file, file_line, column, message, text, " ".repeat(column - 1), "^".repeat(width)
+   )
+}
+
+// Runs f with wgpu's validation errors caught rather than sent to the
+// uncaptured error handler (which panics by default). Some mistakes, like
+// a binding that doesn't match the pipeline layout, only show up when the
+// pipeline gets built, so naga alone can't catch them
+pub fn catch_validation_errors<T>(device: &wgpu::Device, f: impl FnOnce() -> T) -> Result<T> {
+   device.push_error_scope(wgpu::ErrorFilter::Validation);
+   let value = f();
+   match pollster::block_on(device.pop_error_scope()) {
+      Some(error) => Err(anyhow!("{}", error)),
+      None => Ok(value),
+   }
+}
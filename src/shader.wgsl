@@ -1,39 +1,111 @@
 // Vertex Shader
 
-struct VertexInput {
-   @location(0) position: vec3<f32>,
-   @location(1) tex_coords: vec2<f32>,
-}
+#include "vertex.wgsl"
+#include "pbr.wgsl"
 
-struct VertexOutput {
-   @builtin(position) clip_position: vec4<f32>,
-   @location(0) tex_coords: vec2<f32>,
-};
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
 
 // using @vertex we mark this function as a valid entry point for a
 // vertex shader. We expect a u32 called in_vertex_index, which gets its
 // value from @builtin(vertex_index)
 // 
 // we declare a variable called "out" using our VertexOutput struct
+//
+// multiplying by the camera's view_proj moves the vertex from world space
+// into clip space - matrix multiplication order matters, the vector goes
+// on the right
 @vertex
-fn vs_main( model: VertexInput ) -> VertexOutput {
+fn vs_main( model: VertexInput, instance: InstanceInput ) -> VertexOutput {
+   let model_matrix = mat4x4<f32>(
+      instance.model_matrix_0,
+      instance.model_matrix_1,
+      instance.model_matrix_2,
+      instance.model_matrix_3,
+   );
    var out: VertexOutput; 
    out.tex_coords = model.tex_coords;
-   out.clip_position = vec4<f32>(model.position, 1.0);
+   out.color = instance.color;
+   // Fine for rotations and uniform scales, which is all instances have
+   out.world_normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
+   let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+   out.world_position = world_position.xyz;
+   out.clip_position = camera.view_proj * world_position;
    return out;
 }
 
 
+// The material, laid out as model::Material builds its bind group. Every
+// texture is read with s_diffuse
 @group(0) @binding(0)
 var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
+@group(0) @binding(2)
+var t_metallic_roughness: texture_2d<f32>;
+@group(0) @binding(3)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(4)
+var t_occlusion: texture_2d<f32>;
+@group(0) @binding(5)
+var t_emissive: texture_2d<f32>;
+@group(0) @binding(6)
+var<uniform> material: Material;
 
 // Fragment Shader
-// this sets the color of the current fragment to brown
+// this lights the material with the lights in lighting.wgsl
 // @location(0) tells WGPU to store the vec4 return value in the first
 // color target
+//
+// 1. Every texture is sampled up front - textureSample (and the
+//       derivatives perturb_normal needs) have to happen in uniform
+//       control flow
+//
+// 2. Ambient light (and light from the environment, see
+//       image_based_lighting) comes from all around rather than from the
+//       lights, so the only thing that shades it is the occlusion texture
+//
+// Built with BLINN_PHONG defined, it uses the older Blinn-Phong lighting
+// (which ignores metallic and roughness) instead of Cook-Torrance. Built
+// with DEBUG_NORMALS defined, it shows the world space normals as colors,
+// and with DEBUG_CASCADES it tints everything by its shadow cascade (see
+// debug_cascades)
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-   return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+#ifdef DEBUG_NORMALS
+   return vec4<f32>(normalize(in.world_normal) * 0.5 + 0.5, 1.0);
+#else
+   let albedo = textureSample(t_diffuse, s_diffuse, in.tex_coords) * in.color * material.base_color; // 1.
+   let metallic_roughness = textureSample(t_metallic_roughness, s_diffuse, in.tex_coords);
+   let tangent_normal = textureSample(t_normal, s_diffuse, in.tex_coords).xyz * 2.0 - 1.0;
+   let occlusion = textureSample(t_occlusion, s_diffuse, in.tex_coords).r;
+   let emissive = textureSample(t_emissive, s_diffuse, in.tex_coords).rgb * material.emissive;
+
+   let normal = perturb_normal(
+      normalize(in.world_normal),
+      dpdx(in.world_position),
+      dpdy(in.world_position),
+      dpdx(in.tex_coords),
+      dpdy(in.tex_coords),
+      tangent_normal * vec3<f32>(material.normal_scale, material.normal_scale, 1.0),
+   );
+   let ao = 1.0 + material.occlusion_strength * (occlusion - 1.0);
+
+#ifdef BLINN_PHONG
+   let direct = blinn_phong(albedo.rgb, normal, in.world_position, camera.view_position.xyz);
+   let indirect = lights.ambient * albedo.rgb * ao; // 2.
+#else
+   let metallic = material.metallic * metallic_roughness.b;
+   let roughness = material.roughness * metallic_roughness.g;
+   let direct = cook_torrance(albedo.rgb, metallic, roughness, normal, in.world_position, camera.view_position.xyz);
+   let to_view = normalize(camera.view_position.xyz - in.world_position);
+   let environment = image_based_lighting(albedo.rgb, metallic, roughness, normal, to_view);
+   let indirect = (lights.ambient * albedo.rgb + environment) * ao; // 2.
+#endif
+   var color = indirect + direct + emissive;
+#ifdef DEBUG_CASCADES
+   color = debug_cascades(color, in.world_position);
+#endif
+   return vec4<f32>(color, albedo.a);
+#endif
 }
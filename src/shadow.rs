@@ -0,0 +1,473 @@
+use std::ops::Range;
+
+use cgmath::prelude::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+   camera,
+   light::{ self, Light },
+   model::{ self, DrawModel },
+   preprocessor::{ self, ShaderDefs },
+   shader,
+   texture,
+};
+
+// Matches MAX_CASCADES in shadow.wgsl
+pub const MAX_CASCADES: usize = 4;
+
+// Shadows from a directional light ("shadow mapping"). Before the main
+// pass, we draw the scene from the light's point of view into a depth
+// texture, the shadow map. The main pass then works out where each
+// fragment would be in that texture: if something nearer the light was
+// drawn there, the fragment is in its shadow (see shadow.wgsl)
+//
+// Only the first directional light in Renderer::lights casts shadows. A
+// directional light is infinitely far away, so the map is an orthographic
+// view of whatever the camera can see. Over a big scene that spreads the
+// texels thin, so we use cascaded shadow maps: the camera's view is cut
+// into slices by distance, and each slice (cascade) gets its own layer of
+// the shadow map. The near ones cover less ground, so the shadows close
+// to the camera - where they're seen biggest - get the most detail
+pub struct ShadowMap {
+   settings: Option<ShadowSettings>,
+   // One layer per cascade
+   pub texture: texture::Texture,
+   // Shadow in shadow.wgsl, written in update()
+   pub buffer: wgpu::Buffer,
+   pipeline: wgpu::RenderPipeline,
+   cascades: Vec<CascadePass>,
+   // Whether a light cast shadows as of the last update, i.e. whether
+   // there's anything to render
+   casting: bool,
+}
+
+// What the shadow pass needs to draw one cascade: the layer of the shadow
+// map it goes in, and a buffer with the light's view-projection for it
+struct CascadePass {
+   view: wgpu::TextureView,
+   buffer: wgpu::Buffer,
+   bind_group: wgpu::BindGroup,
+}
+
+// Where the cascades start and end, between the camera's znear and
+// ShadowSettings::max_distance
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CascadeSplit {
+   // Every cascade is the same depth
+   Uniform,
+   // Each cascade goes the same number of times further than the last,
+   // which matches how perspective shrinks things - but leaves the far
+   // cascades very long
+   Logarithmic,
+   // A blend of the two (the "practical split scheme"), from 0.0 (uniform)
+   // to 1.0 (logarithmic)
+   Practical(f32),
+}
+
+// How big the shadow map is, how much of the view it covers and how it's
+// sampled. The default is a single shadow map over a fixed box around the
+// camera's target, and cascaded() fits cascades to the camera's view
+// instead. Chain the builder methods to change them, e.g.
+//
+//    ShadowSettings::default()
+//       .resolution(4096)
+//       .bias(0.05, 0.02)
+//
+//    ShadowSettings::cascaded()
+//       .cascades(3, CascadeSplit::Logarithmic)
+//       .texel_bias(2.0, 1.0)
+#[derive(Clone, Debug)]
+pub struct ShadowSettings {
+   // Width and height of each cascade's layer in texels
+   pub resolution: u32,
+   // 1 to MAX_CASCADES. 1 is a plain shadow map over the whole view
+   pub cascade_count: u32,
+   pub split: CascadeSplit,
+   // How far from the camera shadows reach, in world units. Past this
+   // (or the camera's zfar, if that's closer) everything is lit. The
+   // smaller it is, the sharper the shadows inside it
+   pub max_distance: f32,
+   // Instead of fitting the shadow map to the camera's view, cover a box
+   // this far either side of the camera's target, in world units. There's
+   // just the one cascade then, and max_distance isn't used - anything in
+   // the box is shadowed, however far it is from the camera
+   pub radius: Option<f32>,
+   // World units to move each fragment towards the light before testing
+   // it, to keep surfaces from shadowing themselves. Too much and
+   // shadows come away from whatever casts them ("peter panning")
+   pub depth_bias: f32,
+   // The same, but out along the surface's normal - which helps most on
+   // surfaces the light only grazes
+   pub normal_bias: f32,
+   // More of each bias, in texels of the fragment's cascade rather than
+   // world units, so farther cascades (with bigger texels) get more. Added
+   // to depth_bias and normal_bias. A texel is 2 * radius / resolution
+   // world units with radius set
+   pub depth_bias_texels: f32,
+   pub normal_bias_texels: f32,
+   // How many texels either side of the fragment percentage-closer
+   // filtering looks at - 0 is a single (bilinear) test, 1 a 3x3 block,
+   // 2 a 5x5 block and so on
+   pub pcf_radius: u32,
+}
+
+impl Default for ShadowSettings {
+   fn default() -> Self {
+      Self {
+         resolution: 2048,
+         cascade_count: 1,
+         split: CascadeSplit::Practical(0.75),
+         max_distance: 50.0,
+         radius: Some(10.0),
+         depth_bias: 0.02,
+         normal_bias: 0.02,
+         depth_bias_texels: 0.0,
+         normal_bias_texels: 0.0,
+         pcf_radius: 1,
+      }
+   }
+}
+
+impl ShadowSettings {
+   // 4 cascades out to 50 units from the camera. The biases are all in
+   // texels, since a world unit bias big enough for the far cascades is
+   // far too much for the near ones
+   pub fn cascaded() -> Self {
+      Self {
+         cascade_count: 4,
+         radius: None,
+         depth_bias: 0.0,
+         normal_bias: 0.0,
+         depth_bias_texels: 1.5,
+         normal_bias_texels: 1.5,
+         ..Self::default()
+      }
+   }
+
+   pub fn resolution(mut self, resolution: u32) -> Self {
+      self.resolution = resolution;
+      self
+   }
+
+   // Turns the fixed box off, if there was one
+   pub fn cascades(mut self, cascade_count: u32, split: CascadeSplit) -> Self {
+      self.cascade_count = cascade_count;
+      self.split = split;
+      self.radius = None;
+      self
+   }
+
+   pub fn max_distance(mut self, max_distance: f32) -> Self {
+      self.max_distance = max_distance;
+      self
+   }
+
+   pub fn radius(mut self, radius: f32) -> Self {
+      self.radius = Some(radius);
+      self
+   }
+
+   pub fn bias(mut self, depth_bias: f32, normal_bias: f32) -> Self {
+      self.depth_bias = depth_bias;
+      self.normal_bias = normal_bias;
+      self
+   }
+
+   pub fn texel_bias(mut self, depth_bias: f32, normal_bias: f32) -> Self {
+      self.depth_bias_texels = depth_bias;
+      self.normal_bias_texels = normal_bias;
+      self
+   }
+
+   pub fn pcf_radius(mut self, pcf_radius: u32) -> Self {
+      self.pcf_radius = pcf_radius;
+      self
+   }
+
+   fn cascade_count(&self) -> usize {
+      match self.radius {
+         Some(_) => 1,
+         None => (self.cascade_count as usize).clamp(1, MAX_CASCADES),
+      }
+   }
+}
+
+// Matches Cascade in shadow.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct CascadeRaw {
+   view_proj: [[f32; 4]; 4],
+   far: f32,
+   texel_size: f32,
+   _padding: [f32; 2],
+}
+
+// Matches Shadow in shadow.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowUniform {
+   cascades: [CascadeRaw; MAX_CASCADES],
+   view_plane: [f32; 4],
+   light: u32,
+   cascade_count: u32,
+   pcf_radius: i32,
+   depth_bias: f32,
+   normal_bias: f32,
+   depth_bias_texels: f32,
+   normal_bias_texels: f32,
+   _padding: f32,
+}
+
+impl ShadowUniform {
+   // Matches no light at all, so everything is lit
+   fn none() -> Self {
+      Self {
+         light: u32::MAX,
+         ..bytemuck::Zeroable::zeroed()
+      }
+   }
+}
+
+impl ShadowMap {
+   // With settings None nothing casts shadows, and the shadow map is a
+   // single texel that's never drawn to. vertex_buffers are the ones the
+   // main pipeline draws the scene with
+   pub fn new(
+      device: &wgpu::Device,
+      samplers: &mut texture::SamplerCache,
+      settings: Option<ShadowSettings>,
+      vertex_buffers: &[wgpu::VertexBufferLayout],
+   ) -> Self {
+      let (resolution, cascade_count) = settings.as_ref()
+         .map_or((1, 1), |settings| (settings.resolution, settings.cascade_count()));
+      let texture = texture::Texture::create_shadow_map(
+         device, samplers, resolution, cascade_count as u32, "shadow_map"
+      );
+      let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+         label: Some("Shadow Buffer"),
+         contents: bytemuck::cast_slice(&[ShadowUniform::none()]),
+         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+      });
+
+      let shader_source = preprocessor::preprocess("shadow_pass.wgsl", &ShaderDefs::default(), shader::builtin).unwrap();
+      let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+         label: Some("Shadow Shader"),
+         source: wgpu::ShaderSource::Wgsl(shader_source.source.into()),
+      });
+      let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+         label: Some("Shadow Pipeline"),
+         layout: None,
+         vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: vertex_buffers,
+         },
+         // Depth only
+         fragment: None,
+         primitive: wgpu::PrimitiveState {
+            // Our meshes are often single sided (like the pentagon), and
+            // their back faces should still block the light
+            cull_mode: None,
+            ..Default::default()
+         },
+         depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+         }),
+         multisample: wgpu::MultisampleState::default(),
+         multiview: None,
+      });
+
+      let cascades = (0..cascade_count as u32).map(|layer| {
+         let view = texture.texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("shadow_cascade"),
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            base_array_layer: layer,
+            array_layer_count: Some(1),
+            ..Default::default()
+         });
+         let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Shadow Cascade Buffer"),
+            contents: bytemuck::cast_slice(&[[[0.0f32; 4]; 4]]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+         });
+         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("shadow_cascade_bind_group"),
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+               wgpu::BindGroupEntry {
+                  binding: 0,
+                  resource: buffer.as_entire_binding(),
+               },
+            ],
+         });
+         CascadePass { view, buffer, bind_group }
+      }).collect();
+
+      Self { settings, texture, buffer, pipeline, cascades, casting: false }
+   }
+
+   pub fn settings(&self) -> Option<&ShadowSettings> {
+      self.settings.as_ref()
+   }
+
+   // Points the shadow map at the first directional light (if there is
+   // one), with the cascades fitted around what the camera sees - or the
+   // box around its target, with ShadowSettings::radius
+   pub fn update(&mut self, queue: &wgpu::Queue, lights: &[Light], camera: &camera::Camera) {
+      let caster = lights.iter()
+         .take(light::MAX_LIGHTS)
+         .enumerate()
+         .find_map(|(i, light)| match *light {
+            Light::Directional { direction, .. } => Some((i, direction)),
+            _ => None,
+         });
+      let (Some(settings), Some((light, direction))) = (&self.settings, caster) else {
+         self.casting = false;
+         queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[ShadowUniform::none()]));
+         return;
+      };
+
+      // Cascades are picked by distance along the way the camera looks,
+      // which the shader works out as a dot product with this plane
+      let forward = (camera.target - camera.eye).normalize();
+      let mut uniform = ShadowUniform {
+         view_plane: forward.extend(-forward.dot(camera.eye.to_vec())).into(),
+         light: light as u32,
+         cascade_count: settings.cascade_count() as u32,
+         pcf_radius: settings.pcf_radius as i32,
+         depth_bias: settings.depth_bias,
+         normal_bias: settings.normal_bias,
+         depth_bias_texels: settings.depth_bias_texels,
+         normal_bias_texels: settings.normal_bias_texels,
+         ..ShadowUniform::none()
+      };
+
+      let cascades = match settings.radius {
+         // Reaches as far as the camera can see, the box decides what's
+         // shadowed
+         Some(radius) => vec![(light_view_proj(direction, camera.target, radius, 0.0, settings.resolution), f32::MAX)],
+         None => {
+            let far = settings.max_distance.min(camera.zfar);
+            let splits = cascade_splits(camera.znear, far, settings.cascade_count(), settings.split);
+            let nears = std::iter::once(camera.znear).chain(splits.iter().copied());
+            nears.zip(splits.iter().copied())
+               .map(|(near, far)| (fit_cascade(direction, &camera.frustum_corners(near, far), settings), far))
+               .collect()
+         },
+      };
+      for ((raw, pass), ((view_proj, texel_size), far)) in uniform.cascades.iter_mut().zip(&self.cascades).zip(cascades) {
+         *raw = CascadeRaw { view_proj: view_proj.into(), far, texel_size, _padding: [0.0; 2] };
+         queue.write_buffer(&pass.buffer, 0, bytemuck::cast_slice(&[raw.view_proj]));
+      }
+
+      self.casting = true;
+      queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[uniform]));
+   }
+
+   // Draws the depth of model's instances into every cascade of the
+   // shadow map. Has to be recorded before the pass that reads it
+   pub fn render(
+      &self,
+      encoder: &mut wgpu::CommandEncoder,
+      model: &model::Model,
+      instance_buffer: &wgpu::Buffer,
+      instances: Range<u32>,
+   ) {
+      if !self.casting {
+         return;
+      }
+      for cascade in &self.cascades {
+         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Shadow Pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+               view: &cascade.view,
+               depth_ops: Some(wgpu::Operations {
+                  load: wgpu::LoadOp::Clear(1.0),
+                  store: true,
+               }),
+               stencil_ops: None,
+            }),
+         });
+         render_pass.set_pipeline(&self.pipeline);
+         render_pass.set_bind_group(0, &cascade.bind_group, &[]);
+         render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+         render_pass.draw_model_geometry_instanced(model, instances.clone());
+      }
+   }
+}
+
+// The far end of each of count cascades, which together cover near to far
+fn cascade_splits(near: f32, far: f32, count: usize, split: CascadeSplit) -> Vec<f32> {
+   let lambda = match split {
+      CascadeSplit::Uniform => 0.0,
+      CascadeSplit::Logarithmic => 1.0,
+      CascadeSplit::Practical(lambda) => lambda.clamp(0.0, 1.0),
+   };
+   (1..=count).map(|i| {
+      let t = i as f32 / count as f32;
+      let uniform = near + (far - near) * t;
+      let logarithmic = near * (far / near).powf(t);
+      uniform + (logarithmic - uniform) * lambda
+   }).collect()
+}
+
+// The light's view-projection for a cascade covering corners (a slice of
+// the camera's view)
+//
+// 1. The box is fitted around a sphere around the corners rather than the
+//       corners themselves. It's a little bigger, but it's the same size
+//       however the camera turns, so the texels stay the same size too.
+//       Rounding the radius up stops float noise changing it a hair
+//       every frame
+//
+// 2. Things between the light and the slice can still cast shadows into
+//       it, so the near plane is pulled back towards the light - as far
+//       again as the shadows reach
+fn fit_cascade(
+   direction: cgmath::Vector3<f32>,
+   corners: &[cgmath::Point3<f32>; 8],
+   settings: &ShadowSettings,
+) -> (cgmath::Matrix4<f32>, f32) {
+   let centre = cgmath::Point3::centroid(corners); // 1.
+   let radius = corners.iter().map(|corner| corner.distance(centre)).fold(0.0, f32::max);
+   let radius = (radius * 16.0).ceil() / 16.0;
+   light_view_proj(direction, centre, radius, settings.max_distance, settings.resolution) // 2.
+}
+
+// An orthographic projection looking along direction, covering a box
+// radius either side of centre - and behind further towards the light.
+// Also returns how wide one texel of the shadow map is in world units, for
+// the shader to scale the biases by
+//
+// As the camera moves the box moves with it, which makes the edges of
+// shadows crawl as they land on different texels. Moving the box in whole
+// texels (in the light's view, where the texels are squares) stops that
+fn light_view_proj(
+   direction: cgmath::Vector3<f32>,
+   centre: cgmath::Point3<f32>,
+   radius: f32,
+   behind: f32,
+   resolution: u32,
+) -> (cgmath::Matrix4<f32>, f32) {
+   let direction = direction.normalize();
+   // Any up will do, as long as it isn't the direction itself
+   let up = if direction.y.abs() > 0.99 { cgmath::Vector3::unit_z() } else { cgmath::Vector3::unit_y() };
+   let view = cgmath::Matrix4::look_to_rh(cgmath::Point3::origin(), direction, up);
+
+   let texel_size = 2.0 * radius / resolution.max(1) as f32;
+   let centre = view.transform_point(centre);
+   let x = (centre.x / texel_size).floor() * texel_size;
+   let y = (centre.y / texel_size).floor() * texel_size;
+
+   // The view looks down -z, so the near and far planes are at -z
+   let near = -centre.z - radius - behind;
+   let far = -centre.z + radius;
+   let proj = cgmath::ortho(x - radius, x + radius, y - radius, y + radius, near, far);
+
+   (camera::OPENGL_TO_WGPU_MATRIX * proj * view, texel_size)
+}
@@ -0,0 +1,115 @@
+// Shadows from the one directional light that casts them, read from the
+// cascaded shadow map shadow.rs renders each frame - see shadow.rs for the
+// Rust side of these
+
+#define MAX_CASCADES 4u
+
+struct Cascade {
+   // Takes world space into this cascade's layer of the shadow map: x and
+   // y across it, z the depth
+   view_proj: mat4x4<f32>,
+   // How far along the camera's view the cascade reaches
+   far: f32,
+   // How wide one of its texels is in world units
+   texel_size: f32,
+};
+
+struct Shadow {
+   cascades: array<Cascade, MAX_CASCADES>,
+   // Dot with a world position (plus w) for its distance along the camera's
+   // view
+   view_plane: vec4<f32>,
+   // Index into lights.lights of the light casting shadows. Anything past
+   // MAX_LIGHTS (when no light casts shadows) matches none of them
+   light: u32,
+   cascade_count: u32,
+   // Samples this many texels either side of the middle one, in x and y
+   pcf_radius: i32,
+   // World units to move the fragment towards the light, and out along
+   // its normal, before looking it up
+   depth_bias: f32,
+   normal_bias: f32,
+   // More of the same, in texels of the fragment's cascade
+   depth_bias_texels: f32,
+   normal_bias_texels: f32,
+};
+@group(2) @binding(6)
+var<uniform> shadow: Shadow;
+@group(2) @binding(7)
+var t_shadow: texture_depth_2d_array;
+@group(2) @binding(8)
+var s_shadow: sampler_comparison;
+
+// The first cascade that reaches world_position, or cascade_count if it's
+// further away than all of them
+fn shadow_cascade(world_position: vec3<f32>) -> u32 {
+   let distance = dot(shadow.view_plane.xyz, world_position) + shadow.view_plane.w;
+   for (var i = 0u; i < min(shadow.cascade_count, MAX_CASCADES); i += 1u) {
+      if distance <= shadow.cascades[i].far {
+         return i;
+      }
+   }
+   return shadow.cascade_count;
+}
+
+// How much of light light_index reaches world_position past whatever is in
+// the way, from 0 (in shadow) to 1 (lit)
+//
+// 1. Without the biases, a surface would partly shadow itself wherever the
+//       texels of the shadow map are bigger than its pixels ("shadow
+//       acne")
+//
+// 2. Percentage-closer filtering: rather than a single lit/not lit test,
+//       average the tests of a block of texels around the fragment, so
+//       shadow edges fade out instead of showing the texels as stairs
+//
+// 3. The cascades only cover so much of the world. Outside them we can't
+//       know, so we call it lit
+//
+// Uses textureSampleCompareLevel, so it works outside of uniform control
+// flow, like the light loops this is called from
+fn shadow_factor(light_index: u32, world_position: vec3<f32>, normal: vec3<f32>, to_light: vec3<f32>) -> f32 {
+   let cascade_index = shadow_cascade(world_position);
+   if light_index != shadow.light || cascade_index >= shadow.cascade_count {
+      return 1.0;
+   }
+   let cascade = shadow.cascades[cascade_index];
+
+   let depth_bias = shadow.depth_bias + shadow.depth_bias_texels * cascade.texel_size; // 1.
+   let normal_bias = shadow.normal_bias + shadow.normal_bias_texels * cascade.texel_size;
+   let clip = cascade.view_proj * vec4<f32>(world_position + to_light * depth_bias + normal * normal_bias, 1.0);
+   let ndc = clip.xyz / clip.w;
+   // Clip space y goes up, texture coordinates go down
+   let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+
+   let texel = 1.0 / vec2<f32>(textureDimensions(t_shadow));
+   var lit = 0.0;
+   for (var y = -shadow.pcf_radius; y <= shadow.pcf_radius; y += 1) { // 2.
+      for (var x = -shadow.pcf_radius; x <= shadow.pcf_radius; x += 1) {
+         let offset = vec2<f32>(f32(x), f32(y)) * texel;
+         lit += textureSampleCompareLevel(t_shadow, s_shadow, uv + offset, i32(cascade_index), ndc.z);
+      }
+   }
+   let width = f32(shadow.pcf_radius * 2 + 1);
+
+   let outside = any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || ndc.z > 1.0; // 3.
+   return select(lit / (width * width), 1.0, outside);
+}
+
+// Tints color by the cascade world_position falls in - red, green, blue
+// then yellow, getting further away - to see where the cascades split.
+// Past the last cascade (or without shadows) color is left alone
+fn debug_cascades(color: vec3<f32>, world_position: vec3<f32>) -> vec3<f32> {
+   let cascade_index = shadow_cascade(world_position);
+   if cascade_index >= shadow.cascade_count {
+      return color;
+   }
+   var tint: vec3<f32>;
+   switch cascade_index {
+      case 0u: { tint = vec3<f32>(1.0, 0.0, 0.0); }
+      case 1u: { tint = vec3<f32>(0.0, 1.0, 0.0); }
+      case 2u: { tint = vec3<f32>(0.0, 0.0, 1.0); }
+      default: { tint = vec3<f32>(1.0, 1.0, 0.0); }
+   }
+   return mix(color, tint, 0.4);
+}
@@ -0,0 +1,23 @@
+// Draws the scene's depth as seen from the light that casts shadows, into
+// one cascade of the shadow map - see shadow.rs. There's no fragment
+// shader, the depth is all we want
+
+#include "vertex.wgsl"
+
+// The view_proj of the Cascade (in shadow.wgsl) being drawn
+struct ShadowPass {
+   light_view_proj: mat4x4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> shadow: ShadowPass;
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> @builtin(position) vec4<f32> {
+   let model_matrix = mat4x4<f32>(
+      instance.model_matrix_0,
+      instance.model_matrix_1,
+      instance.model_matrix_2,
+      instance.model_matrix_3,
+   );
+   return shadow.light_view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+}
@@ -0,0 +1,180 @@
+use cgmath::prelude::*;
+use wgpu::util::DeviceExt;
+
+use crate::{ camera, texture };
+
+// Only the inverse is needed - the shader goes from screen back to world
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkyboxUniform {
+   inv_view_proj: [[f32; 4]; 4],
+}
+
+// Draws a cubemap around the camera. It's drawn first, with depth testing
+// and writing turned off, so everything else simply draws over it - that
+// way it doesn't matter which depth compare function the scene uses
+pub struct Skybox {
+   pipeline: wgpu::RenderPipeline,
+   // Only held on to so it lives as long as the bind group using it
+   _cubemap: texture::Texture,
+   texture_bind_group: wgpu::BindGroup,
+   uniform_buffer: wgpu::Buffer,
+   uniform_bind_group: wgpu::BindGroup,
+}
+
+impl Skybox {
+   // format and depth_format have to match the render pass the skybox is
+   // drawn in
+   pub fn new(
+      device: &wgpu::Device,
+      cubemap: texture::Texture,
+      format: wgpu::TextureFormat,
+      depth_format: wgpu::TextureFormat,
+   ) -> Self {
+      let texture_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+         label: Some("skybox_texture_bind_group_layout"),
+         entries: &[
+            wgpu::BindGroupLayoutEntry {
+               binding: 0,
+               visibility: wgpu::ShaderStages::FRAGMENT,
+               ty: wgpu::BindingType::Texture {
+                  sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                  view_dimension: wgpu::TextureViewDimension::Cube,
+                  multisampled: false,
+               },
+               count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+               binding: 1,
+               visibility: wgpu::ShaderStages::FRAGMENT,
+               ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+               count: None,
+            },
+         ],
+      });
+      let texture_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+         label: Some("skybox_texture_bind_group"),
+         layout: &texture_bind_group_layout,
+         entries: &[
+            wgpu::BindGroupEntry {
+               binding: 0,
+               resource: wgpu::BindingResource::TextureView(&cubemap.view),
+            },
+            wgpu::BindGroupEntry {
+               binding: 1,
+               resource: wgpu::BindingResource::Sampler(&cubemap.sampler),
+            },
+         ],
+      });
+
+      let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+         label: Some("Skybox Buffer"),
+         contents: bytemuck::cast_slice(&[SkyboxUniform {
+            inv_view_proj: cgmath::Matrix4::identity().into(),
+         }]),
+         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+      });
+      let uniform_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+         label: Some("skybox_bind_group_layout"),
+         entries: &[
+            wgpu::BindGroupLayoutEntry {
+               binding: 0,
+               visibility: wgpu::ShaderStages::FRAGMENT,
+               ty: wgpu::BindingType::Buffer {
+                  ty: wgpu::BufferBindingType::Uniform,
+                  has_dynamic_offset: false,
+                  min_binding_size: None,
+               },
+               count: None,
+            },
+         ],
+      });
+      let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+         label: Some("skybox_bind_group"),
+         layout: &uniform_bind_group_layout,
+         entries: &[
+            wgpu::BindGroupEntry {
+               binding: 0,
+               resource: uniform_buffer.as_entire_binding(),
+            },
+         ],
+      });
+
+      let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+         label: Some("Skybox Shader"),
+         source: wgpu::ShaderSource::Wgsl(include_str!("skybox.wgsl").into()),
+      });
+      let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+         label: Some("Skybox Pipeline Layout"),
+         bind_group_layouts: &[&texture_bind_group_layout, &uniform_bind_group_layout],
+         push_constant_ranges: &[],
+      });
+      let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+         label: Some("Skybox Pipeline"),
+         layout: Some(&pipeline_layout),
+         vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+         },
+         fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+               format,
+               blend: Some(wgpu::BlendState::REPLACE),
+               write_mask: wgpu::ColorWrites::ALL,
+            })],
+         }),
+         primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            ..Default::default()
+         },
+         // The pass has a depth attachment, so we have to say how we use
+         // it - which is not at all
+         depth_stencil: Some(wgpu::DepthStencilState {
+            format: depth_format,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Always,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+         }),
+         multisample: wgpu::MultisampleState::default(),
+         multiview: None,
+      });
+
+      Self {
+         pipeline,
+         _cubemap: cubemap,
+         texture_bind_group,
+         uniform_buffer,
+         uniform_bind_group,
+      }
+   }
+
+   // The skybox is infinitely far away, so moving the camera mustn't move
+   // it - only the rotation part of the view matrix is kept
+   pub fn update(&self, queue: &wgpu::Queue, camera: &camera::Camera) {
+      let mut view = camera.build_view_matrix();
+      view.w = cgmath::Vector4::unit_w();
+      let view_proj = camera.build_projection_matrix() * view;
+      let inv_view_proj = view_proj.invert().unwrap_or_else(cgmath::Matrix4::identity);
+      queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[SkyboxUniform {
+         inv_view_proj: inv_view_proj.into(),
+      }]));
+   }
+
+   pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+      render_pass.set_pipeline(&self.pipeline);
+      render_pass.set_bind_group(0, &self.texture_bind_group, &[]);
+      render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+      render_pass.draw(0..3, 0..1);
+   }
+}
+
+// Cubemaps for skyboxes are magnified a lot, so filter them smoothly.
+// Clamping keeps the edges between faces from bleeding into each other
+pub fn texture_options() -> texture::TextureOptions {
+   texture::TextureOptions::default()
+      .sampler(texture::SamplerOptions::default().filter(wgpu::FilterMode::Linear))
+}
@@ -0,0 +1,40 @@
+// Draws the cubemap behind everything else. There's no geometry - a full
+// screen triangle works out which way each pixel looks by running its
+// position back through the camera's (rotation only) view projection
+
+struct SkyboxUniform {
+   inv_view_proj: mat4x4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> skybox: SkyboxUniform;
+
+struct VertexOutput {
+   @builtin(position) clip_position: vec4<f32>,
+   @location(0) ndc: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> VertexOutput {
+   var out: VertexOutput;
+   let x = f32(i32(in_vertex_index & 1u) * 4 - 1);
+   let y = f32(1 - i32(in_vertex_index & 2u) * 2);
+   out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+   out.ndc = vec2<f32>(x, y);
+   return out;
+}
+
+@group(0) @binding(0)
+var t_skybox: texture_cube<f32>;
+@group(0) @binding(1)
+var s_skybox: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+   // The points on the near (z = 0) and far (z = 1) planes under this
+   // pixel - the view direction runs from one to the other. Doing it this
+   // way works for orthographic cameras too, they just see one color
+   let near = skybox.inv_view_proj * vec4<f32>(in.ndc, 0.0, 1.0);
+   let far = skybox.inv_view_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+   let dir = far.xyz / far.w - near.xyz / near.w;
+   return textureSample(t_skybox, s_skybox, dir);
+}
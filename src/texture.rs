@@ -1,85 +1,774 @@
+use std::{ collections::HashMap, sync::Arc };
+
 use image::GenericImageView;
 use anyhow::*;
 
+use crate::{ compressed, cubemap, decompress, mipmap::{ self, MipmapPipeline } };
+
+// How (and whether) to fill in the smaller mip levels of a texture.
+//
+// Gpu renders each level from the one above it. If the texture's format
+// can't be rendered to or filtered on this device, it quietly falls back
+// to Cpu, which resizes the image with the image crate before uploading
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Mipmaps {
+   // Just the full size image, like before
+   #[default]
+   None,
+   Gpu,
+   Cpu,
+}
+
+// What the values in an 8 bit image mean.
+//
+// Srgb is for colors - albedo/diffuse maps, anything painted or
+// photographed. The gpu decodes them to linear when sampling.
+//
+// Linear is for data - normal maps, roughness/metallic maps, height maps.
+// Those have to come back exactly as they were stored, so they mustn't be
+// run through the sRGB curve
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+   #[default]
+   Srgb,
+   Linear,
+}
+
+impl ColorSpace {
+   // The 8 bit RGBA format images in this color space are loaded into
+   pub fn format(self) -> wgpu::TextureFormat {
+      match self {
+         ColorSpace::Srgb => wgpu::TextureFormat::Rgba8UnormSrgb,
+         ColorSpace::Linear => wgpu::TextureFormat::Rgba8Unorm,
+      }
+   }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TextureOptions {
+   pub mipmaps: Mipmaps,
+   pub sampler: SamplerOptions,
+   // Ignored for hdr textures, which are always linear
+   pub color_space: ColorSpace,
+}
+
+impl TextureOptions {
+   pub fn mipmaps(mut self, mipmaps: Mipmaps) -> Self {
+      self.mipmaps = mipmaps;
+      self
+   }
+
+   pub fn color_space(mut self, color_space: ColorSpace) -> Self {
+      self.color_space = color_space;
+      self
+   }
+
+   pub fn sampler(mut self, sampler: SamplerOptions) -> Self {
+      self.sampler = sampler;
+      self
+   }
+}
+
+// Everything that goes into a wgpu::SamplerDescriptor, minus the label.
+// The defaults are what textures have always used: clamped edges, linear
+// magnification and nearest minification. Chain the builder methods to
+// change them, e.g.
+//
+//    SamplerOptions::default()
+//       .address_mode(wgpu::AddressMode::Repeat)
+//       .filter(wgpu::FilterMode::Linear)
+//       .anisotropy(16)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SamplerOptions {
+   pub address_mode_u: wgpu::AddressMode,
+   pub address_mode_v: wgpu::AddressMode,
+   pub address_mode_w: wgpu::AddressMode,
+   pub mag_filter: wgpu::FilterMode,
+   pub min_filter: wgpu::FilterMode,
+   pub mipmap_filter: wgpu::FilterMode,
+   pub lod_min_clamp: f32,
+   pub lod_max_clamp: f32,
+   // 1 turns anisotropic filtering off. Anything higher needs all three
+   // filters to be Linear, and is ignored on adapters that don't support it
+   pub anisotropy_clamp: u16,
+   // Only for comparison samplers, e.g. sampling a depth texture for shadows
+   pub compare: Option<wgpu::CompareFunction>,
+}
+
+impl Default for SamplerOptions {
+   fn default() -> Self {
+      Self {
+         address_mode_u: wgpu::AddressMode::ClampToEdge,
+         address_mode_v: wgpu::AddressMode::ClampToEdge,
+         address_mode_w: wgpu::AddressMode::ClampToEdge,
+         mag_filter: wgpu::FilterMode::Linear,
+         min_filter: wgpu::FilterMode::Nearest,
+         mipmap_filter: wgpu::FilterMode::Nearest,
+         lod_min_clamp: 0.0,
+         lod_max_clamp: 32.0,
+         anisotropy_clamp: 1,
+         compare: None,
+      }
+   }
+}
+
+impl SamplerOptions {
+   // Same address mode in every direction
+   pub fn address_mode(self, mode: wgpu::AddressMode) -> Self {
+      self.address_modes(mode, mode, mode)
+   }
+
+   pub fn address_modes(mut self, u: wgpu::AddressMode, v: wgpu::AddressMode, w: wgpu::AddressMode) -> Self {
+      self.address_mode_u = u;
+      self.address_mode_v = v;
+      self.address_mode_w = w;
+      self
+   }
+
+   // Same filter for magnification, minification and between mip levels -
+   // Linear everywhere is trilinear filtering
+   pub fn filter(self, filter: wgpu::FilterMode) -> Self {
+      self.filters(filter, filter, filter)
+   }
+
+   pub fn filters(mut self, mag: wgpu::FilterMode, min: wgpu::FilterMode, mipmap: wgpu::FilterMode) -> Self {
+      self.mag_filter = mag;
+      self.min_filter = min;
+      self.mipmap_filter = mipmap;
+      self
+   }
+
+   pub fn lod_clamp(mut self, min: f32, max: f32) -> Self {
+      self.lod_min_clamp = min;
+      self.lod_max_clamp = max;
+      self
+   }
+
+   pub fn anisotropy(mut self, clamp: u16) -> Self {
+      self.anisotropy_clamp = clamp;
+      self
+   }
+
+   pub fn compare(mut self, compare: wgpu::CompareFunction) -> Self {
+      self.compare = Some(compare);
+      self
+   }
+
+   pub fn descriptor<'a>(&self, label: Option<&'a str>) -> wgpu::SamplerDescriptor<'a> {
+      let all_linear = [self.mag_filter, self.min_filter, self.mipmap_filter]
+         .iter()
+         .all(|filter| *filter == wgpu::FilterMode::Linear);
+      // wgpu rejects anisotropy without linear filtering, rather than
+      // fail we just turn it off
+      let anisotropy_clamp = if all_linear {
+         self.anisotropy_clamp.clamp(1, 16)
+      } else {
+         if self.anisotropy_clamp > 1 {
+            log::warn!("Anisotropic filtering needs linear filters, ignoring anisotropy_clamp {}", self.anisotropy_clamp);
+         }
+         1
+      };
+
+      wgpu::SamplerDescriptor {
+         label,
+         address_mode_u: self.address_mode_u,
+         address_mode_v: self.address_mode_v,
+         address_mode_w: self.address_mode_w,
+         mag_filter: self.mag_filter,
+         min_filter: self.min_filter,
+         mipmap_filter: self.mipmap_filter,
+         lod_min_clamp: self.lod_min_clamp.max(0.0),
+         lod_max_clamp: self.lod_max_clamp.max(self.lod_min_clamp.max(0.0)),
+         compare: self.compare,
+         anisotropy_clamp,
+         border_color: None,
+      }
+   }
+
+   // f32 isn't Hash, so the cache compares the lod clamps by their bits
+   fn key(&self) -> SamplerKey {
+      SamplerKey {
+         address_modes: [self.address_mode_u, self.address_mode_v, self.address_mode_w],
+         filters: [self.mag_filter, self.min_filter, self.mipmap_filter],
+         lod_clamp: [self.lod_min_clamp.to_bits(), self.lod_max_clamp.to_bits()],
+         anisotropy_clamp: self.anisotropy_clamp,
+         compare: self.compare,
+      }
+   }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct SamplerKey {
+   address_modes: [wgpu::AddressMode; 3],
+   filters: [wgpu::FilterMode; 3],
+   lod_clamp: [u32; 2],
+   anisotropy_clamp: u16,
+   compare: Option<wgpu::CompareFunction>,
+}
+
+// Most textures in a scene are sampled exactly the same way, and there's
+// no reason for each of them to have its own wgpu::Sampler. The cache
+// hands out one shared sampler per distinct set of SamplerOptions.
+//
+// Samplers belong to the device that made them, so keep one cache per
+// device. It also keeps the pipelines mipmap::generate_gpu draws with, one
+// per format, since every texture that gets mipmapped passes through here
+#[derive(Default)]
+pub struct SamplerCache {
+   samplers: HashMap<SamplerKey, Arc<wgpu::Sampler>>,
+   mipmap_pipelines: HashMap<wgpu::TextureFormat, MipmapPipeline>,
+}
+
+impl SamplerCache {
+   pub fn new() -> Self {
+      Self::default()
+   }
+
+   pub fn get(&mut self, device: &wgpu::Device, options: &SamplerOptions) -> Arc<wgpu::Sampler> {
+      self.samplers.entry(options.key())
+         .or_insert_with(|| Arc::new(device.create_sampler(&options.descriptor(Some("Cached Sampler")))))
+         .clone()
+   }
+
+   pub(crate) fn mipmap_pipeline(&mut self, device: &wgpu::Device, format: wgpu::TextureFormat) -> &MipmapPipeline {
+      self.mipmap_pipelines.entry(format)
+         .or_insert_with(|| MipmapPipeline::new(device, format))
+   }
+
+   // How many different samplers have been created so far
+   pub fn len(&self) -> usize {
+      self.samplers.len()
+   }
+
+   pub fn is_empty(&self) -> bool {
+      self.samplers.is_empty()
+   }
+}
+
 pub struct Texture {
    pub texture: wgpu::Texture,
    pub view: wgpu::TextureView,
-   pub sampler: wgpu::Sampler,
+   // Shared with every other texture that was created with the same
+   // SamplerOptions through the same SamplerCache
+   pub sampler: Arc<wgpu::Sampler>,
 }
 
 impl Texture {
+   pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+   // The depth texture needs to be the same size as the color target we
+   // render into, so it has to be recreated whenever that gets resized.
+   //
+   // RENDER_ATTACHMENT so we can render to it, TEXTURE_BINDING so shaders
+   // can read it back (e.g. to visualize depth). The sampler's compare
+   // function is only used if we sample it with a comparison sampler
+   pub fn create_depth_texture(
+      device: &wgpu::Device,
+      samplers: &mut SamplerCache,
+      width: u32,
+      height: u32,
+      format: wgpu::TextureFormat,
+      label: &str
+   ) -> Self {
+      let size = wgpu::Extent3d {
+         width: width.max(1),
+         height: height.max(1),
+         depth_or_array_layers: 1,
+      };
+
+      let texture = device.create_texture(
+         &wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[]
+         }
+      );
+
+      let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+      let sampler = samplers.get(device, &SamplerOptions::default()
+         .filters(wgpu::FilterMode::Linear, wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest)
+         .compare(wgpu::CompareFunction::LessEqual));
+
+      Self { texture, view, sampler }
+   }
+
+   // An array of square depth textures for a light to render the scene
+   // into, one layer per shadow cascade (see shadow.rs), read back with a
+   // comparison sampler. Comparing gives 1.0 where the fragment is no
+   // further from the light than what's stored, i.e. lit. Linear filtering
+   // on a comparison sampler blends the results of the 4 nearest texels
+   // rather than their depths, which softens shadow edges for free
+   //
+   // The view covers every layer. There are always at least 2, since the
+   // gl backend can't view a texture with just one as an array
+   pub fn create_shadow_map(
+      device: &wgpu::Device,
+      samplers: &mut SamplerCache,
+      size: u32,
+      layers: u32,
+      label: &str,
+   ) -> Self {
+      let texture = device.create_texture(
+         &wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d { width: size.max(1), height: size.max(1), depth_or_array_layers: layers.max(2) },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[]
+         }
+      );
+
+      let view = texture.create_view(&wgpu::TextureViewDescriptor {
+         dimension: Some(wgpu::TextureViewDimension::D2Array),
+         ..Default::default()
+      });
+      let sampler = samplers.get(device, &SamplerOptions::default()
+         .filters(wgpu::FilterMode::Linear, wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest)
+         .compare(wgpu::CompareFunction::LessEqual));
+
+      Self { texture, view, sampler }
+   }
+
    pub fn from_bytes(
       device: &wgpu::Device,
       queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
       bytes: &[u8],
       label: &str
    ) -> Result<Self> {
+      Self::from_bytes_with_options(device, queue, samplers, bytes, label, TextureOptions::default())
+   }
+
+   pub fn from_bytes_with_options(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
+      bytes: &[u8],
+      label: &str,
+      options: TextureOptions,
+   ) -> Result<Self> {
+      if compressed::is_container(bytes) {
+         return Self::from_compressed_bytes(device, queue, samplers, bytes, label, options);
+      }
 
       let img = image::load_from_memory(bytes)?;
-      Self::from_image(device, queue, &img, Some(label))
+      Self::from_image_with_options(device, queue, samplers, &img, Some(label), options)
+   }
+
+   // Loads a KTX2 or DDS file. Those are usually block compressed (BCn,
+   // ETC2 or ASTC), which the gpu samples as is - so they take a fraction
+   // of the memory and there's nothing to decode. They come with their mip
+   // chain already built, so options.mipmaps is ignored.
+   //
+   // Which compressed formats we can sample depends on the device's
+   // features (see OPTIONAL_FEATURES). When it can't sample this one, BC1-5
+   // and ETC2/EAC get decompressed on the cpu into RGBA8 instead, and
+   // anything else is an error.
+   //
+   // Like other images, options.color_space picks between the sRGB and
+   // linear versions of the file's format
+   pub fn from_compressed_bytes(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
+      bytes: &[u8],
+      label: &str,
+      options: TextureOptions,
+   ) -> Result<Self> {
+      let image = compressed::parse(bytes).with_context(|| format!("Couldn't load {}", label))?;
+      let format = match options.color_space {
+         ColorSpace::Srgb => image.format.add_srgb_suffix(),
+         ColorSpace::Linear => image.format.remove_srgb_suffix(),
+      };
+      let dimensions = (image.width, image.height);
+
+      // wgpu also wants compressed textures to be a whole number of blocks
+      let (block_width, block_height) = format.block_dimensions();
+      if device.features().contains(format.required_features())
+         && image.width % block_width == 0
+         && image.height % block_height == 0
+      {
+         return Ok(Self::from_mip_levels(device, queue, samplers, Some(label), format, dimensions, options, &image.levels));
+      }
+
+      ensure!(
+         decompress::can_decompress(format),
+         "Couldn't load {}: the device can't use {:?} textures and they can't be decompressed on the cpu",
+         label, format
+      );
+      log::info!("Can't use {:?} textures, decompressing {} on the cpu", format, label);
+      let size = wgpu::Extent3d { width: image.width, height: image.height, depth_or_array_layers: 1 };
+      let levels = image.levels.iter().enumerate().map(|(mip_level, level)| {
+         let size = size.mip_level_size(mip_level as u32, wgpu::TextureDimension::D2);
+         Ok(decompress::decompress(format, size.width, size.height, level)?.into_raw())
+      }).collect::<Result<Vec<_>>>()?;
+
+      let format = if format.is_srgb() { wgpu::TextureFormat::Rgba8UnormSrgb } else { wgpu::TextureFormat::Rgba8Unorm };
+      Ok(Self::from_mip_levels(device, queue, samplers, Some(label), format, dimensions, options, &levels))
+   }
+
+
+   // A 1x1 texture of a single color - handy as a stand in when a material
+   // doesn't have a texture of its own
+   pub fn from_color(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
+      color: [u8; 4],
+      label: &str
+   ) -> Self {
+      let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+      // from_image only fails while decoding, which we skip here
+      Self::from_image_with_options(device, queue, samplers, &img, Some(label), TextureOptions::default()).unwrap()
    }
 
 
    pub fn from_image(
       device: &wgpu::Device,
       queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
       img: &image::DynamicImage,
       label: Option<&str>
    ) -> Result<Self> {
+      Self::from_image_with_options(device, queue, samplers, img, label, TextureOptions::default())
+   }
+
+   pub fn from_image_with_options(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
+      img: &image::DynamicImage,
+      label: Option<&str>,
+      options: TextureOptions,
+   ) -> Result<Self> {
 
       let rgba = img.to_rgba8();
-      let dimensions = img.dimensions();
+      let format = options.color_space.format();
 
-      let size = wgpu::Extent3d {
-         width: dimensions.0,
-         height: dimensions.1,
-         depth_or_array_layers: 1,
-      };
+      Ok(Self::from_levels(device, queue, samplers, label, format, img.dimensions(), options, &rgba, |count| {
+         mipmap::generate_cpu(&rgba, count).into_iter().map(|level| level.into_raw()).collect()
+      }))
+   }
 
-      let texture = device.create_texture(
-         &wgpu::TextureDescriptor {
-            label,
-            size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-            view_formats: &[]
+   // Loads a Radiance .hdr or OpenEXR file (anything else image can decode
+   // works too) into a floating point texture, so values above 1.0 survive
+   // for lighting. format has to be Rgba16Float or Rgba32Float - hdr_format
+   // picks the best one the adapter can filter
+   pub fn from_hdr_bytes(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
+      bytes: &[u8],
+      label: &str,
+      format: wgpu::TextureFormat,
+      options: TextureOptions,
+   ) -> Result<Self> {
+      let img = decode_hdr(bytes).with_context(|| format!("Couldn't decode {}", label))?;
+      Self::from_hdr_image(device, queue, samplers, &img, Some(label), format, options)
+   }
+
+   pub fn from_hdr_image(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
+      img: &image::Rgba32FImage,
+      label: Option<&str>,
+      format: wgpu::TextureFormat,
+      options: TextureOptions,
+   ) -> Result<Self> {
+      // Rgba16Float has to be converted from the f32s we decoded into
+      let to_bytes = |img: &image::Rgba32FImage| -> Vec<u8> {
+         match format {
+            wgpu::TextureFormat::Rgba32Float => bytemuck::cast_slice(img.as_raw()).to_vec(),
+            _ => {
+               let halves = img.as_raw().iter().map(|&v| half::f16::from_f32(v)).collect::<Vec<_>>();
+               bytemuck::cast_slice(&halves).to_vec()
+            },
          }
+      };
+      ensure!(
+         matches!(format, wgpu::TextureFormat::Rgba16Float | wgpu::TextureFormat::Rgba32Float),
+         "hdr textures must be Rgba16Float or Rgba32Float, not {:?}", format
       );
 
-      queue.write_texture(
-         wgpu::ImageCopyTexture {
-            aspect: wgpu::TextureAspect::All,
-            texture: &texture,
-            mip_level: 0,
-            origin: wgpu::Origin3d::ZERO,
-         },
-         &rgba,
-         wgpu::ImageDataLayout {
-            offset: 0,
-            bytes_per_row: Some(4 * dimensions.0),
-            rows_per_image: Some(dimensions.1)
-         },
-         size,
+      Ok(Self::from_levels(device, queue, samplers, label, format, img.dimensions(), options, &to_bytes(img), |count| {
+         mipmap::generate_cpu_hdr(img, count).iter().map(to_bytes).collect()
+      }))
+   }
+
+   // A cubemap from six square images of the same size, in the order
+   // +x, -x, +y, -y, +z, -z. view is a cube view, so bind it as
+   // texture_cube in shaders. Mipmaps are always made on the cpu here, the
+   // gpu path only handles plain 2d textures
+   pub fn from_cube_faces(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
+      faces: &[image::DynamicImage],
+      label: Option<&str>,
+      options: TextureOptions,
+   ) -> Result<Self> {
+      ensure!(faces.len() == cubemap::FACE_COUNT as usize, "a cubemap needs 6 faces, got {}", faces.len());
+      let (face_size, height) = faces[0].dimensions();
+      ensure!(face_size == height, "cubemap faces must be square, got {}x{}", face_size, height);
+      ensure!(
+         faces.iter().all(|face| face.dimensions() == (face_size, face_size)),
+         "cubemap faces must all be the same size"
       );
 
-      let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-      let sampler = device.create_sampler(
-         &wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
+      let format = options.color_space.format();
+      let mip_level_count = match options.mipmaps {
+         Mipmaps::None => 1,
+         Mipmaps::Gpu | Mipmaps::Cpu => mipmap::mip_level_count(face_size, face_size),
+      };
+      // COPY_SRC lets ibl read the cubemap back, to key its cache on
+      let texture = cubemap::create_cube_texture(
+         device, label, face_size, format, mip_level_count,
+         wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
+      );
+
+      for (face, img) in faces.iter().enumerate() {
+         let rgba = img.to_rgba8();
+         let mips = mipmap::generate_cpu(&rgba, mip_level_count);
+         for (mip_level, level) in std::iter::once(&rgba).chain(mips.iter()).enumerate() {
+            queue.write_texture(
+               wgpu::ImageCopyTexture {
+                  aspect: wgpu::TextureAspect::All,
+                  texture: &texture,
+                  mip_level: mip_level as u32,
+                  // z picks the array layer, which is the face
+                  origin: wgpu::Origin3d { x: 0, y: 0, z: face as u32 },
+               },
+               level,
+               wgpu::ImageDataLayout {
+                  offset: 0,
+                  bytes_per_row: Some(4 * level.width()),
+                  rows_per_image: Some(level.height())
+               },
+               wgpu::Extent3d {
+                  width: level.width(),
+                  height: level.height(),
+                  depth_or_array_layers: 1,
+               },
+            );
          }
+      }
+
+      Ok(Self::cube(device, samplers, texture, &options))
+   }
+
+   // Converts an equirectangular panorama (a 2:1 image wrapping all the way
+   // around, like most hdr environment maps) into a cubemap, on the gpu.
+   // The cubemap has the same format as equirect and a single mip level.
+   // The conversion filters the panorama, so hdr panoramas have to be
+   // Rgba16Float rather than Rgba32Float
+   pub fn from_equirectangular(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
+      equirect: &Texture,
+      face_size: u32,
+      label: Option<&str>,
+      options: TextureOptions,
+   ) -> Result<Self> {
+      let format = equirect.texture.format();
+      let features = format.guaranteed_format_features(device.features());
+      ensure!(
+         features.allowed_usages.contains(wgpu::TextureUsages::RENDER_ATTACHMENT),
+         "can't convert {:?} panoramas on the gpu, the format isn't renderable", format
+      );
+      ensure!(
+         features.flags.contains(wgpu::TextureFormatFeatureFlags::FILTERABLE),
+         "can't convert {:?} panoramas on the gpu, the format isn't filterable (try Rgba16Float)", format
       );
 
-      Ok(Self { texture, view, sampler })
+      let texture = cubemap::create_cube_texture(
+         device, label, face_size.max(1), format, 1,
+         wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+      );
+      cubemap::from_equirectangular(device, queue, samplers, &equirect.view, &texture, format);
+
+      Ok(Self::cube(device, samplers, texture, &options))
+   }
+
+   pub(crate) fn cube(device: &wgpu::Device, samplers: &mut SamplerCache, texture: wgpu::Texture, options: &TextureOptions) -> Self {
+      let view = texture.create_view(&wgpu::TextureViewDescriptor {
+         dimension: Some(wgpu::TextureViewDimension::Cube),
+         ..Default::default()
+      });
+      let sampler = samplers.get(device, &options.sampler);
+
+      Self { texture, view, sampler }
    }
 
+   // Creates the texture, uploads the full size image (base) and fills in
+   // the rest of the mip chain as options asks. cpu_mips is only called for
+   // Mipmaps::Cpu (or when the gpu path isn't available) and returns the
+   // bytes of levels 1.. in format
+   #[allow(clippy::too_many_arguments)]
+   fn from_levels<F>(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
+      label: Option<&str>,
+      format: wgpu::TextureFormat,
+      dimensions: (u32, u32),
+      options: TextureOptions,
+      base: &[u8],
+      cpu_mips: F,
+   ) -> Self
+   where
+      F: FnOnce(u32) -> Vec<Vec<u8>>,
+   {
+      let mip_level_count = match options.mipmaps {
+         Mipmaps::None => 1,
+         Mipmaps::Gpu | Mipmaps::Cpu => mipmap::mip_level_count(dimensions.0, dimensions.1),
+      };
+      let mipmaps = match options.mipmaps {
+         Mipmaps::Gpu if !mipmap::can_generate_on_gpu(device, format) => Mipmaps::Cpu,
+         mipmaps => mipmaps,
+      };
+
+      let texture = create_2d_texture(device, label, format, dimensions, mip_level_count);
+
+      write_mip_level(queue, &texture, 0, base);
+      match mipmaps {
+         Mipmaps::None => {},
+         Mipmaps::Gpu => mipmap::generate_gpu(device, queue, samplers, &texture, format, mip_level_count),
+         Mipmaps::Cpu => {
+            for (i, level) in cpu_mips(mip_level_count).iter().enumerate() {
+               write_mip_level(queue, &texture, i as u32 + 1, level);
+            }
+         },
+      }
 
-}
\ No newline at end of file
+      let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+      let sampler = samplers.get(device, &options.sampler);
+
+      Self { texture, view, sampler }
+   }
+
+   // Like from_levels, for when every level is already there
+   #[allow(clippy::too_many_arguments)]
+   fn from_mip_levels(
+      device: &wgpu::Device,
+      queue: &wgpu::Queue,
+      samplers: &mut SamplerCache,
+      label: Option<&str>,
+      format: wgpu::TextureFormat,
+      dimensions: (u32, u32),
+      options: TextureOptions,
+      levels: &[Vec<u8>],
+   ) -> Self {
+      let texture = create_2d_texture(device, label, format, dimensions, levels.len() as u32);
+      for (mip_level, level) in levels.iter().enumerate() {
+         write_mip_level(queue, &texture, mip_level as u32, level);
+      }
+
+      let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+      let sampler = samplers.get(device, &options.sampler);
+
+      Self { texture, view, sampler }
+   }
+}
+
+fn create_2d_texture(
+   device: &wgpu::Device,
+   label: Option<&str>,
+   format: wgpu::TextureFormat,
+   dimensions: (u32, u32),
+   mip_level_count: u32,
+) -> wgpu::Texture {
+   device.create_texture(
+      &wgpu::TextureDescriptor {
+         label,
+         size: wgpu::Extent3d {
+            width: dimensions.0,
+            height: dimensions.1,
+            depth_or_array_layers: 1,
+         },
+         mip_level_count,
+         sample_count: 1,
+         dimension: wgpu::TextureDimension::D2,
+         format,
+         // COPY_SRC lets the texture be read back, e.g. to check its mip chain
+         usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
+         view_formats: &[]
+      }
+   )
+}
+
+// Uploads one tightly packed mip level. Compressed formats are laid out in
+// rows of blocks rather than pixels, and levels smaller than a block still
+// take up a whole one, so the copy covers the level rounded up to blocks
+fn write_mip_level(queue: &wgpu::Queue, texture: &wgpu::Texture, mip_level: u32, data: &[u8]) {
+   let format = texture.format();
+   let size = texture.size().mip_level_size(mip_level, wgpu::TextureDimension::D2);
+   let (block_width, block_height) = format.block_dimensions();
+   let block_size = format.block_size(None).unwrap();
+
+   queue.write_texture(
+      wgpu::ImageCopyTexture {
+         aspect: wgpu::TextureAspect::All,
+         texture,
+         mip_level,
+         origin: wgpu::Origin3d::ZERO,
+      },
+      data,
+      wgpu::ImageDataLayout {
+         offset: 0,
+         bytes_per_row: Some(block_size * size.width.div_ceil(block_width)),
+         rows_per_image: Some(size.height.div_ceil(block_height))
+      },
+      size.physical_size(format),
+   );
+}
+
+// Features textures can make use of when the adapter has them. Request
+// adapter.features() & OPTIONAL_FEATURES when creating the device
+//
+// TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES lets us use whatever the adapter
+// says a format can do, rather than only what WebGPU guarantees everywhere.
+// The TEXTURE_COMPRESSION ones let KTX2/DDS files stay compressed on the gpu
+pub const OPTIONAL_FEATURES: wgpu::Features = wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+   .union(wgpu::Features::TEXTURE_COMPRESSION_BC)
+   .union(wgpu::Features::TEXTURE_COMPRESSION_ETC2)
+   .union(wgpu::Features::TEXTURE_COMPRESSION_ASTC)
+   .union(wgpu::Features::TEXTURE_COMPRESSION_ASTC_HDR);
+
+// The best format to load hdr textures into. Rgba32Float keeps every bit
+// of precision, but WebGPU doesn't promise it can be filtered - only some
+// adapters can, and only with TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+// turned on. Otherwise we settle for Rgba16Float, which always can
+pub fn hdr_format(adapter: &wgpu::Adapter, device: &wgpu::Device) -> wgpu::TextureFormat {
+   let format = wgpu::TextureFormat::Rgba32Float;
+   let adapter_specific = device.features().contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES);
+   let flags = adapter.get_texture_format_features(format).flags;
+   if adapter_specific && flags.contains(wgpu::TextureFormatFeatureFlags::FILTERABLE) {
+      format
+   } else {
+      wgpu::TextureFormat::Rgba16Float
+   }
+}
+
+// image's generic decoder tone maps .hdr files down to 8 bits, so those
+// go through the hdr decoder directly. OpenEXR already decodes to f32
+fn decode_hdr(bytes: &[u8]) -> Result<image::Rgba32FImage> {
+   if image::guess_format(bytes)? == image::ImageFormat::Hdr {
+      let decoder = image::codecs::hdr::HdrDecoder::new(bytes)?;
+      let metadata = decoder.metadata();
+      let pixels = decoder.read_image_hdr()?;
+      let rgba = pixels.iter().flat_map(|p| [p[0], p[1], p[2], 1.0]).collect();
+      return image::Rgba32FImage::from_raw(metadata.width, metadata.height, rgba)
+         .ok_or_else(|| anyhow!("hdr image is the wrong size"));
+   }
+   Ok(image::load_from_memory(bytes)?.into_rgba32f())
+}
@@ -0,0 +1,29 @@
+// Shared by the shaders that draw meshes - #include "vertex.wgsl"
+
+struct CameraUniform {
+   view_position: vec4<f32>,
+   view_proj: mat4x4<f32>,
+};
+
+struct VertexInput {
+   @location(0) position: vec3<f32>,
+   @location(1) tex_coords: vec2<f32>,
+   @location(2) normal: vec3<f32>,
+}
+
+// The model matrix comes in as 4 columns, see InstanceRaw::desc
+struct InstanceInput {
+   @location(5) model_matrix_0: vec4<f32>,
+   @location(6) model_matrix_1: vec4<f32>,
+   @location(7) model_matrix_2: vec4<f32>,
+   @location(8) model_matrix_3: vec4<f32>,
+   @location(9) color: vec4<f32>,
+}
+
+struct VertexOutput {
+   @builtin(position) clip_position: vec4<f32>,
+   @location(0) tex_coords: vec2<f32>,
+   @location(1) color: vec4<f32>,
+   @location(2) world_normal: vec3<f32>,
+   @location(3) world_position: vec3<f32>,
+};
@@ -0,0 +1,197 @@
+// Golden image harness - renders a scene offscreen and compares it against
+// a checked-in reference png in tests/golden.
+//
+// Set UPDATE_GOLDEN=1 to (re)write the references from the current output
+// instead of comparing against them. On a mismatch, the rendered frame and a
+// diff image are written to target/golden-diffs so you can see what changed.
+
+use std::path::PathBuf;
+
+use image::{ Rgba, RgbaImage };
+use wgpu_tutorial::{ DepthSettings, OffscreenRenderer };
+
+#[derive(Copy, Clone, Debug)]
+pub struct Tolerance {
+   // Largest difference allowed in any one channel before a pixel counts
+   // as different
+   pub per_channel: u8,
+   // Fraction (0.0 - 1.0) of pixels allowed to be different. Rasterizers
+   // disagree a little on triangle edges, so we don't want this to be 0
+   pub max_different_pixels: f32,
+}
+
+impl Default for Tolerance {
+   fn default() -> Self {
+      Self {
+         per_channel: 2,
+         max_different_pixels: 0.001,
+      }
+   }
+}
+
+pub struct Comparison {
+   pub different_pixels: u32,
+   pub total_pixels: u32,
+   pub diff: RgbaImage,
+}
+
+// Compares two images pixel by pixel. The diff image is the reference
+// dimmed to grey, with pixels outside the tolerance painted red
+pub fn compare(actual: &RgbaImage, expected: &RgbaImage, per_channel: u8) -> Comparison {
+   assert_eq!(actual.dimensions(), expected.dimensions(), "image sizes differ");
+
+   let mut diff = RgbaImage::new(actual.width(), actual.height());
+   let mut different_pixels = 0;
+   for (x, y, a) in actual.enumerate_pixels() {
+      let e = expected.get_pixel(x, y);
+      let differs = a.0.iter()
+         .zip(e.0.iter())
+         .any(|(a, e)| a.abs_diff(*e) > per_channel);
+
+      let pixel = if differs {
+         different_pixels += 1;
+         Rgba([255, 0, 0, 255])
+      } else {
+         let grey = ((e[0] as u32 + e[1] as u32 + e[2] as u32) / 3 / 4) as u8;
+         Rgba([grey, grey, grey, 255])
+      };
+      diff.put_pixel(x, y, pixel);
+   }
+
+   Comparison {
+      different_pixels,
+      total_pixels: actual.width() * actual.height(),
+      diff,
+   }
+}
+
+pub fn asset(name: &str) -> PathBuf {
+   PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("assets").join(name)
+}
+
+// Returns None (and prints why) when there is no adapter to render with,
+// so machines without any gpu or software renderer skip instead of failing
+pub fn renderer(width: u32, height: u32) -> Option<OffscreenRenderer> {
+   renderer_with_format(width, height, OffscreenRenderer::FORMAT)
+}
+
+pub fn renderer_with_format(width: u32, height: u32, format: wgpu::TextureFormat) -> Option<OffscreenRenderer> {
+   match pollster::block_on(OffscreenRenderer::with_format(width, height, format)) {
+      Ok(renderer) => Some(renderer),
+      Err(e) => {
+         eprintln!("skipping golden test, couldn't create offscreen renderer: {e}");
+         None
+      }
+   }
+}
+
+pub fn renderer_with_depth_settings(width: u32, height: u32, depth_settings: DepthSettings) -> Option<OffscreenRenderer> {
+   match pollster::block_on(OffscreenRenderer::with_depth_settings(width, height, depth_settings)) {
+      Ok(renderer) => Some(renderer),
+      Err(e) => {
+         eprintln!("skipping golden test, couldn't create offscreen renderer: {e}");
+         None
+      }
+   }
+}
+
+pub fn renderer_without_features(width: u32, height: u32, features: wgpu::Features) -> Option<OffscreenRenderer> {
+   match pollster::block_on(OffscreenRenderer::without_features(width, height, features)) {
+      Ok(renderer) => Some(renderer),
+      Err(e) => {
+         eprintln!("skipping golden test, couldn't create offscreen renderer: {e}");
+         None
+      }
+   }
+}
+
+pub fn assert_golden(name: &str, actual: &RgbaImage, tolerance: Tolerance) {
+   let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+   let reference_path = manifest_dir.join("tests").join("golden").join(format!("{name}.png"));
+
+   if std::env::var_os("UPDATE_GOLDEN").is_some() {
+      actual.save(&reference_path).unwrap();
+      eprintln!("wrote {}", reference_path.display());
+      return;
+   }
+
+   let expected = image::open(&reference_path)
+      .unwrap_or_else(|e| panic!(
+         "couldn't open {}: {e}\nrun with UPDATE_GOLDEN=1 to create it",
+         reference_path.display()
+      ))
+      .to_rgba8();
+
+   assert_eq!(
+      actual.dimensions(), expected.dimensions(),
+      "{name}: rendered size doesn't match {}", reference_path.display()
+   );
+
+   let comparison = compare(actual, &expected, tolerance.per_channel);
+   let fraction = comparison.different_pixels as f32 / comparison.total_pixels as f32;
+   if fraction > tolerance.max_different_pixels {
+      let out_dir = manifest_dir.join("target").join("golden-diffs");
+      std::fs::create_dir_all(&out_dir).unwrap();
+      let actual_path = out_dir.join(format!("{name}.actual.png"));
+      let diff_path = out_dir.join(format!("{name}.diff.png"));
+      actual.save(&actual_path).unwrap();
+      comparison.diff.save(&diff_path).unwrap();
+
+      panic!(
+         "{name}: {} of {} pixels differ by more than {} ({:.3}% > {:.3}%)\n  actual: {}\n  diff:   {}",
+         comparison.different_pixels,
+         comparison.total_pixels,
+         tolerance.per_channel,
+         fraction * 100.0,
+         tolerance.max_different_pixels * 100.0,
+         actual_path.display(),
+         diff_path.display(),
+      );
+   }
+}
+
+// Copies one mip level of texture back to the cpu, tightly packed. Only
+// for uncompressed formats
+pub fn read_texture(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture, mip_level: u32) -> Vec<u8> {
+   let size = texture.size().mip_level_size(mip_level, wgpu::TextureDimension::D2);
+   let bytes_per_pixel = texture.format().block_size(None).unwrap();
+   let row_bytes = bytes_per_pixel * size.width;
+   // Buffer copies need every row to start on a 256 byte boundary
+   let padded_row_bytes = row_bytes.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+
+   let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+      label: Some("Readback Buffer"),
+      size: (padded_row_bytes * size.height) as wgpu::BufferAddress,
+      usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+      mapped_at_creation: false,
+   });
+   let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+   encoder.copy_texture_to_buffer(
+      wgpu::ImageCopyTexture {
+         texture,
+         mip_level,
+         origin: wgpu::Origin3d::ZERO,
+         aspect: wgpu::TextureAspect::All,
+      },
+      wgpu::ImageCopyBuffer {
+         buffer: &buffer,
+         layout: wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(padded_row_bytes),
+            rows_per_image: Some(size.height),
+         },
+      },
+      size,
+   );
+   queue.submit(std::iter::once(encoder.finish()));
+
+   let slice = buffer.slice(..);
+   slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+   device.poll(wgpu::Maintain::Wait);
+   let data = slice.get_mapped_range()
+      .chunks(padded_row_bytes as usize)
+      .flat_map(|row| row[..row_bytes as usize].to_vec())
+      .collect();
+   buffer.unmap();
+   data
+}
@@ -0,0 +1,876 @@
+mod common;
+
+use common::Tolerance;
+use cgmath::Rotation3;
+use wgpu_tutorial::{
+   camera::Projection,
+   instance::Instance,
+   light::Light,
+   texture::{ hdr_format, ColorSpace, Mipmaps, SamplerCache, SamplerOptions, Texture, TextureOptions },
+   CascadeSplit, DepthSettings, IblSettings, ShaderDefs, ShadowSettings,
+};
+
+#[test]
+fn textured_pentagon() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   let frame = renderer.render().unwrap();
+   common::assert_golden("textured_pentagon", &frame, Tolerance::default());
+}
+
+#[test]
+fn textured_pentagon_orthographic() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   let camera = renderer.camera_mut();
+   camera.eye = (0.0, 0.0, 2.0).into();
+   camera.projection = Projection::Orthographic { height: 1.5 };
+   let frame = renderer.render().unwrap();
+   common::assert_golden("textured_pentagon_orthographic", &frame, Tolerance::default());
+}
+
+// A target that isn't sRGB goes through the gamma pass, and should come
+// out looking the same as rendering straight into an sRGB one
+#[test]
+fn gamma_pass_matches_srgb_target() {
+   // One at a time - the gl backend doesn't like two devices being alive
+   // on the same thread
+   let expected = {
+      let Some(mut srgb) = common::renderer(256, 256) else { return };
+      srgb.render().unwrap()
+   };
+   let Some(mut linear) = common::renderer_with_format(256, 256, wgpu::TextureFormat::Rgba8Unorm) else { return };
+   let actual = linear.render().unwrap();
+
+   let tolerance = Tolerance::default();
+   let comparison = common::compare(&actual, &expected, tolerance.per_channel);
+   let fraction = comparison.different_pixels as f32 / comparison.total_pixels as f32;
+   assert!(
+      fraction <= tolerance.max_different_pixels,
+      "{} of {} pixels differ", comparison.different_pixels, comparison.total_pixels
+   );
+}
+
+#[test]
+fn instanced_grid() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.camera_mut().eye = (0.0, 0.0, 4.0).into();
+   *renderer.instances_mut() = (0..9).map(|i| {
+      let (x, y) = ((i % 3) as f32 - 1.0, (i / 3) as f32 - 1.0);
+      Instance {
+         scale: 0.8,
+         color: [1.0, 1.0 - i as f32 / 8.0, i as f32 / 8.0, 1.0],
+         ..Instance::new((x, y, 0.0).into())
+      }
+   }).collect();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("instanced_grid", &frame, Tolerance::default());
+}
+
+// Three overlapping pentagons at different depths: red in front, then
+// green, then blue at the back
+fn overlapping_instances(renderer: &mut wgpu_tutorial::OffscreenRenderer, back_to_front: bool) {
+   renderer.camera_mut().eye = (0.0, 0.0, 3.0).into();
+   let mut instances = [[1.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0]]
+      .into_iter()
+      .enumerate()
+      .map(|(i, color)| Instance {
+         color,
+         ..Instance::new((i as f32 * 0.3 - 0.3, i as f32 * 0.15 - 0.15, i as f32 * -0.5).into())
+      })
+      .collect::<Vec<_>>();
+   if back_to_front {
+      instances.reverse();
+   }
+   *renderer.instances_mut() = instances;
+}
+
+// The depth buffer keeps the nearest pentagon on top whichever order
+// they're drawn in
+#[test]
+fn depth_buffer() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   overlapping_instances(&mut renderer, false);
+   let front_to_back = renderer.render().unwrap();
+   overlapping_instances(&mut renderer, true);
+   let back_to_front = renderer.render().unwrap();
+   assert_eq!(front_to_back, back_to_front);
+   common::assert_golden("depth_buffer", &back_to_front, Tolerance::default());
+}
+
+// Greater (as used for reverse-z) with a stencil format. The depth buffer
+// is cleared to 0.0, and with our ordinary projection the pentagon
+// furthest away ends up on top
+#[test]
+fn depth_compare_greater() {
+   let depth_settings = DepthSettings {
+      format: wgpu::TextureFormat::Depth24PlusStencil8,
+      compare: wgpu::CompareFunction::Greater,
+   };
+   let Some(mut renderer) = common::renderer_with_depth_settings(256, 256, depth_settings) else { return };
+   overlapping_instances(&mut renderer, false);
+   let front_to_back = renderer.render().unwrap();
+   overlapping_instances(&mut renderer, true);
+   let back_to_front = renderer.render().unwrap();
+   assert_eq!(front_to_back, back_to_front);
+   common::assert_golden("depth_compare_greater", &back_to_front, Tolerance::default());
+}
+
+#[test]
+fn obj_model_with_materials() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.load_model(common::asset("cube.obj")).unwrap();
+   renderer.camera_mut().eye = (1.5, 1.2, 2.0).into();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("obj_model_with_materials", &frame, Tolerance::default());
+}
+
+// A map_Kd that isn't there falls back to Kd, the same as if the material
+// didn't have one
+#[test]
+fn obj_missing_texture_falls_back_to_kd() {
+   let Some(mut renderer) = common::renderer(128, 128) else { return };
+   renderer.camera_mut().eye = (1.5, 1.2, 2.0).into();
+   let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("missing_texture");
+   std::fs::create_dir_all(&dir).unwrap();
+   std::fs::copy(common::asset("cube.obj"), dir.join("cube.obj")).unwrap();
+
+   let mut frames = Vec::new();
+   for kirby in ["Kd 0.2 0.4 0.8\nmap_Kd missing.png", "Kd 0.2 0.4 0.8"] {
+      std::fs::write(dir.join("cube.mtl"), format!("newmtl kirby\n{kirby}\n\nnewmtl red\nKd 0.8 0.1 0.1\n")).unwrap();
+      renderer.load_model(dir.join("cube.obj")).unwrap();
+      frames.push(renderer.render().unwrap());
+   }
+   assert_eq!(frames[0], frames[1]);
+}
+
+// One of each kind of light over a grid of pentagons: a dim directional
+// light everywhere, a red point light top right and a spot light on the
+// bottom left pentagon
+#[test]
+fn blinn_phong_lights() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.camera_mut().eye = (0.0, 0.0, 4.0).into();
+   *renderer.instances_mut() = (0..9).map(|i| {
+      let (x, y) = ((i % 3) as f32 - 1.0, (i / 3) as f32 - 1.0);
+      Instance { scale: 0.8, ..Instance::new((x, y, 0.0).into()) }
+   }).collect();
+   renderer.set_ambient([0.05, 0.05, 0.05]);
+   *renderer.lights_mut() = vec![
+      Light::Directional { direction: (0.5, 0.0, -1.0).into(), color: [1.0, 1.0, 1.0], intensity: 0.3 },
+      Light::Point { position: (1.0, 1.0, 0.6).into(), color: [1.0, 0.2, 0.2], intensity: 1.0, range: 2.0 },
+      Light::Spot {
+         position: (-1.0, -1.0, 2.0).into(),
+         direction: (0.0, 0.0, -1.0).into(),
+         color: [1.0, 1.0, 1.0],
+         intensity: 4.0,
+         range: 5.0,
+         inner_angle: cgmath::Deg(5.0),
+         outer_angle: cgmath::Deg(10.0),
+      },
+   ];
+   renderer.set_shader_defs(ShaderDefs::new().define("BLINN_PHONG")).unwrap();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("blinn_phong_lights", &frame, Tolerance::default());
+}
+
+// Spheres lit by a key light and a point light from the front left. Top
+// row is red plastic and the middle row gold, both getting rougher to the
+// right. The bottom row has a normal map, a metallic-roughness texture
+// with occlusion, and an emissive texture
+#[test]
+fn pbr_materials() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.load_model(common::asset("pbr_spheres.gltf")).unwrap();
+   renderer.camera_mut().eye = (0.0, 0.0, 3.6).into();
+   renderer.set_ambient([0.03, 0.03, 0.03]);
+   *renderer.lights_mut() = vec![
+      Light::Directional { direction: (0.4, -0.5, -1.0).into(), color: [1.0, 1.0, 1.0], intensity: 3.0 },
+      Light::Point { position: (-1.5, 1.5, 1.5).into(), color: [0.6, 0.8, 1.0], intensity: 4.0, range: 6.0 },
+   ];
+   let frame = renderer.render().unwrap();
+   common::assert_golden("pbr_materials", &frame, Tolerance::default());
+}
+
+// Small, quick settings - plenty for 256x256
+fn test_ibl_settings() -> IblSettings {
+   IblSettings::default()
+      .irradiance_size(8)
+      .specular_size(32, 4)
+      .brdf_lut_size(32)
+      .sample_count(64)
+}
+
+// The same spheres lit only by the sky around them, which is drawn too.
+// The smooth ones reflect it, the rough ones pick up its average colors
+#[test]
+fn image_based_lighting() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.load_model(common::asset("pbr_spheres.gltf")).unwrap();
+   renderer.camera_mut().eye = (0.0, 0.0, 3.6).into();
+   renderer.set_ambient([0.0, 0.0, 0.0]);
+   renderer.load_environment(common::asset("sky_equirect.png"), 64, &test_ibl_settings()).unwrap();
+   renderer.load_skybox(common::asset("sky_equirect.png"), 64).unwrap();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("image_based_lighting", &frame, Tolerance::default());
+}
+
+// The precomputed textures are saved to the cache dir and used from there
+// the next time the same environment is loaded, while a broken cache file
+// is made again
+#[test]
+fn ibl_cache() {
+   let Some(mut renderer) = common::renderer(64, 64) else { return };
+   renderer.load_model(common::asset("pbr_spheres.gltf")).unwrap();
+   renderer.camera_mut().eye = (0.0, 0.0, 3.6).into();
+   renderer.set_ambient([0.0, 0.0, 0.0]);
+   let unlit = renderer.render().unwrap();
+
+   let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("ibl_cache");
+   std::fs::remove_dir_all(&dir).ok();
+   let settings = test_ibl_settings().cache_dir(&dir);
+   let sky = common::asset("sky_equirect.png");
+
+   renderer.load_environment(&sky, 64, &settings).unwrap();
+   let lit = renderer.render().unwrap();
+   assert_ne!(lit, unlit);
+   let files = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+   assert_eq!(files.len(), 1, "{:?}", files);
+   let cache = std::fs::read(&files[0]).unwrap();
+
+   // Blacking out everything after the 24 byte header shows the cache is
+   // what gets used
+   let mut black = cache.clone();
+   black[24..].fill(0);
+   std::fs::write(&files[0], &black).unwrap();
+   renderer.load_environment(&sky, 64, &settings).unwrap();
+   assert_eq!(renderer.render().unwrap(), unlit);
+
+   std::fs::write(&files[0], &cache[..cache.len() / 2]).unwrap();
+   renderer.load_environment(&sky, 64, &settings).unwrap();
+   assert_eq!(renderer.render().unwrap(), lit);
+   assert_eq!(std::fs::read(&files[0]).unwrap(), cache);
+}
+
+// A big pentagon lying flat as a floor, with three upright ones standing
+// over it and a sun shining down from behind them
+fn shadow_scene(renderer: &mut wgpu_tutorial::OffscreenRenderer) {
+   let camera = renderer.camera_mut();
+   camera.eye = (0.0, 2.5, 3.0).into();
+   camera.target = (0.0, -0.3, 0.0).into();
+   let mut instances = vec![Instance {
+      rotation: cgmath::Quaternion::from_angle_x(cgmath::Deg(-90.0)),
+      scale: 5.0,
+      ..Instance::new((0.0, -0.5, 0.0).into())
+   }];
+   instances.extend((0..3).map(|i| Instance {
+      scale: 0.6,
+      ..Instance::new((i as f32 - 1.0, 0.0, -0.3 * i as f32).into())
+   }));
+   *renderer.instances_mut() = instances;
+   renderer.set_ambient([0.05, 0.05, 0.05]);
+   *renderer.lights_mut() = vec![
+      Light::Directional { direction: (0.3, -1.0, 0.6).into(), color: [1.0, 1.0, 1.0], intensity: 3.0 },
+   ];
+}
+
+#[test]
+fn directional_shadows() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   shadow_scene(&mut renderer);
+   renderer.set_shadows(Some(ShadowSettings::default().resolution(512).radius(3.0)));
+   let frame = renderer.render().unwrap();
+   common::assert_golden("directional_shadows", &frame, Tolerance::default());
+}
+
+// Turning shadows off again puts everything back the way it was, and a
+// bigger filter softens the edges rather than moving them
+#[test]
+fn shadow_settings() {
+   let Some(mut renderer) = common::renderer(128, 128) else { return };
+   shadow_scene(&mut renderer);
+   let unshadowed = renderer.render().unwrap();
+
+   let settings = ShadowSettings::default().resolution(512).radius(3.0);
+   renderer.set_shadows(Some(settings.clone().pcf_radius(0)));
+   let hard = renderer.render().unwrap();
+   assert_ne!(hard, unshadowed);
+   renderer.set_shadows(Some(settings.pcf_radius(3)));
+   let soft = renderer.render().unwrap();
+   assert_ne!(soft, hard);
+   assert_eq!(renderer.shadow_settings().unwrap().pcf_radius, 3);
+
+   // Every pixel that changed is darker - shadows only ever take light away
+   let brightness = |pixel: &image::Rgba<u8>| pixel.0[..3].iter().map(|&c| c as u32).sum::<u32>();
+   for (shadowed, lit) in soft.pixels().zip(unshadowed.pixels()) {
+      assert!(brightness(shadowed) <= brightness(lit));
+   }
+
+   renderer.set_shadows(None);
+   assert_eq!(renderer.render().unwrap(), unshadowed);
+}
+
+// A floor stretching far into the distance, with rows of pentagons
+// standing on it all the way back, seen from low down
+fn cascade_scene(renderer: &mut wgpu_tutorial::OffscreenRenderer) {
+   let camera = renderer.camera_mut();
+   camera.eye = (0.0, 1.5, 6.0).into();
+   camera.target = (0.0, 0.5, -4.0).into();
+   let mut instances = vec![Instance {
+      rotation: cgmath::Quaternion::from_angle_x(cgmath::Deg(-90.0)),
+      scale: 80.0,
+      ..Instance::new((0.0, 0.0, -20.0).into())
+   }];
+   instances.extend((0..24).map(|i| Instance {
+      scale: 0.8,
+      ..Instance::new(((i % 3) as f32 * 3.0 - 3.0, 0.4, 3.0 - (i / 3) as f32 * 4.0).into())
+   }));
+   *renderer.instances_mut() = instances;
+   renderer.set_ambient([0.05, 0.05, 0.05]);
+   *renderer.lights_mut() = vec![
+      Light::Directional { direction: (0.8, -1.0, -0.6).into(), color: [1.0, 1.0, 1.0], intensity: 3.0 },
+   ];
+}
+
+#[test]
+fn cascaded_shadows() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   cascade_scene(&mut renderer);
+   renderer.set_shadows(Some(ShadowSettings::cascaded().resolution(512).max_distance(40.0)));
+   let frame = renderer.render().unwrap();
+   common::assert_golden("cascaded_shadows", &frame, Tolerance::default());
+}
+
+// DEBUG_CASCADES tints by cascade, which shows where the split scheme
+// puts them. The default is most of the way to logarithmic, so the near
+// cascades are much shorter than with a uniform split
+#[test]
+fn cascade_debug_view() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   cascade_scene(&mut renderer);
+   renderer.set_shader_defs(ShaderDefs::new().define("DEBUG_CASCADES")).unwrap();
+   let settings = ShadowSettings::cascaded().resolution(512).max_distance(40.0);
+   renderer.set_shadows(Some(settings.clone().cascades(4, CascadeSplit::Uniform)));
+   let uniform = renderer.render().unwrap();
+   renderer.set_shadows(Some(settings));
+   let frame = renderer.render().unwrap();
+   assert_ne!(frame, uniform);
+   common::assert_golden("cascade_debug_view", &frame, Tolerance::default());
+}
+
+#[test]
+fn gltf_node_hierarchy() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.load_model(common::asset("hierarchy.gltf")).unwrap();
+   renderer.camera_mut().eye = (0.0, 0.0, 2.5).into();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("gltf_node_hierarchy", &frame, Tolerance::default());
+}
+
+// Two nodes that are each other's child. Loading fails rather than
+// walking round them forever
+#[test]
+fn gltf_node_cycle_is_an_error() {
+   let Some(mut renderer) = common::renderer(4, 4) else { return };
+   let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("cycle.gltf");
+   std::fs::write(&path, r#"{
+      "asset": { "version": "2.0" },
+      "scenes": [{ "nodes": [0] }],
+      "nodes": [{ "children": [1] }, { "children": [0] }]
+   }"#).unwrap();
+
+   let error = format!("{:#}", renderer.load_model(&path).unwrap_err());
+   assert!(error.contains("reached more than once"), "{}", error);
+}
+
+// The same quad twice, the right one under a node with a negative x scale.
+// Its winding gets flipped back, so back face culling keeps it and it shows
+// up as a mirror image of the left one
+#[test]
+fn gltf_mirrored_node() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.load_model(common::asset("mirrored.gltf")).unwrap();
+   renderer.camera_mut().eye = (0.0, 0.0, 2.5).into();
+   let frame = renderer.render().unwrap();
+   let background = *frame.get_pixel(0, 0);
+   assert_ne!(*frame.get_pixel(64, 128), background);
+   assert_ne!(*frame.get_pixel(192, 128), background);
+   common::assert_golden("gltf_mirrored_node", &frame, Tolerance::default());
+}
+
+// A triangle with only two normals. Loading fails instead of reading past
+// the end of the normals
+#[test]
+fn gltf_attribute_count_mismatch_is_an_error() {
+   let Some(mut renderer) = common::renderer(4, 4) else { return };
+   let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("mismatch.gltf");
+   std::fs::write(&path, r#"{
+      "asset": { "version": "2.0" },
+      "scenes": [{ "nodes": [0] }],
+      "nodes": [{ "mesh": 0 }],
+      "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0, "NORMAL": 1 } }] }],
+      "buffers": [{
+         "byteLength": 60,
+         "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAAAAAAAAAAAAIA/AAAAAAAAAAAAAIA/"
+      }],
+      "bufferViews": [
+         { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+         { "buffer": 0, "byteOffset": 36, "byteLength": 24 }
+      ],
+      "accessors": [
+         { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] },
+         { "bufferView": 1, "componentType": 5126, "count": 2, "type": "VEC3" }
+      ]
+   }"#).unwrap();
+
+   let error = format!("{:#}", renderer.load_model(&path).unwrap_err());
+   assert!(error.contains("different number of normals"), "{}", error);
+}
+
+// The file stores its indices as u32, but has so few vertices that they
+// get narrowed to Uint16 - u32_index_buffer covers the Uint32 path
+#[test]
+fn glb_embedded_image_and_u32_indices() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.load_model(common::asset("quads.glb")).unwrap();
+   renderer.camera_mut().eye = (0.0, 0.0, 2.5).into();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("glb_embedded_image_and_u32_indices", &frame, Tolerance::default());
+}
+
+// A 300x300 vertex grid, too many vertices for 16 bit indices. The last
+// rows only show up in the right place if the index buffer is bound as
+// Uint32, so the grid comes out as a whole square with a hole in the middle
+#[test]
+fn u32_index_buffer() {
+   let Some(mut renderer) = common::renderer(128, 128) else { return };
+   let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("big_grid.obj");
+   let size = 300;
+   let mut obj = String::new();
+   for y in 0..size {
+      for x in 0..size {
+         let (u, v) = (x as f32 / (size - 1) as f32, y as f32 / (size - 1) as f32);
+         obj += &format!("v {} {} 0\n", u * 2.0 - 1.0, v * 2.0 - 1.0);
+      }
+   }
+   for y in 0..size - 1 {
+      for x in 0..size - 1 {
+         // Leave out the middle, so there's something to see
+         if (110..190).contains(&x) && (110..190).contains(&y) {
+            continue;
+         }
+         // OBJ indices start at 1
+         let i = y * size + x + 1;
+         obj += &format!("f {} {} {} {}\n", i, i + 1, i + size + 1, i + size);
+      }
+   }
+   std::fs::write(&path, obj).unwrap();
+
+   renderer.load_model(&path).unwrap();
+   let mesh = &renderer.model().unwrap().meshes[0];
+   assert_eq!(mesh.index_format, wgpu::IndexFormat::Uint32);
+   renderer.camera_mut().eye = (0.0, 0.0, 3.0).into();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("u32_index_buffer", &frame, Tolerance::default());
+}
+
+// Looking at a corner of the cube so three faces show, with the pentagon
+// drawn over the skybox
+#[test]
+fn skybox_from_faces() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   let faces = ["px", "nx", "py", "ny", "pz", "nz"].map(|face| common::asset(&format!("sky_{face}.png")));
+   renderer.load_skybox_faces(&faces).unwrap();
+   let camera = renderer.camera_mut();
+   camera.eye = (0.0, 0.0, 2.0).into();
+   camera.target = (1.0, 1.0, 1.0).into();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("skybox_from_faces", &frame, Tolerance::default());
+}
+
+// Looking at the seam between two of the panorama's columns, just above
+// the horizon. Moving the camera mustn't move the sky, only turning it should
+#[test]
+fn skybox_from_equirectangular() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.load_skybox(common::asset("sky_equirect.png"), 32).unwrap();
+   let camera = renderer.camera_mut();
+   camera.eye = (0.0, 0.0, -2.0).into();
+   camera.target = (0.0, 0.2, -3.0).into();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("skybox_from_equirectangular", &frame, Tolerance::default());
+
+   let camera = renderer.camera_mut();
+   camera.eye = (5.0, 2.0, -10.0).into();
+   camera.target = (5.0, 2.2, -11.0).into();
+   let moved = renderer.render().unwrap();
+   common::assert_golden("skybox_from_equirectangular", &moved, Tolerance::default());
+}
+
+// Rgba32Float isn't filterable everywhere, so an Rgba32Float panorama is
+// an error rather than a panic when the conversion binds it
+#[test]
+fn equirectangular_needs_a_filterable_format() {
+   let Some(renderer) = common::renderer(4, 4) else { return };
+   let (device, queue) = (renderer.device(), renderer.queue());
+   let mut samplers = SamplerCache::new();
+   let bytes = std::fs::read(common::asset("hdr_values.hdr")).unwrap();
+   let panorama = |format, samplers: &mut SamplerCache| {
+      Texture::from_hdr_bytes(device, queue, samplers, &bytes, "panorama", format, TextureOptions::default()).unwrap()
+   };
+
+   let equirect = panorama(wgpu::TextureFormat::Rgba32Float, &mut samplers);
+   let error = Texture::from_equirectangular(device, queue, &mut samplers, &equirect, 4, None, TextureOptions::default())
+      .err()
+      .unwrap();
+   assert!(error.to_string().contains("isn't filterable"), "{}", error);
+
+   let equirect = panorama(wgpu::TextureFormat::Rgba16Float, &mut samplers);
+   Texture::from_equirectangular(device, queue, &mut samplers, &equirect, 4, None, TextureOptions::default()).unwrap();
+}
+
+// A quad each for BC1 and BC3 (DDS), BC5, ETC2 RGB, RGB A1 and RGBA
+// (KTX2), all random blocks so every block mode shows up
+#[test]
+fn compressed_textures() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.load_model(common::asset("compressed.obj")).unwrap();
+   renderer.camera_mut().eye = (0.0, 0.0, 3.8).into();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("compressed_textures", &frame, Tolerance::default());
+}
+
+// Without the compression features the same files get decompressed on the
+// cpu, which should look just like the gpu decoding them
+#[test]
+fn compressed_textures_cpu_fallback() {
+   let compression = wgpu::Features::TEXTURE_COMPRESSION_BC | wgpu::Features::TEXTURE_COMPRESSION_ETC2;
+   let Some(mut renderer) = common::renderer_without_features(256, 256, compression) else { return };
+   renderer.load_model(common::asset("compressed.obj")).unwrap();
+   renderer.camera_mut().eye = (0.0, 0.0, 3.8).into();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("compressed_textures", &frame, Tolerance::default());
+}
+
+// A fine checkerboard on pentagons further and further away. With a mip
+// chain and trilinear filtering it fades to grey in the distance, rather
+// than breaking up into noise like it does without
+#[test]
+fn mipmapped_checkerboard() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("checkerboard.png");
+   image::RgbaImage::from_fn(256, 256, |x, y| {
+      let c = if (x / 4 + y / 4) % 2 == 0 { 255 } else { 0 };
+      image::Rgba([c, c, c, 255])
+   }).save(&path).unwrap();
+
+   renderer.camera_mut().eye = (0.0, 0.0, 2.0).into();
+   *renderer.instances_mut() = [(-0.6, 0.3, 0.0), (0.4, 0.2, -3.0), (1.5, -2.5, -10.0)]
+      .into_iter()
+      .map(|position| Instance::new(position.into()))
+      .collect();
+   renderer.load_texture(&path).unwrap();
+   let unfiltered = renderer.render().unwrap();
+
+   renderer.set_texture_options(TextureOptions::default()
+      .mipmaps(Mipmaps::Gpu)
+      .sampler(SamplerOptions::default().filter(wgpu::FilterMode::Linear)));
+   renderer.load_texture(&path).unwrap();
+   let frame = renderer.render().unwrap();
+   assert_ne!(frame, unfiltered);
+   common::assert_golden("mipmapped_checkerboard", &frame, Tolerance::default());
+}
+
+// The cpu fallback builds the same mip chain as the gpu. Both average 2x2
+// blocks of a linear texture's raw values, so only rounding differs
+#[test]
+fn cpu_mipmaps_match_gpu() {
+   let Some(renderer) = common::renderer(4, 4) else { return };
+   let (device, queue) = (renderer.device(), renderer.queue());
+   let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 32, |x, y| {
+      image::Rgba([x as u8 * 4, y as u8 * 8, 255 - (x + y) as u8 * 2, 255])
+   }));
+
+   let mut samplers = SamplerCache::new();
+   let mut load = |mipmaps| {
+      let options = TextureOptions::default().mipmaps(mipmaps).color_space(ColorSpace::Linear);
+      Texture::from_image_with_options(device, queue, &mut samplers, &img, None, options).unwrap().texture
+   };
+   let gpu = load(Mipmaps::Gpu);
+   let cpu = load(Mipmaps::Cpu);
+   assert_eq!(gpu.mip_level_count(), 7);
+   assert_eq!(cpu.mip_level_count(), 7);
+
+   for mip_level in 0..gpu.mip_level_count() {
+      let gpu_level = common::read_texture(device, queue, &gpu, mip_level);
+      let cpu_level = common::read_texture(device, queue, &cpu, mip_level);
+      let largest_difference = gpu_level.iter().zip(&cpu_level).map(|(g, c)| g.abs_diff(*c)).max().unwrap();
+      assert!(largest_difference <= 1, "level {} differs by up to {}", mip_level, largest_difference);
+   }
+}
+
+// Overwrites a texture file and checks the new version gets drawn, and
+// that a broken file leaves the last good texture in place
+#[test]
+fn texture_hot_reload() {
+   let Some(mut renderer) = common::renderer(64, 64) else { return };
+   renderer.camera_mut().eye = (0.0, 0.0, 1.0).into();
+
+   let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("hot_reload.png");
+   let solid = |color: [u8; 3]| image::RgbaImage::from_pixel(4, 4, image::Rgba([color[0], color[1], color[2], 255]));
+   let centre = |renderer: &mut wgpu_tutorial::OffscreenRenderer| *renderer.render().unwrap().get_pixel(32, 32);
+
+   solid([255, 0, 0]).save(&path).unwrap();
+   renderer.load_texture(&path).unwrap();
+   assert_eq!(centre(&mut renderer).0, [255, 0, 0, 255]);
+
+   solid([0, 255, 0]).save(&path).unwrap();
+   assert_eq!(renderer.reload_textures(std::slice::from_ref(&path)), 1);
+   assert_eq!(centre(&mut renderer).0, [0, 255, 0, 255]);
+
+   std::fs::write(&path, b"not a png").unwrap();
+   assert_eq!(renderer.reload_textures(std::slice::from_ref(&path)), 0);
+   assert_eq!(centre(&mut renderer).0, [0, 255, 0, 255]);
+}
+
+// Edits to the shader take effect, while ones that don't compile or don't
+// fit the pipeline are reported and leave the last good shader running
+#[test]
+fn shader_hot_reload() {
+   let Some(mut renderer) = common::renderer(64, 64) else { return };
+   renderer.camera_mut().eye = (0.0, 0.0, 1.0).into();
+
+   let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("shader_hot_reload");
+   std::fs::create_dir_all(&dir).unwrap();
+   let path = dir.join("hot_reload.wgsl");
+   let original = include_str!("../src/shader.wgsl");
+   std::fs::write(dir.join("vertex.wgsl"), include_str!("../src/vertex.wgsl")).unwrap();
+   std::fs::write(dir.join("lighting.wgsl"), include_str!("../src/lighting.wgsl")).unwrap();
+   std::fs::write(dir.join("pbr.wgsl"), include_str!("../src/pbr.wgsl")).unwrap();
+   std::fs::write(dir.join("shadow.wgsl"), include_str!("../src/shadow.wgsl")).unwrap();
+   let centre = |renderer: &mut wgpu_tutorial::OffscreenRenderer| *renderer.render().unwrap().get_pixel(32, 32);
+   let expected = centre(&mut renderer);
+
+   // Every fragment magenta
+   let magenta = original.replace(
+      "textureSample(t_diffuse, s_diffuse, in.tex_coords) * in.color",
+      "vec4<f32>(1.0, 0.0, 1.0, 1.0)",
+   );
+   std::fs::write(&path, &magenta).unwrap();
+   renderer.load_shader(&path).unwrap();
+   assert_eq!(centre(&mut renderer).0, [255, 0, 255, 255]);
+
+   // A syntax error, reported with its line and column
+   std::fs::write(&path, magenta.replace("return out;", "return out")).unwrap();
+   let error = format!("{:#}", renderer.load_shader(&path).unwrap_err());
+   let line = magenta.lines().position(|line| line.contains("return out;")).unwrap() + 2;
+   assert!(error.contains(&format!("hot_reload.wgsl:{}:", line)), "{}", error);
+   assert_eq!(centre(&mut renderer).0, [255, 0, 255, 255]);
+
+   // Valid WGSL, but the camera isn't in group 2
+   std::fs::write(&path, magenta.replace("@group(1) @binding(0)", "@group(2) @binding(0)")).unwrap();
+   assert!(renderer.load_shader(&path).is_err());
+   assert_eq!(centre(&mut renderer).0, [255, 0, 255, 255]);
+
+   std::fs::write(&path, original).unwrap();
+   renderer.load_shader(&path).unwrap();
+   assert_eq!(centre(&mut renderer), expected);
+}
+
+#[test]
+fn shader_def_variant() {
+   let Some(mut renderer) = common::renderer(256, 256) else { return };
+   renderer.set_shader_defs(ShaderDefs::new().define("DEBUG_NORMALS")).unwrap();
+   let frame = renderer.render().unwrap();
+   common::assert_golden("shader_def_variant", &frame, Tolerance::default());
+}
+
+// #define values and #ifdef/#else pick what gets drawn, and errors in
+// included files point at the included file's own lines
+#[test]
+fn shader_preprocessor() {
+   let Some(mut renderer) = common::renderer(64, 64) else { return };
+   renderer.camera_mut().eye = (0.0, 0.0, 1.0).into();
+
+   let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("shader_preprocessor");
+   std::fs::create_dir_all(dir.join("lib")).unwrap();
+   std::fs::write(dir.join("lib/vertex.wgsl"), include_str!("../src/vertex.wgsl")).unwrap();
+   std::fs::write(dir.join("lighting.wgsl"), include_str!("../src/lighting.wgsl")).unwrap();
+   std::fs::write(dir.join("pbr.wgsl"), include_str!("../src/pbr.wgsl")).unwrap();
+   std::fs::write(dir.join("shadow.wgsl"), include_str!("../src/shadow.wgsl")).unwrap();
+   std::fs::write(dir.join("lib/color.wgsl"), [
+      "fn color() -> vec4<f32> {",
+      "#ifdef RED",
+      "   return vec4<f32>(RED, 0.0, 0.0, 1.0);",
+      "#else",
+      "   return vec4<f32>(0.0, 0.0, 1.0, 1.0);",
+      "#endif",
+      "}",
+   ].join("\n")).unwrap();
+   let shader = include_str!("../src/shader.wgsl")
+      .replace("#include \"vertex.wgsl\"", "#include \"lib/vertex.wgsl\"\n#include \"lib/color.wgsl\"")
+      .replace("textureSample(t_diffuse, s_diffuse, in.tex_coords) * in.color", "color()");
+   let path = dir.join("main.wgsl");
+   std::fs::write(&path, shader).unwrap();
+   let centre = |renderer: &mut wgpu_tutorial::OffscreenRenderer| renderer.render().unwrap().get_pixel(32, 32).0;
+
+   renderer.load_shader(&path).unwrap();
+   assert_eq!(centre(&mut renderer), [0, 0, 255, 255]);
+   renderer.set_shader_defs(ShaderDefs::new().define_value("RED", "1.0")).unwrap();
+   assert_eq!(centre(&mut renderer), [255, 0, 0, 255]);
+
+   // A mistake on line 3 of color.wgsl, which is only compiled with RED
+   // defined - as it is now
+   let color = std::fs::read_to_string(dir.join("lib/color.wgsl")).unwrap();
+   std::fs::write(dir.join("lib/color.wgsl"), color.replace("RED, 0.0, 0.0, 1.0", "RED, 0.0, 0.0, 1.0,,")).unwrap();
+   let error = format!("{:#}", renderer.load_shader(&path).unwrap_err());
+   assert!(error.contains("color.wgsl:3:"), "{}", error);
+   assert_eq!(centre(&mut renderer), [255, 0, 0, 255]);
+   renderer.set_shader_defs(ShaderDefs::new()).unwrap();
+   assert_eq!(centre(&mut renderer), [0, 0, 255, 255]);
+}
+
+// Shaders that don't match Vertex::desc or the bind group layouts are
+// turned down with an error naming what doesn't match
+#[test]
+fn shader_reflection_mismatches() {
+   let Some(mut renderer) = common::renderer(64, 64) else { return };
+
+   let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("shader_reflection");
+   std::fs::create_dir_all(&dir).unwrap();
+   let path = dir.join("shader.wgsl");
+   let shader = include_str!("../src/shader.wgsl");
+   let vertex = include_str!("../src/vertex.wgsl");
+   std::fs::write(dir.join("lighting.wgsl"), include_str!("../src/lighting.wgsl")).unwrap();
+   std::fs::write(dir.join("pbr.wgsl"), include_str!("../src/pbr.wgsl")).unwrap();
+   std::fs::write(dir.join("shadow.wgsl"), include_str!("../src/shadow.wgsl")).unwrap();
+   let mut load = |shader: &str, vertex: &str| {
+      std::fs::write(&path, shader).unwrap();
+      std::fs::write(dir.join("vertex.wgsl"), vertex).unwrap();
+      renderer.load_shader(&path).map_err(|e| format!("{:#}", e))
+   };
+
+   load(shader, vertex).unwrap();
+
+   let error = load(shader, &vertex.replace("@location(1) tex_coords", "@location(3) tex_coords")).unwrap_err();
+   assert!(error.contains("VertexInput.tex_coords (@location(3)) isn't in any of the vertex buffers"), "{}", error);
+
+   let error = load(
+      &shader.replace("out.tex_coords = model.tex_coords;", "out.tex_coords = model.tex_coords.xy;"),
+      &vertex.replace("@location(1) tex_coords: vec2<f32>", "@location(1) tex_coords: vec3<f32>"),
+   ).unwrap_err();
+   assert!(error.contains("VertexInput.tex_coords (@location(1)) is vec3<f32>, but the vertex attribute is Float32x2"), "{}", error);
+
+   let error = load(&shader.replace("t_diffuse: texture_2d<f32>", "t_diffuse: texture_depth_2d"), vertex).unwrap_err();
+   assert!(error.contains("the shader's @group(0) @binding(0) is"), "{}", error);
+}
+
+#[test]
+fn compare_flags_pixels_outside_tolerance() {
+   let expected = image::RgbaImage::from_pixel(4, 4, image::Rgba([100, 100, 100, 255]));
+   let mut actual = expected.clone();
+   actual.put_pixel(0, 0, image::Rgba([102, 100, 100, 255]));
+   actual.put_pixel(1, 0, image::Rgba([110, 100, 100, 255]));
+
+   let comparison = common::compare(&actual, &expected, 2);
+   assert_eq!(comparison.different_pixels, 1);
+   assert_eq!(comparison.total_pixels, 16);
+   assert_eq!(*comparison.diff.get_pixel(1, 0), image::Rgba([255, 0, 0, 255]));
+}
+
+// Textures loaded with the same SamplerOptions through one cache share a
+// single sampler
+// A Linear texture is sampled as the bytes it stores, an Srgb one is
+// decoded first. The pentagon is drawn with full ambient and no lights, so
+// the sampled value goes straight to the sRGB target - where 128 read as
+// linear gets encoded to 188, and 128 decoded from sRGB is encoded back to
+// 128
+#[test]
+fn texture_color_spaces() {
+   let Some(mut renderer) = common::renderer(64, 64) else { return };
+   let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("grey_128.png");
+   image::RgbaImage::from_pixel(1, 1, image::Rgba([128, 128, 128, 255])).save(&path).unwrap();
+
+   for (color_space, expected) in [(ColorSpace::Linear, 188), (ColorSpace::Srgb, 128)] {
+      renderer.set_texture_options(TextureOptions::default().color_space(color_space));
+      renderer.load_texture(&path).unwrap();
+      let frame = renderer.render().unwrap();
+      let pixel = frame.get_pixel(32, 32);
+      for channel in &pixel.0[..3] {
+         assert!(channel.abs_diff(expected) <= 1, "{:?} texture drew {:?}", color_space, pixel);
+      }
+   }
+}
+
+// Both hdr formats keep values above 1.0 from both kinds of hdr file
+#[test]
+fn hdr_textures_keep_values_above_one() {
+   let Some(renderer) = common::renderer(4, 4) else { return };
+   let (device, queue) = (renderer.device(), renderer.queue());
+   let expected = [4.0, 0.5, 0.25, 1.0, 1.0, 2.0, 3.0, 1.0, 0.0, 0.0, 0.0, 1.0, 16.0, 8.0, 1.0, 1.0];
+
+   let mut samplers = SamplerCache::new();
+   for name in ["hdr_values.hdr", "hdr_values.exr"] {
+      let bytes = std::fs::read(common::asset(name)).unwrap();
+      for format in [wgpu::TextureFormat::Rgba16Float, wgpu::TextureFormat::Rgba32Float] {
+         let texture = Texture::from_hdr_bytes(device, queue, &mut samplers, &bytes, name, format, TextureOptions::default()).unwrap();
+         assert_eq!(texture.texture.format(), format);
+         let data = common::read_texture(device, queue, &texture.texture, 0);
+         let values = match format {
+            wgpu::TextureFormat::Rgba16Float => {
+               bytemuck::cast_slice::<u8, half::f16>(&data).iter().map(|v| v.to_f32()).collect::<Vec<_>>()
+            },
+            _ => bytemuck::cast_slice::<u8, f32>(&data).to_vec(),
+         };
+         assert_eq!(values, expected, "{} as {:?}", name, format);
+      }
+   }
+}
+
+// Without TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES Rgba32Float can't be
+// filtered, so hdr textures fall back to Rgba16Float
+#[test]
+fn hdr_format_falls_back_to_rgba16float() {
+   let features = wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES;
+   let Some(renderer) = common::renderer_without_features(4, 4, features) else { return };
+   assert_eq!(hdr_format(renderer.adapter(), renderer.device()), wgpu::TextureFormat::Rgba16Float);
+}
+
+// With it, it's up to the adapter whether Rgba32Float can be filtered
+#[test]
+fn hdr_format_follows_the_adapter() {
+   let Some(renderer) = common::renderer(4, 4) else { return };
+   let filterable = renderer.adapter().get_texture_format_features(wgpu::TextureFormat::Rgba32Float).flags
+      .contains(wgpu::TextureFormatFeatureFlags::FILTERABLE);
+   let adapter_specific = renderer.device().features().contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES);
+   let expected = if filterable && adapter_specific {
+      wgpu::TextureFormat::Rgba32Float
+   } else {
+      wgpu::TextureFormat::Rgba16Float
+   };
+   assert_eq!(hdr_format(renderer.adapter(), renderer.device()), expected);
+}
+
+#[test]
+fn sampler_cache_shares_samplers() {
+   let Some(renderer) = common::renderer(4, 4) else { return };
+   let (device, queue) = (renderer.device(), renderer.queue());
+   let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(2, 2, image::Rgba([255, 0, 0, 255])));
+   let options = TextureOptions::default().sampler(
+      SamplerOptions::default().address_mode(wgpu::AddressMode::Repeat).filter(wgpu::FilterMode::Linear)
+   );
+
+   let mut samplers = SamplerCache::new();
+   let a = Texture::from_image_with_options(device, queue, &mut samplers, &img, Some("a"), options).unwrap();
+   let b = Texture::from_image_with_options(device, queue, &mut samplers, &img, Some("b"), options).unwrap();
+   assert_eq!(samplers.len(), 1);
+   assert!(std::sync::Arc::ptr_eq(&a.sampler, &b.sampler));
+
+   Texture::from_image(device, queue, &mut samplers, &img, Some("c")).unwrap();
+   assert_eq!(samplers.len(), 2);
+
+   // Depth textures get recreated on every resize, their comparison
+   // sampler doesn't
+   let depth = Texture::create_depth_texture(device, &mut samplers, 4, 4, Texture::DEPTH_FORMAT, "depth");
+   let resized = Texture::create_depth_texture(device, &mut samplers, 8, 8, Texture::DEPTH_FORMAT, "depth");
+   assert_eq!(samplers.len(), 3);
+   assert!(std::sync::Arc::ptr_eq(&depth.sampler, &resized.sampler));
+}